@@ -0,0 +1,336 @@
+use std::str::FromStr;
+
+use bigdecimal::Zero;
+use eyre::Result;
+use num_bigint::BigUint;
+use serde::Serialize;
+use starknet::core::crypto::{compute_hash_on_elements, pedersen_hash};
+use starknet::core::types::Felt;
+use starknet_crypto::{poseidon_hash, poseidon_hash_many};
+
+use crate::balance::BalanceSnapshot;
+use crate::destination::Destination;
+
+/// How the airdrop amount of each holder is derived from its balance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AllocationRule {
+    /// Every holder receives its balance, 1:1
+    Balance,
+    /// Every holder receives the same raw amount
+    Fixed(BigUint),
+    /// A raw total amount is split pro rata to the balances, rounding down
+    Proportional(BigUint),
+}
+
+impl FromStr for AllocationRule {
+    type Err = String;
+
+    /// Parse `balance`, `fixed:<amount>` or `proportional:<total>`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (kind, amount) = match value.split_once(':') {
+            Some((kind, amount)) => (kind, Some(amount)),
+            None => (value, None),
+        };
+        let parse_amount = || {
+            let amount = amount.ok_or_else(|| format!("rule '{kind}' needs an amount"))?;
+            BigUint::from_str(amount).map_err(|e| format!("invalid amount '{amount}': {e}"))
+        };
+        match kind {
+            "balance" => Ok(AllocationRule::Balance),
+            "fixed" => Ok(AllocationRule::Fixed(parse_amount()?)),
+            "proportional" => Ok(AllocationRule::Proportional(parse_amount()?)),
+            _ => Err(format!(
+                "unknown allocation rule '{value}', expected balance, fixed:<amount> or proportional:<total>"
+            )),
+        }
+    }
+}
+
+/// Hash function used for the leaves and nodes of the tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MerkleHash {
+    Pedersen,
+    Poseidon,
+}
+
+impl MerkleHash {
+    /// Leaf of a claim: `compute_hash_on_elements([account, amount])` for pedersen and
+    /// `poseidon_hash_many([account, amount])` for poseidon
+    pub fn leaf(&self, account: &Felt, amount: &Felt) -> Felt {
+        match self {
+            MerkleHash::Pedersen => compute_hash_on_elements(&[*account, *amount]),
+            MerkleHash::Poseidon => poseidon_hash_many(&[*account, *amount]),
+        }
+    }
+
+    /// Commutative node hash, the smaller child is always hashed first
+    ///
+    /// This matches the sorted-pair verification of the common Cairo merkle proof
+    /// libraries, so proofs don't need to carry left/right flags.
+    pub fn node(&self, a: &Felt, b: &Felt) -> Felt {
+        let (low, high) = if a <= b { (a, b) } else { (b, a) };
+        match self {
+            MerkleHash::Pedersen => pedersen_hash(low, high),
+            MerkleHash::Poseidon => poseidon_hash(*low, *high),
+        }
+    }
+}
+
+/// A single airdrop allocation with its proof of inclusion
+#[derive(Debug, Clone, Serialize)]
+pub struct Claim {
+    pub account: Felt,
+    pub amount: Felt,
+    pub leaf: Felt,
+    pub proof: Vec<Felt>,
+}
+
+/// Merkle root and per-account claims of an airdrop
+#[derive(Debug, Clone, Serialize)]
+pub struct Airdrop {
+    pub token: Felt,
+    pub hash: MerkleHash,
+    pub root: Felt,
+    /// Claims ordered by account address
+    pub claims: Vec<Claim>,
+}
+
+/// Compute the amount of every holder of `token` with a non-zero allocation
+fn allocate(
+    snapshot: &BalanceSnapshot,
+    token: &Felt,
+    rule: &AllocationRule,
+) -> Result<Vec<(Felt, Felt)>> {
+    let balances = snapshot
+        .balances
+        .get(token)
+        .ok_or_else(|| eyre::eyre!("Token {:#064x} is not part of the snapshot", token))?;
+
+    let mut holders: Vec<(Felt, BigUint)> = balances
+        .iter()
+        .filter(|(_, balance)| **balance != Felt::ZERO)
+        .map(|(account, balance)| (*account, balance.to_biguint()))
+        .collect();
+    holders.sort();
+
+    let total: BigUint = holders.iter().map(|(_, balance)| balance).sum();
+    let mut allocations = Vec::with_capacity(holders.len());
+    for (account, balance) in holders {
+        let amount = match rule {
+            AllocationRule::Balance => balance,
+            AllocationRule::Fixed(amount) => amount.clone(),
+            AllocationRule::Proportional(amount) => amount * balance / &total,
+        };
+        if amount.bits() > 251 {
+            return Err(eyre::eyre!(
+                "Allocation {} for account {:#064x} does not fit in a felt",
+                amount,
+                account
+            ));
+        }
+        if !amount.is_zero() {
+            allocations.push((account, Felt::from(amount)));
+        }
+    }
+    Ok(allocations)
+}
+
+/// Build the layers of the tree, from the sorted leaves up to the root
+///
+/// A node without a sibling is promoted to the next layer unchanged.
+fn build_layers(mut leaves: Vec<Felt>, hash: MerkleHash) -> Vec<Vec<Felt>> {
+    leaves.sort();
+    let mut layers = vec![leaves];
+    while layers.last().map(|layer| layer.len() > 1).unwrap_or(false) {
+        let next = layers
+            .last()
+            .unwrap()
+            .chunks(2)
+            .map(|pair| match pair {
+                [a, b] => hash.node(a, b),
+                [a] => *a,
+                _ => unreachable!(),
+            })
+            .collect();
+        layers.push(next);
+    }
+    layers
+}
+
+/// Collect the sibling hashes from a leaf up to the root
+fn proof_of(layers: &[Vec<Felt>], mut index: usize) -> Vec<Felt> {
+    let mut proof = Vec::new();
+    for layer in &layers[..layers.len() - 1] {
+        if let Some(sibling) = layer.get(index ^ 1) {
+            proof.push(*sibling);
+        }
+        index /= 2;
+    }
+    proof
+}
+
+/// Allocate the airdrop from the snapshot and build its merkle tree and proofs
+pub fn build_airdrop(
+    snapshot: &BalanceSnapshot,
+    token: &Felt,
+    rule: &AllocationRule,
+    hash: MerkleHash,
+) -> Result<Airdrop> {
+    let allocations = allocate(snapshot, token, rule)?;
+    if allocations.is_empty() {
+        return Err(eyre::eyre!(
+            "No holder of token {:#064x} receives an allocation",
+            token
+        ));
+    }
+
+    let leaves: Vec<Felt> = allocations
+        .iter()
+        .map(|(account, amount)| hash.leaf(account, amount))
+        .collect();
+    let layers = build_layers(leaves.clone(), hash);
+
+    let claims: Vec<Claim> = allocations
+        .into_iter()
+        .zip(leaves)
+        .map(|((account, amount), leaf)| {
+            let index = layers[0]
+                .binary_search(&leaf)
+                .expect("every leaf is part of the first layer");
+            Claim {
+                account,
+                amount,
+                leaf,
+                proof: proof_of(&layers, index),
+            }
+        })
+        .collect();
+
+    let root = layers.last().unwrap()[0];
+    debug_assert!(claims
+        .iter()
+        .all(|claim| verify_proof(hash, &root, &claim.leaf, &claim.proof)));
+
+    Ok(Airdrop {
+        token: *token,
+        hash,
+        root,
+        claims,
+    })
+}
+
+/// Check a proof the same way a claim contract does
+pub fn verify_proof(hash: MerkleHash, root: &Felt, leaf: &Felt, proof: &[Felt]) -> bool {
+    let computed = proof
+        .iter()
+        .fold(*leaf, |node, sibling| hash.node(&node, sibling));
+    &computed == root
+}
+
+/// Write the root and all claims to a JSON file, replacing an existing one only with `force`
+pub fn write_airdrop(airdrop: &Airdrop, destination: &Destination, force: bool) -> Result<()> {
+    destination
+        .write_with(force, |w| {
+            serde_json::to_writer_pretty(w, airdrop)?;
+            Ok(())
+        })
+        .map_err(|e| eyre::eyre!("Failed to store airdrop: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn snapshot(token: Felt, balances: &[(u64, u64)]) -> BalanceSnapshot {
+        BalanceSnapshot {
+            balances: HashMap::from([(
+                token,
+                balances
+                    .iter()
+                    .map(|(account, balance)| (Felt::from(*account), Felt::from(*balance)))
+                    .collect(),
+            )]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_allocation_rule() {
+        assert_eq!("balance".parse(), Ok(AllocationRule::Balance));
+        assert_eq!(
+            "fixed:100".parse(),
+            Ok(AllocationRule::Fixed(BigUint::from(100u64)))
+        );
+        assert_eq!(
+            "proportional:1000".parse(),
+            Ok(AllocationRule::Proportional(BigUint::from(1000u64)))
+        );
+        assert!("fixed".parse::<AllocationRule>().is_err());
+        assert!("random:5".parse::<AllocationRule>().is_err());
+    }
+
+    #[test]
+    fn test_proportional_allocation() -> Result<()> {
+        let token = Felt::from(1u64);
+        let snapshot = snapshot(token, &[(10, 100), (11, 300), (12, 0)]);
+
+        let allocations = allocate(
+            &snapshot,
+            &token,
+            &AllocationRule::Proportional(BigUint::from(1000u64)),
+        )?;
+
+        assert_eq!(
+            allocations,
+            vec![
+                (Felt::from(10u64), Felt::from(250u64)),
+                (Felt::from(11u64), Felt::from(750u64)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_every_proof_verifies() -> Result<()> {
+        let token = Felt::from(1u64);
+        // An odd number of leaves exercises the promotion of unpaired nodes
+        let snapshot = snapshot(token, &[(10, 1), (11, 2), (12, 3), (13, 4), (14, 5)]);
+
+        for hash in [MerkleHash::Pedersen, MerkleHash::Poseidon] {
+            let airdrop = build_airdrop(&snapshot, &token, &AllocationRule::Balance, hash)?;
+            assert_eq!(airdrop.claims.len(), 5);
+            for claim in &airdrop.claims {
+                assert_eq!(claim.leaf, hash.leaf(&claim.account, &claim.amount));
+                assert!(verify_proof(hash, &airdrop.root, &claim.leaf, &claim.proof));
+                assert!(!verify_proof(
+                    hash,
+                    &airdrop.root,
+                    &hash.leaf(&claim.account, &(claim.amount + Felt::ONE)),
+                    &claim.proof
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_single_claim_root_is_leaf() -> Result<()> {
+        let token = Felt::from(1u64);
+        let snapshot = snapshot(token, &[(10, 42)]);
+
+        let airdrop = build_airdrop(
+            &snapshot,
+            &token,
+            &AllocationRule::Fixed(BigUint::from(5u64)),
+            MerkleHash::Pedersen,
+        )?;
+
+        let claim = &airdrop.claims[0];
+        assert_eq!(claim.amount, Felt::from(5u64));
+        assert!(claim.proof.is_empty());
+        assert_eq!(airdrop.root, claim.leaf);
+        Ok(())
+    }
+}
@@ -0,0 +1,453 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use bigdecimal::BigDecimal;
+use eyre::Result;
+use rusqlite::{Connection, OpenFlags};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use starknet::core::types::Felt;
+use tracing::{error, info, warn};
+
+use crate::balance::{balance_of, head_block_number, Addresses, DEFAULT_DECIMALS};
+use crate::destination::Destination;
+use crate::valuation::to_units;
+
+/// Time allowed for a webhook to answer
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Evaluate balance alert rules against the database and post the alerts to a webhook
+#[derive(Debug, clap::Args)]
+pub struct AlertArgs {
+    /// Path to the JSON alert rules
+    #[arg(long, env = "ALERT_RULES")]
+    rules: PathBuf,
+
+    /// URL the alerts are posted to as JSON
+    #[arg(long, env = "ALERT_WEBHOOK")]
+    webhook: String,
+
+    /// Path to the database
+    #[arg(short, long, env = "DB_PATH")]
+    db_path: String,
+
+    /// Path to an addresses JSON file with the token symbols and decimals and the account
+    /// labels added to the alerts
+    #[arg(short, long, env = "INPUT_FILE")]
+    input_file: Option<String>,
+
+    /// File keeping the last balance and firing state of every rule across runs
+    #[arg(long, env = "ALERT_STATE", default_value = "alerts_state.json")]
+    state: PathBuf,
+
+    /// Evaluate the rules again every this many seconds instead of once
+    #[arg(long)]
+    interval: Option<u64>,
+}
+
+/// A balance to watch and when to alert about it
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AlertRule {
+    /// Unique name, also the key of the rule's persisted state
+    pub name: String,
+    pub token: Felt,
+    pub account: Felt,
+    #[serde(flatten)]
+    pub condition: Condition,
+}
+
+/// Alert condition, with amounts in whole token units
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// Fires when the balance drops below the amount, resolves once it is back
+    Below(#[serde(deserialize_with = "amount")] BigDecimal),
+    /// Fires when the balance rises above the amount, resolves once it is back
+    Above(#[serde(deserialize_with = "amount")] BigDecimal),
+    /// Fires when the balance moved by more than the amount since the previous evaluation
+    ChangeOver(#[serde(deserialize_with = "amount")] BigDecimal),
+}
+
+/// Amounts can be given as JSON strings (preferred, no precision loss) or numbers
+fn amount<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BigDecimal, D::Error> {
+    let text = match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(text) => text,
+        serde_json::Value::Number(number) => number.to_string(),
+        other => return Err(D::Error::custom(format!("invalid amount {other}"))),
+    };
+    BigDecimal::from_str(&text)
+        .map_err(|e| D::Error::custom(format!("invalid amount '{text}': {e}")))
+}
+
+/// Last evaluated balance of a rule and whether its alert is firing
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleState {
+    /// Raw balance
+    pub balance: String,
+    pub block_number: Option<u64>,
+    pub firing: bool,
+}
+
+/// rule name -> state
+pub type AlertState = BTreeMap<String, RuleState>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+    Changed,
+}
+
+/// JSON body posted to the webhook
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Alert {
+    pub rule: String,
+    pub status: AlertStatus,
+    pub token: String,
+    pub symbol: Option<String>,
+    pub account: String,
+    pub label: Option<String>,
+    pub block_number: Option<u64>,
+    /// Balances and threshold in token units
+    pub balance: String,
+    pub previous_balance: Option<String>,
+    pub threshold: String,
+}
+
+impl AlertRule {
+    /// Alert raised by the new balance of the rule, if any, and the rule's next state
+    fn check(
+        &self,
+        previous: Option<&RuleState>,
+        balance: &Felt,
+        block_number: Option<u64>,
+        decimals: u8,
+    ) -> (Option<AlertStatus>, RuleState) {
+        let units = to_units(balance, decimals);
+        let mut next = RuleState {
+            balance: balance.to_string(),
+            block_number,
+            firing: previous.is_some_and(|p| p.firing),
+        };
+        let status = match &self.condition {
+            Condition::Below(threshold) | Condition::Above(threshold) => {
+                let met = match self.condition {
+                    Condition::Below(_) => units < *threshold,
+                    _ => units > *threshold,
+                };
+                next.firing = met;
+                match (met, previous.is_some_and(|p| p.firing)) {
+                    (true, false) => Some(AlertStatus::Firing),
+                    (false, true) => Some(AlertStatus::Resolved),
+                    _ => None,
+                }
+            }
+            Condition::ChangeOver(threshold) => previous
+                .and_then(|p| Felt::from_dec_str(&p.balance).ok())
+                .map(|before| units.clone() - to_units(&before, decimals))
+                .filter(|delta| delta.abs() > *threshold)
+                .map(|_| AlertStatus::Changed),
+        };
+        (status, next)
+    }
+}
+
+/// Load the alert rules, rejecting duplicate names
+pub fn load_rules(path: &Path) -> Result<Vec<AlertRule>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| eyre::eyre!("Failed to read alert rules '{}': {}", path.display(), e))?;
+    let rules: Vec<AlertRule> = serde_json::from_str(&content)
+        .map_err(|e| eyre::eyre!("Failed to parse alert rules '{}': {}", path.display(), e))?;
+    let mut names = std::collections::HashSet::new();
+    if let Some(rule) = rules.iter().find(|rule| !names.insert(&rule.name)) {
+        eyre::bail!("Duplicate alert rule '{}'", rule.name);
+    }
+    Ok(rules)
+}
+
+/// Load the persisted alert state, empty if the file does not exist yet
+pub fn load_state(path: &Path) -> Result<AlertState> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map_err(|e| eyre::eyre!("Failed to parse alert state '{}': {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(AlertState::new()),
+        Err(e) => Err(eyre::eyre!(
+            "Failed to read alert state '{}': {}",
+            path.display(),
+            e
+        )),
+    }
+}
+
+pub fn write_state(state: &AlertState, path: &Path) -> Result<()> {
+    Destination::File(path.to_path_buf())
+        .write_with(true, |w| Ok(serde_json::to_writer_pretty(w, state)?))
+        .map_err(|e| eyre::eyre!("Failed to store alert state: {}", e))
+}
+
+/// Post an alert to the webhook as JSON
+pub fn post_webhook(url: &str, alert: &Alert) -> Result<()> {
+    ureq::post(url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .set("Content-Type", "application/json")
+        .send_string(&serde_json::to_string(alert)?)
+        .map_err(|e| eyre::eyre!("Failed to post alert '{}': {}", alert.rule, e))?;
+    Ok(())
+}
+
+/// Evaluate every rule at the head block and deliver the alerts raised
+///
+/// A rule's state only advances once its alert was delivered, so an alert that failed to
+/// be delivered is raised again by the next evaluation. A rule whose balance can't be read
+/// is skipped and the first such error returned once every other rule was evaluated, so
+/// the state keeps the alerts already delivered. Returns the alerts delivered.
+pub fn evaluate_rules(
+    conn: &Connection,
+    rules: &[AlertRule],
+    addresses: Option<&Addresses>,
+    state: &mut AlertState,
+    mut deliver: impl FnMut(&Alert) -> Result<()>,
+) -> Result<usize> {
+    let block_number = head_block_number(conn);
+    let at = block_number.unwrap_or(i64::MAX as u64);
+    let mut delivered = 0;
+    let mut failed = None;
+    for rule in rules {
+        let token_info = addresses.and_then(|a| a.token_info.get(&rule.token));
+        let decimals = token_info.map_or(DEFAULT_DECIMALS, |info| info.decimals_or_default());
+        let balance = match balance_of(conn, &rule.token, &rule.account, at) {
+            Ok(balance) => balance,
+            Err(e) => {
+                warn!(rule = %rule.name, "{}", e);
+                failed.get_or_insert(e);
+                continue;
+            }
+        };
+        let previous = state.get(&rule.name);
+        let (status, next) = rule.check(previous, &balance, block_number, decimals);
+
+        if let Some(status) = status {
+            let threshold = match &rule.condition {
+                Condition::Below(amount)
+                | Condition::Above(amount)
+                | Condition::ChangeOver(amount) => amount,
+            };
+            let alert = Alert {
+                rule: rule.name.clone(),
+                status,
+                token: format!("{:#064x}", rule.token),
+                symbol: token_info.and_then(|info| info.symbol.clone()),
+                account: format!("{:#064x}", rule.account),
+                label: addresses
+                    .and_then(|a| a.labels.get(&rule.account))
+                    .and_then(|label| label.label.clone()),
+                block_number,
+                balance: to_units(&balance, decimals).to_string(),
+                previous_balance: previous
+                    .and_then(|p| Felt::from_dec_str(&p.balance).ok())
+                    .map(|before| to_units(&before, decimals).to_string()),
+                threshold: threshold.to_string(),
+            };
+            if let Err(e) = deliver(&alert) {
+                warn!(rule = %rule.name, "{}", e);
+                continue;
+            }
+            info!(rule = %rule.name, status = ?status, balance = %alert.balance, "Alert delivered");
+            delivered += 1;
+        }
+        state.insert(rule.name.clone(), next);
+    }
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(delivered),
+    }
+}
+
+/// Evaluate the rules once, or every interval until the process is stopped
+pub fn run_alerts(args: &AlertArgs) -> Result<()> {
+    let rules = load_rules(&args.rules)?;
+    let addresses = args.input_file.as_ref().map(Addresses::load).transpose()?;
+    let conn = Connection::open_with_flags(&args.db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| eyre::eyre!("Failed to open database '{}': {}", args.db_path, e))?;
+    let mut state = load_state(&args.state)?;
+
+    loop {
+        let evaluated = evaluate_rules(&conn, &rules, addresses.as_ref(), &mut state, |alert| {
+            post_webhook(&args.webhook, alert)
+        });
+        // Delivered alerts are persisted even when other rules failed, so they don't fire twice
+        let evaluated = write_state(&state, &args.state).and(evaluated);
+        // A failed evaluation, e.g. while Pathfinder holds a lock, is retried next interval
+        match (evaluated, args.interval) {
+            (Ok(delivered), _) => info!(rules = rules.len(), delivered, "Evaluated alert rules"),
+            (Err(e), Some(_)) => error!("Failed to evaluate alert rules: {:#}", e),
+            (Err(e), None) => return Err(e),
+        }
+
+        match args.interval {
+            Some(interval) => std::thread::sleep(Duration::from_secs(interval)),
+            None => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::balance::{create_storage_tables, insert_balance};
+
+    fn set_balance(conn: &Connection, account: u64, balance: u64, block: u64) -> Result<()> {
+        insert_balance(
+            conn,
+            &Felt::from(0x100u64),
+            &Felt::from(account),
+            balance,
+            block,
+        )?;
+        conn.execute("INSERT OR IGNORE INTO block_headers VALUES (?1)", [block])?;
+        Ok(())
+    }
+
+    fn create_database() -> Result<Connection> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute(
+            "CREATE TABLE block_headers (number INTEGER PRIMARY KEY)",
+            [],
+        )?;
+        create_storage_tables(&conn)?;
+        Ok(conn)
+    }
+
+    #[test]
+    fn test_alert_rules() -> Result<()> {
+        let rules: Vec<AlertRule> = serde_json::from_str(
+            r#"[
+                {"name": "hot-low", "token": "0x100", "account": "0x1", "below": "10"},
+                {"name": "treasury-moved", "token": "0x100", "account": "0x2", "change_over": 0.5}
+            ]"#,
+        )?;
+        assert_eq!(
+            rules[1].condition,
+            Condition::ChangeOver(BigDecimal::from_str("0.5")?)
+        );
+        let addresses: Addresses = serde_json::from_str(
+            r#"{"accounts": [], "tokens": [{"address": "0x100", "symbol": "STRK", "decimals": 2}]}"#,
+        )?;
+
+        let conn = create_database()?;
+        set_balance(&conn, 1, 5000, 1)?;
+        set_balance(&conn, 2, 1000, 1)?;
+        let mut state = AlertState::new();
+        let mut alerts = Vec::new();
+        let mut evaluate = |state: &mut AlertState, fail: bool| {
+            evaluate_rules(&conn, &rules, Some(&addresses), state, |alert| {
+                if fail {
+                    eyre::bail!("webhook down");
+                }
+                alerts.push(alert.clone());
+                Ok(())
+            })
+        };
+
+        // First evaluation only records the balances
+        assert_eq!(evaluate(&mut state, false)?, 0);
+        assert_eq!(state["treasury-moved"].balance, "1000");
+
+        set_balance(&conn, 1, 900, 2)?;
+        set_balance(&conn, 2, 1040, 2)?;
+        assert_eq!(evaluate(&mut state, false)?, 1);
+        assert!(state["hot-low"].firing);
+        // Still below: the alert does not fire again
+        assert_eq!(evaluate(&mut state, false)?, 0);
+
+        // A failed delivery leaves the state as it was, so the alert is raised again
+        set_balance(&conn, 1, 2000, 3)?;
+        set_balance(&conn, 2, 1100, 3)?;
+        assert_eq!(evaluate(&mut state, true)?, 0);
+        assert!(state["hot-low"].firing);
+        assert_eq!(state["treasury-moved"].balance, "1040");
+        assert_eq!(evaluate(&mut state, false)?, 2);
+        assert!(!state["hot-low"].firing);
+
+        let statuses: Vec<_> = alerts.iter().map(|a| (a.rule.as_str(), a.status)).collect();
+        assert_eq!(
+            statuses,
+            [
+                ("hot-low", AlertStatus::Firing),
+                ("hot-low", AlertStatus::Resolved),
+                ("treasury-moved", AlertStatus::Changed),
+            ]
+        );
+        assert_eq!(alerts[0].symbol.as_deref(), Some("STRK"));
+        assert_eq!(alerts[0].balance, "9.00");
+        assert_eq!(alerts[0].threshold, "10");
+        assert_eq!(alerts[2].previous_balance.as_deref(), Some("10.40"));
+
+        // A rule that can't be read doesn't undo the alerts delivered before it
+        set_balance(&conn, 1, 100, 4)?;
+        set_balance(&conn, 2, 5000, 4)?;
+        let result = evaluate_rules(&conn, &rules, Some(&addresses), &mut state, |_| {
+            conn.execute_batch("ALTER TABLE storage_updates RENAME TO locked")?;
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert!(state["hot-low"].firing);
+        assert_eq!(state["treasury-moved"].balance, "1100");
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_and_webhook() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("state.json");
+        assert!(load_state(&path)?.is_empty());
+        let state = AlertState::from([(
+            "hot-low".to_string(),
+            RuleState {
+                balance: "900".to_string(),
+                block_number: Some(2),
+                firing: true,
+            },
+        )]);
+        write_state(&state, &path)?;
+        assert_eq!(load_state(&path)?, state);
+
+        // Local stub receiving the alert
+        let server = tiny_http::Server::http("127.0.0.1:0").map_err(|e| eyre::eyre!(e))?;
+        let url = format!("http://{}/alerts", server.server_addr().to_ip().unwrap());
+        let alert = Alert {
+            rule: "hot-low".to_string(),
+            status: AlertStatus::Firing,
+            token: format!("{:#064x}", Felt::from(0x100u64)),
+            symbol: Some("STRK".to_string()),
+            account: format!("{:#064x}", Felt::ONE),
+            label: None,
+            block_number: Some(2),
+            balance: "0.9".to_string(),
+            previous_balance: Some("5".to_string()),
+            threshold: "1".to_string(),
+        };
+        let received = std::thread::scope(|scope| {
+            let stub = scope.spawn(|| {
+                let mut request = server.recv().unwrap();
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let path = request.url().to_string();
+                request.respond(tiny_http::Response::empty(204)).unwrap();
+                (path, body)
+            });
+            post_webhook(&url, &alert)?;
+            eyre::Ok(stub.join().unwrap())
+        })?;
+        assert_eq!(received.0, "/alerts");
+        let body: serde_json::Value = serde_json::from_str(&received.1)?;
+        assert_eq!(body["status"], "firing");
+        assert_eq!(body["symbol"], "STRK");
+        assert_eq!(body["balance"], "0.9");
+        Ok(())
+    }
+}
@@ -1,15 +1,404 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 
 use eyre::Result;
 use rayon::prelude::*;
-use rusqlite::Connection;
-use serde::Deserialize;
+use rusqlite::types::Value;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use starknet::{core::crypto::pedersen_hash, core::types::Felt, core::utils::starknet_keccak};
+use tracing::{debug, info};
+
+use crate::progress::{estimated_slots_per_token, ScanProgress};
+use crate::proof::VerificationStatus;
+use crate::stats::TokenStats;
+use crate::valuation::Valuation;
 
 #[derive(Deserialize)]
+#[serde(from = "RawAddresses")]
 pub struct Addresses {
     pub accounts: Vec<Felt>,
     pub tokens: Vec<Felt>,
+    /// Optional label and group for each account, keyed by account address
+    pub labels: HashMap<Felt, AccountLabel>,
+    /// Optional symbol and decimals for each token, keyed by token address
+    pub token_info: HashMap<Felt, TokenInfo>,
+}
+
+/// Human readable metadata attached to an account in the input file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AccountLabel {
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+/// Decimals assumed for tokens that don't specify them in the input file
+pub const DEFAULT_DECIMALS: u8 = 18;
+
+/// Metadata attached to a token in the input file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TokenInfo {
+    #[serde(default)]
+    pub symbol: Option<String>,
+    #[serde(default)]
+    pub decimals: Option<u8>,
+}
+
+impl TokenInfo {
+    /// Returns the token decimals, falling back to [`DEFAULT_DECIMALS`]
+    pub fn decimals_or_default(&self) -> u8 {
+        self.decimals.unwrap_or(DEFAULT_DECIMALS)
+    }
+}
+
+impl Addresses {
+    /// Read and parse an addresses JSON file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::load_with_hash(path).map(|(addresses, _)| addresses)
+    }
+
+    /// Read and parse an addresses JSON file, with the hex SHA-256 of its content
+    pub fn load_with_hash(path: impl AsRef<Path>) -> Result<(Self, String)> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| eyre::eyre!("Failed to read JSON file '{}': {}", path.display(), e))?;
+        let addresses = serde_json::from_str(&content)
+            .map_err(|e| eyre::eyre!("Failed to parse JSON file '{}': {}", path.display(), e))?;
+        Ok((addresses, hex::encode(Sha256::digest(content.as_bytes()))))
+    }
+}
+
+/// An account entry is either a bare address or an address with a label and group
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AccountEntry {
+    Address(Felt),
+    Labelled {
+        address: Felt,
+        #[serde(flatten)]
+        label: AccountLabel,
+    },
+}
+
+/// A token entry is either a bare address or an address with a symbol and decimals
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TokenEntry {
+    Address(Felt),
+    Described {
+        address: Felt,
+        #[serde(flatten)]
+        info: TokenInfo,
+    },
+}
+
+#[derive(Deserialize)]
+struct RawAddresses {
+    accounts: Vec<AccountEntry>,
+    tokens: Vec<TokenEntry>,
+}
+
+impl From<RawAddresses> for Addresses {
+    fn from(raw: RawAddresses) -> Self {
+        let mut accounts = Vec::with_capacity(raw.accounts.len());
+        let mut labels = HashMap::new();
+        for entry in raw.accounts {
+            match entry {
+                AccountEntry::Address(address) => accounts.push(address),
+                AccountEntry::Labelled { address, label } => {
+                    accounts.push(address);
+                    labels.insert(address, label);
+                }
+            }
+        }
+
+        let mut tokens = Vec::with_capacity(raw.tokens.len());
+        let mut token_info = HashMap::new();
+        for entry in raw.tokens {
+            match entry {
+                TokenEntry::Address(address) => tokens.push(address),
+                TokenEntry::Described { address, info } => {
+                    tokens.push(address);
+                    token_info.insert(address, info);
+                }
+            }
+        }
+
+        Self {
+            accounts,
+            tokens,
+            labels,
+            token_info,
+        }
+    }
+}
+
+/// Balances per token and account, together with the account and token metadata from the input
+#[derive(Debug, Clone, Default)]
+pub struct BalanceSnapshot {
+    /// token -> account -> balance
+    pub balances: HashMap<Felt, HashMap<Felt, Felt>>,
+    pub labels: HashMap<Felt, AccountLabel>,
+    pub token_info: HashMap<Felt, TokenInfo>,
+    /// Fiat values of the balances, if a price file was given
+    pub valuation: Option<Valuation>,
+    /// Holder statistics per token, once computed
+    pub stats: Option<BTreeMap<Felt, TokenStats>>,
+    /// Storage proof verification status per token and account, if verified
+    pub verification: Option<HashMap<Felt, HashMap<Felt, VerificationStatus>>>,
+    /// Head block of the database the balances were read at, if it has block headers
+    pub block_number: Option<u64>,
+    /// Rows read by every shard of the storage scan
+    pub scan: Vec<ShardScan>,
+}
+
+/// Rows read by one shard of a token's storage scan
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardScan {
+    pub token: Felt,
+    pub shard: usize,
+    /// Storage slots of the token in this shard
+    pub rows_scanned: u64,
+    /// Slots that are the balance of a requested account
+    pub rows_matched: u64,
+    pub elapsed_ms: u64,
+}
+
+impl BalanceSnapshot {
+    /// Returns the label of an account, if one was given in the input
+    pub fn label_of(&self, account: &Felt) -> Option<&AccountLabel> {
+        self.labels.get(account)
+    }
+
+    /// Returns the verification status of a balance, if the snapshot was verified
+    pub fn verification_of(&self, token: &Felt, account: &Felt) -> Option<&VerificationStatus> {
+        self.verification
+            .as_ref()
+            .and_then(|v| v.get(token))
+            .and_then(|m| m.get(account))
+    }
+
+    /// Returns the decimals of a token, falling back to [`DEFAULT_DECIMALS`]
+    pub fn decimals_of(&self, token: &Felt) -> u8 {
+        self.token_info
+            .get(token)
+            .map(TokenInfo::decimals_or_default)
+            .unwrap_or(DEFAULT_DECIMALS)
+    }
+}
+
+/// Storage address of an account's entry in the `ERC20_balances` mapping
+pub fn balance_storage_key(account: &Felt) -> Felt {
+    pedersen_hash(&starknet_keccak("ERC20_balances".as_bytes()), account)
+}
+
+/// Create the Pathfinder contract and storage tables read by the balance queries
+#[cfg(test)]
+pub(crate) fn create_storage_tables(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE contract_addresses (id INTEGER PRIMARY KEY, contract_address BLOB NOT NULL);
+        CREATE TABLE storage_addresses (id INTEGER PRIMARY KEY, storage_address BLOB NOT NULL);
+        CREATE TABLE storage_updates (
+            contract_address_id INTEGER NOT NULL,
+            storage_address_id INTEGER NOT NULL,
+            storage_value BLOB NOT NULL,
+            block_number INTEGER NOT NULL
+        );",
+    )?;
+    Ok(())
+}
+
+/// Record an update of a contract's storage slot, registering the contract and slot if new
+#[cfg(test)]
+pub(crate) fn insert_storage(
+    conn: &Connection,
+    contract: &Felt,
+    key: &Felt,
+    value: &Felt,
+    block_number: u64,
+) -> Result<()> {
+    let (contract, key) = (contract.to_bytes_be().to_vec(), key.to_bytes_be().to_vec());
+    conn.execute(
+        "INSERT INTO contract_addresses (contract_address) SELECT ?1
+        WHERE NOT EXISTS (SELECT 1 FROM contract_addresses WHERE contract_address = ?1)",
+        [&contract],
+    )?;
+    conn.execute(
+        "INSERT INTO storage_addresses (storage_address) SELECT ?1
+        WHERE NOT EXISTS (SELECT 1 FROM storage_addresses WHERE storage_address = ?1)",
+        [&key],
+    )?;
+    conn.execute(
+        "INSERT INTO storage_updates
+        SELECT c.id, s.id, ?3, ?4 FROM contract_addresses c, storage_addresses s
+        WHERE c.contract_address = ?1 AND s.storage_address = ?2",
+        rusqlite::params![contract, key, value.to_bytes_be().to_vec(), block_number],
+    )?;
+    Ok(())
+}
+
+/// Record an account's balance of a token from a block on
+#[cfg(test)]
+pub(crate) fn insert_balance(
+    conn: &Connection,
+    token: &Felt,
+    account: &Felt,
+    balance: u64,
+    block_number: u64,
+) -> Result<()> {
+    insert_storage(
+        conn,
+        token,
+        &balance_storage_key(account),
+        &Felt::from(balance),
+        block_number,
+    )
+}
+
+/// Latest value of a storage slot of a contract at or before a block, zero if never set
+pub fn storage_value(
+    conn: &Connection,
+    contract_address: &Felt,
+    key: &Felt,
+    block_number: u64,
+) -> Result<Felt> {
+    conn.query_row(
+        "SELECT storage_value
+         FROM storage_updates
+            JOIN storage_addresses ON storage_addresses.id = storage_updates.storage_address_id
+            JOIN contract_addresses ON contract_addresses.id = storage_updates.contract_address_id
+         WHERE contract_address = ?1 AND storage_address = ?2 AND block_number <= ?3
+         ORDER BY block_number DESC LIMIT 1",
+        rusqlite::params![
+            contract_address.to_bytes_be().to_vec(),
+            key.to_bytes_be().to_vec(),
+            block_number as i64
+        ],
+        |row| row.get::<_, Vec<u8>>(0),
+    )
+    .optional()
+    .map(|value| value.map_or(Felt::ZERO, |blob| Felt::from_bytes_be_slice(&blob)))
+    .map_err(|e| eyre::eyre!("Failed to query storage value: {}", e))
+}
+
+/// Balance of an account at or before a block, zero if never set
+pub fn balance_of(
+    conn: &Connection,
+    token: &Felt,
+    account: &Felt,
+    block_number: u64,
+) -> Result<Felt> {
+    storage_value(conn, token, &balance_storage_key(account), block_number)
+}
+
+/// Storage slots read per query of [`balances_of`], well below SQLite's variable limit
+const BALANCE_BATCH: usize = 500;
+
+/// Balances of several accounts at or before a block, read in batches of slots
+///
+/// Accounts whose balance was never set are left out.
+pub fn balances_of(
+    conn: &Connection,
+    token: &Felt,
+    accounts: &[Felt],
+    block_number: u64,
+) -> Result<HashMap<Felt, Felt>> {
+    let accounts_by_key: HashMap<Felt, Felt> = accounts
+        .par_iter()
+        .map(|account| (balance_storage_key(account), *account))
+        .collect();
+    let keys: Vec<&Felt> = accounts_by_key.keys().collect();
+
+    let mut balances = HashMap::with_capacity(accounts.len());
+    for chunk in keys.chunks(BALANCE_BATCH) {
+        // The value of the bare column comes from the row of the latest block
+        let mut stmt = conn
+            .prepare_cached(&format!(
+                "SELECT storage_address, storage_value, MAX(block_number)
+                 FROM storage_updates
+                    JOIN storage_addresses ON storage_addresses.id = storage_updates.storage_address_id
+                    JOIN contract_addresses ON contract_addresses.id = storage_updates.contract_address_id
+                 WHERE contract_address = ? AND block_number <= ? AND storage_address IN ({})
+                 GROUP BY storage_address_id",
+                vec!["?"; chunk.len()].join(", ")
+            ))
+            .map_err(|e| eyre::eyre!("Failed to prepare SQL statement: {}", e))?;
+        let params = [
+            Value::Blob(token.to_bytes_be().to_vec()),
+            Value::Integer(block_number as i64),
+        ]
+        .into_iter()
+        .chain(
+            chunk
+                .iter()
+                .map(|key| Value::Blob(key.to_bytes_be().to_vec())),
+        );
+        let rows = stmt
+            .query_map(rusqlite::params_from_iter(params), |row| {
+                let key: Vec<u8> = row.get(0)?;
+                let value: Vec<u8> = row.get(1)?;
+                Ok((
+                    Felt::from_bytes_be_slice(&key),
+                    Felt::from_bytes_be_slice(&value),
+                ))
+            })
+            .map_err(|e| eyre::eyre!("Failed to query balances: {}", e))?;
+        for row in rows {
+            let (key, balance) = row.map_err(|e| eyre::eyre!("Failed to read balance: {}", e))?;
+            if let Some(account) = accounts_by_key.get(&key) {
+                balances.insert(*account, balance);
+            }
+        }
+    }
+    Ok(balances)
+}
+
+/// Every update of an account's balance up to a block, as (block number, balance) in block order
+pub fn balance_history(
+    conn: &Connection,
+    token: &Felt,
+    account: &Felt,
+    up_to: u64,
+) -> Result<Vec<(u64, Felt)>> {
+    let mut stmt = conn
+        .prepare_cached(
+            "SELECT block_number, storage_value
+             FROM storage_updates
+                JOIN storage_addresses ON storage_addresses.id = storage_updates.storage_address_id
+                JOIN contract_addresses ON contract_addresses.id = storage_updates.contract_address_id
+             WHERE contract_address = ?1 AND storage_address = ?2 AND block_number <= ?3
+             ORDER BY block_number",
+        )
+        .map_err(|e| eyre::eyre!("Failed to prepare SQL statement: {}", e))?;
+    let history = stmt
+        .query_map(
+            rusqlite::params![
+                token.to_bytes_be().to_vec(),
+                balance_storage_key(account).to_bytes_be().to_vec(),
+                up_to as i64
+            ],
+            |row| {
+                let block_number: i64 = row.get(0)?;
+                let value: Vec<u8> = row.get(1)?;
+                Ok((block_number as u64, Felt::from_bytes_be_slice(&value)))
+            },
+        )
+        .and_then(|rows| rows.collect())
+        .map_err(|e| eyre::eyre!("Failed to query balance history: {}", e));
+    history
+}
+
+/// Number of the latest block in `block_headers`, `None` for databases without headers
+pub fn head_block_number(conn: &Connection) -> Option<u64> {
+    conn.query_row("SELECT MAX(number) FROM block_headers", [], |row| {
+        row.get::<_, Option<i64>>(0)
+    })
+    .ok()
+    .flatten()
+    .map(|number| number as u64)
 }
 
 // Helper function to create a new database connection
@@ -18,12 +407,15 @@ fn create_connection(db_path: &str) -> Result<Connection> {
         .map_err(|e| eyre::eyre!("Failed to create database connection: {}", e))
 }
 
+/// Read the balances of every account for every token at the head block
+///
+/// The progress display expects the storage slots given per token in `estimates`, e.g. the
+/// rows scanned by the previous run, and the table statistics for the other tokens.
 pub fn get_balance_map(
     conn: &Connection,
     addresses: &Addresses,
-) -> Result<HashMap<Felt, HashMap<Felt, Felt>>> {
-    let total_start = std::time::SystemTime::now();
-
+    estimates: &HashMap<Felt, u64>,
+) -> Result<BalanceSnapshot> {
     // Get the database path from the connection
     let db_path = conn
         .path()
@@ -44,34 +436,52 @@ pub fn get_balance_map(
         .collect();
     let hashing_end = std::time::SystemTime::now();
     let hash_time = hashing_end.duration_since(hashing_start).unwrap();
-    println!("Hashing time: {:?} ms", hash_time.as_millis());
+    debug!(
+        accounts = accounts_hash_map.len(),
+        elapsed_ms = hash_time.as_millis() as u64,
+        "Hashed balance storage keys"
+    );
 
     // Step 2: Process each token in parallel
     let parallel_processing_start = std::time::SystemTime::now();
 
     let num_tokens = addresses.tokens.len();
     let num_cores = rayon::current_num_threads();
-    println!("Processing {num_tokens} tokens using {num_cores} CPU cores");
     // Determine how many shards (DB partitions) to use per token to saturate all cores
     let shards_per_token = std::cmp::max(1, num_cores / std::cmp::max(1, num_tokens));
-    println!(
-        "Using {} shards per token ({} total concurrent DB connections)",
+    let average_estimate = estimated_slots_per_token(conn);
+    info!(
+        tokens = num_tokens,
+        cores = num_cores,
         shards_per_token,
-        shards_per_token * std::cmp::max(1, num_tokens)
+        connections = shards_per_token * std::cmp::max(1, num_tokens),
+        estimated_rows_per_token = average_estimate,
+        "Reading balances"
     );
+    let progress = ScanProgress::new();
 
-    let token_results: Vec<Result<(Felt, HashMap<Felt, Felt>)>> = addresses
+    type TokenResult = (Felt, HashMap<Felt, Felt>, Vec<ShardScan>);
+    let token_results: Vec<Result<TokenResult>> = addresses
         .tokens
         .par_iter()
         .map(|token| {
             // Create placeholders for this token
             let token_hex = format!("{token:#064x}")[2..].to_string();
             let token_bytes = hex::decode(&token_hex).unwrap_or_default();
+            let name = addresses
+                .token_info
+                .get(token)
+                .and_then(|info| info.symbol.clone())
+                .unwrap_or_else(|| format!("{token:#064x}"));
+            let estimate = estimates.get(token).copied().or(average_estimate);
+            let token_progress = progress.token(name, shards_per_token, estimate);
 
             // Run shards in parallel for this token
-            let shard_results: Vec<Result<HashMap<Felt, Felt>>> = (0..shards_per_token)
+            let shard_results: Vec<Result<(HashMap<Felt, Felt>, ShardScan)>> = (0
+                ..shards_per_token)
                 .into_par_iter()
                 .map(|shard_idx| {
+                    let shard_start = std::time::SystemTime::now();
                     // Each shard uses its own DB connection
                     let shard_conn = create_connection(&db_path)?;
 
@@ -125,8 +535,12 @@ pub fn get_balance_map(
                     // Process rows directly into a HashMap for this shard
                     let mut shard_balances: HashMap<Felt, Felt> =
                         HashMap::with_capacity(accounts_hash_map.len());
+                    let mut rows_scanned = 0;
+                    let mut shard_progress = token_progress.shard(shard_idx);
                     for row in rows {
                         let (_contract_address_hex, storage_addr, storage_val, _max_block) = row?;
+                        rows_scanned += 1;
+                        shard_progress.inc();
                         let storage_str = format!("0x{storage_addr}");
                         let storage_addr_felt = match Felt::from_hex(&storage_str) {
                             Ok(f) => f,
@@ -141,21 +555,43 @@ pub fn get_balance_map(
                         let balance_felt = Felt::from_hex(&storage_val).unwrap_or(Felt::ZERO);
                         shard_balances.insert(*account, balance_felt);
                     }
-                    Ok(shard_balances)
+                    shard_progress.finish();
+
+                    let shard_time = std::time::SystemTime::now()
+                        .duration_since(shard_start)
+                        .unwrap();
+                    let scan = ShardScan {
+                        token: *token,
+                        shard: shard_idx,
+                        rows_scanned,
+                        rows_matched: shard_balances.len() as u64,
+                        elapsed_ms: shard_time.as_millis() as u64,
+                    };
+                    debug!(
+                        token = %format!("{token:#064x}"),
+                        shard = scan.shard,
+                        rows_scanned = scan.rows_scanned,
+                        rows_matched = scan.rows_matched,
+                        elapsed_ms = scan.elapsed_ms,
+                        "Scanned shard"
+                    );
+                    Ok((shard_balances, scan))
                 })
                 .collect();
 
             // Merge results from all shards for this token
             let mut token_balances: HashMap<Felt, Felt> =
                 HashMap::with_capacity(accounts_hash_map.len());
-            for shard_balances in shard_results {
-                let shard_balances = shard_balances?;
+            let mut scans = Vec::with_capacity(shards_per_token);
+            for shard_result in shard_results {
+                let (shard_balances, scan) = shard_result?;
                 for (account, balance) in shard_balances {
                     token_balances.insert(account, balance);
                 }
+                scans.push(scan);
             }
 
-            Ok((*token, token_balances))
+            Ok((*token, token_balances, scans))
         })
         .collect();
 
@@ -163,36 +599,40 @@ pub fn get_balance_map(
     let parallel_processing_time = parallel_processing_end
         .duration_since(parallel_processing_start)
         .unwrap();
-    println!(
-        "Parallel processing time: {:?} ms",
-        parallel_processing_time.as_millis()
+    debug!(
+        elapsed_ms = parallel_processing_time.as_millis() as u64,
+        "Scanned storage"
     );
 
     // Step 3: Merge results from all tokens
     let merging_start = std::time::SystemTime::now();
 
     let mut final_token_map: HashMap<Felt, HashMap<Felt, Felt>> = HashMap::new();
+    let mut scan = Vec::new();
 
     for token_result in token_results {
-        let (token, balances) = token_result?;
+        let (token, balances, scans) = token_result?;
         final_token_map.insert(token, balances);
+        scan.extend(scans);
     }
 
     let merging_end = std::time::SystemTime::now();
     let merging_time = merging_end.duration_since(merging_start).unwrap();
-    println!("Result merging time: {:?} ms", merging_time.as_millis());
-
-    let total_end = std::time::SystemTime::now();
-    let total_time = total_end.duration_since(total_start).unwrap();
-    println!("Total function time: {:?} ms", total_time.as_millis());
-
-    // Print summary for each token
-    for token in &addresses.tokens {
-        let balance_count = final_token_map.get(token).map(|m| m.len()).unwrap_or(0);
-        println!("#### Token: {token:#064x} - {balance_count} balances ######");
-    }
+    debug!(
+        elapsed_ms = merging_time.as_millis() as u64,
+        "Merged shard results"
+    );
 
-    Ok(final_token_map)
+    Ok(BalanceSnapshot {
+        balances: final_token_map,
+        labels: addresses.labels.clone(),
+        token_info: addresses.token_info.clone(),
+        valuation: None,
+        stats: None,
+        verification: None,
+        block_number: head_block_number(conn),
+        scan,
+    })
 }
 
 #[cfg(test)]
@@ -305,10 +745,12 @@ mod tests {
             tokens: vec![Felt::from_hex(
                 "0x0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20",
             )?],
+            labels: HashMap::new(),
+            token_info: HashMap::new(),
         };
 
         // Call get_balance_map
-        let result = get_balance_map(&conn, &addresses)?;
+        let result = get_balance_map(&conn, &addresses, &HashMap::new())?.balances;
 
         // Verify the results
         assert_eq!(result.len(), 1, "Should have 1 token");
@@ -360,10 +802,12 @@ mod tests {
             tokens: vec![Felt::from_hex(
                 "0x9999999999999999999999999999999999999999999999999999999999999999",
             )?],
+            labels: HashMap::new(),
+            token_info: HashMap::new(),
         };
 
         // Call get_balance_map
-        let result = get_balance_map(&conn, &addresses)?;
+        let result = get_balance_map(&conn, &addresses, &HashMap::new())?.balances;
 
         // Verify the results - should be empty for non-existent token
         assert_eq!(result.len(), 1, "Should have 1 token entry");
@@ -470,10 +914,12 @@ mod tests {
             tokens: vec![Felt::from_hex(
                 "0x0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20",
             )?],
+            labels: HashMap::new(),
+            token_info: HashMap::new(),
         };
 
         // Call get_balance_map
-        let result = get_balance_map(&conn, &addresses)?;
+        let result = get_balance_map(&conn, &addresses, &HashMap::new())?.balances;
 
         // Verify the results
         assert_eq!(result.len(), 1, "Should have 1 token");
@@ -0,0 +1,376 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use chrono::{DateTime, NaiveDate};
+use eyre::Result;
+use rayon::prelude::*;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use starknet::core::types::Felt;
+use tracing::info;
+
+use crate::balance::{balance_history, Addresses, DEFAULT_DECIMALS};
+use crate::destination::Destination;
+use crate::output::{AddressFormat, AmountFormat, Formatting};
+
+/// Balances of every account at the last block of every period of a date range
+#[derive(Debug, clap::Args)]
+pub struct ClosesArgs {
+    /// Path to the addresses JSON file
+    #[arg(short, long, env = "INPUT_FILE")]
+    input_file: String,
+
+    /// Path to the database
+    #[arg(short, long, env = "DB_PATH")]
+    db_path: String,
+
+    /// First UTC day of the range, e.g. 2024-01-01
+    #[arg(long)]
+    from: NaiveDate,
+
+    /// Last UTC day of the range, included
+    #[arg(long)]
+    to: NaiveDate,
+
+    /// Length of a period, e.g. `1d`, `6h` or `1w`, starting at midnight UTC of --from
+    #[arg(long, default_value = "1d")]
+    period: Period,
+
+    /// Format of balances: raw integer, hex integer or decimal token units
+    #[arg(long, value_enum, default_value = "raw")]
+    amount_format: AmountFormat,
+
+    /// Decimal places of balances in token units, all token decimals by default
+    #[arg(long)]
+    precision: Option<u32>,
+
+    /// Format of token and account addresses
+    #[arg(long, value_enum, default_value = "padded")]
+    address_format: AddressFormat,
+
+    /// Path of the CSV table, `-` for stdout (default: token_map_closes.csv)
+    #[arg(long)]
+    output: Option<Destination>,
+
+    /// Overwrite an existing table
+    #[arg(long)]
+    force: bool,
+}
+
+/// Length of a period in seconds, parsed from `<count><s|m|h|d|w>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Period(pub i64);
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let unit_len = value.chars().last().map_or(0, char::len_utf8);
+        let (count, unit) = value.split_at(value.len() - unit_len);
+        let count: i64 = count
+            .parse()
+            .map_err(|e| format!("invalid period '{value}': {e}"))?;
+        let unit = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            _ => {
+                return Err(format!(
+                    "invalid period '{value}', expected e.g. 1d, 6h or 1w"
+                ))
+            }
+        };
+        if count <= 0 {
+            return Err(format!("invalid period '{value}', must be positive"));
+        }
+        count
+            .checked_mul(unit)
+            .map(Period)
+            .ok_or_else(|| format!("invalid period '{value}', too long"))
+    }
+}
+
+/// Last block of a period, the latest block with a timestamp before the period end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeriodClose {
+    /// Unix time of the start of the period, included
+    pub start: i64,
+    /// Unix time of the end of the period, excluded
+    pub end: i64,
+    pub block_number: u64,
+    pub block_timestamp: i64,
+}
+
+fn block_timestamp(conn: &Connection, number: u64) -> Result<i64> {
+    conn.query_row(
+        "SELECT timestamp FROM block_headers WHERE number = ?1",
+        [number as i64],
+        |row| row.get(0),
+    )
+    .map_err(|e| eyre::eyre!("Failed to read the timestamp of block {}: {}", number, e))
+}
+
+/// Latest block in `first..=head` with a timestamp before `time`
+///
+/// Block timestamps never decrease, so the block numbers are bisected instead of scanning
+/// the headers, which have no index on the timestamp.
+fn last_block_before(conn: &Connection, time: i64, first: u64, head: u64) -> Result<Option<u64>> {
+    if block_timestamp(conn, first)? >= time {
+        return Ok(None);
+    }
+    let (mut before, mut after) = (first, head + 1);
+    while after - before > 1 {
+        let middle = before + (after - before) / 2;
+        if block_timestamp(conn, middle)? < time {
+            before = middle;
+        } else {
+            after = middle;
+        }
+    }
+    Ok(Some(before))
+}
+
+/// Close of every period between unix times `from` and `to`
+///
+/// Periods that end after the head block, which are not closed yet, and periods before
+/// the first block of the database are left out.
+pub fn period_closes(
+    conn: &Connection,
+    from: i64,
+    to: i64,
+    period: Period,
+) -> Result<Vec<PeriodClose>> {
+    let Some((first, head)) = conn
+        .query_row(
+            "SELECT MIN(number), MAX(number) FROM block_headers",
+            [],
+            |row| Ok((row.get::<_, Option<i64>>(0)?, row.get::<_, Option<i64>>(1)?)),
+        )
+        .optional()
+        .map_err(|e| eyre::eyre!("Failed to read block headers: {}", e))?
+        .and_then(|(first, head)| Some((first? as u64, head? as u64)))
+    else {
+        eyre::bail!("The database has no block headers");
+    };
+    let head_timestamp = block_timestamp(conn, head)?;
+
+    let mut closes = Vec::new();
+    let mut start = from;
+    while start < to {
+        let end = start.saturating_add(period.0).min(to);
+        if end > head_timestamp {
+            info!(
+                period_end = %format_time(end),
+                head_block = head,
+                "Periods after the head block are not closed yet"
+            );
+            break;
+        }
+        if let Some(block_number) = last_block_before(conn, end, first, head)? {
+            closes.push(PeriodClose {
+                start,
+                end,
+                block_number,
+                block_timestamp: block_timestamp(conn, block_number)?,
+            });
+        }
+        start = end;
+    }
+    Ok(closes)
+}
+
+/// Balance at every close, from the balance updates in block order
+pub fn balances_at_closes(history: &[(u64, Felt)], closes: &[PeriodClose]) -> Vec<Felt> {
+    let mut updates = history.iter().peekable();
+    let mut balance = Felt::ZERO;
+    closes
+        .iter()
+        .map(|close| {
+            while let Some((_, value)) = updates.next_if(|(block, _)| *block <= close.block_number)
+            {
+                balance = *value;
+            }
+            balance
+        })
+        .collect()
+}
+
+/// ISO 8601 UTC time
+fn format_time(time: i64) -> String {
+    DateTime::from_timestamp(time, 0)
+        .map(|time| time.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+        .unwrap_or_else(|| time.to_string())
+}
+
+/// token -> account -> balance at every close
+type ClosingBalances = Vec<(Felt, Vec<(Felt, Vec<Felt>)>)>;
+
+fn write_closes_as_csv(
+    closes: &[PeriodClose],
+    balances: &ClosingBalances,
+    addresses: &Addresses,
+    formatting: &Formatting,
+    writer: &mut (dyn Write + Send),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record([
+        "Period Start",
+        "Period End",
+        "Block Number",
+        "Block Timestamp",
+        "Token",
+        "Account",
+        "Balance",
+    ])?;
+    for (index, close) in closes.iter().enumerate() {
+        for (token, accounts) in balances {
+            let decimals = addresses
+                .token_info
+                .get(token)
+                .map_or(DEFAULT_DECIMALS, |info| info.decimals_or_default());
+            for (account, balances) in accounts {
+                wtr.write_record([
+                    format_time(close.start),
+                    format_time(close.end),
+                    close.block_number.to_string(),
+                    format_time(close.block_timestamp),
+                    formatting.address(token),
+                    formatting.address(account),
+                    formatting.balance(&balances[index], decimals),
+                ])?;
+            }
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Write the balance of every account at the close of every period of the range
+pub fn run_closes(args: &ClosesArgs) -> Result<()> {
+    if args.to < args.from {
+        eyre::bail!("--to {} is before --from {}", args.to, args.from);
+    }
+    let addresses = Addresses::load(&args.input_file)?;
+
+    let open = || {
+        Connection::open_with_flags(&args.db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| eyre::eyre!("Failed to open database '{}': {}", args.db_path, e))
+    };
+    let from = args
+        .from
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc()
+        .timestamp();
+    let to = args
+        .to
+        .succ_opt()
+        .unwrap_or(args.to)
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc()
+        .timestamp();
+    let closes = period_closes(&open()?, from, to, args.period)?;
+    info!(
+        periods = closes.len(),
+        first_block = closes.first().map(|c| c.block_number),
+        last_block = closes.last().map(|c| c.block_number),
+        "Found period closes"
+    );
+
+    // Each token reads the balance history of every account once, with its own connection
+    let up_to = closes.last().map_or(0, |close| close.block_number);
+    let balances: ClosingBalances = addresses
+        .tokens
+        .par_iter()
+        .map(|token| {
+            let conn = open()?;
+            let accounts = addresses
+                .accounts
+                .iter()
+                .map(|account| {
+                    let history = balance_history(&conn, token, account, up_to)?;
+                    Ok((*account, balances_at_closes(&history, &closes)))
+                })
+                .collect::<Result<_>>()?;
+            Ok((*token, accounts))
+        })
+        .collect::<Result<_>>()?;
+
+    let formatting = Formatting {
+        amounts: args.amount_format,
+        precision: args.precision,
+        addresses: args.address_format,
+        ..Default::default()
+    };
+    let destination = args
+        .output
+        .clone()
+        .unwrap_or_else(|| Destination::File(PathBuf::from("token_map_closes.csv")));
+    destination
+        .write_with(args.force, |w| {
+            write_closes_as_csv(&closes, &balances, &addresses, &formatting, w)
+        })
+        .map_err(|e| eyre::eyre!("Failed to store closing balances: {}", e))?;
+    info!(path = %destination, "Closing balances written");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    #[test]
+    fn test_period_closes() -> Result<()> {
+        assert_eq!("1d".parse::<Period>(), Ok(Period(DAY)));
+        assert_eq!("6h".parse::<Period>(), Ok(Period(6 * 60 * 60)));
+        assert!("0d".parse::<Period>().is_err());
+        assert!("1y".parse::<Period>().is_err());
+        assert!("-1d".parse::<Period>().is_err());
+        assert!("9223372036854775807w".parse::<Period>().is_err());
+
+        // A block every 8 hours from block 100 at midnight of day 10, for 3 days
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE block_headers (number INTEGER PRIMARY KEY, timestamp INTEGER NOT NULL)",
+        )?;
+        for i in 0..9 {
+            conn.execute(
+                "INSERT INTO block_headers VALUES (?1, ?2)",
+                [100 + i, 10 * DAY + i * 8 * 60 * 60],
+            )?;
+        }
+
+        let closes = period_closes(&conn, 9 * DAY, 15 * DAY, Period(DAY))?;
+        // Day 9 is before the first block and day 12 is not closed yet
+        let blocks: Vec<u64> = closes.iter().map(|c| c.block_number).collect();
+        assert_eq!(blocks, [102, 105]);
+        assert_eq!(closes[0].start, 10 * DAY);
+        assert_eq!(closes[0].end, 11 * DAY);
+        assert_eq!(closes[0].block_timestamp, 10 * DAY + 16 * 60 * 60);
+        assert_eq!(format_time(closes[1].end), "1970-01-13T00:00:00Z");
+
+        let balances = balances_at_closes(
+            &[
+                (101, Felt::from(5u64)),
+                (104, Felt::from(7u64)),
+                (105, Felt::from(9u64)),
+            ],
+            &closes,
+        );
+        assert_eq!(balances, [Felt::from(5u64), Felt::from(9u64)]);
+        assert_eq!(balances_at_closes(&[], &closes), [Felt::ZERO, Felt::ZERO]);
+
+        // A period longer than the range closes at its end instead of overflowing
+        let longest: Period = "9223372036854775807s".parse().unwrap();
+        let closes = period_closes(&conn, 10 * DAY, 12 * DAY, longest)?;
+        assert_eq!(closes.len(), 1);
+        assert_eq!(closes[0].end, 12 * DAY);
+        assert!(period_closes(&conn, 10 * DAY, i64::MAX, longest)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,576 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use eyre::Result;
+use num_bigint::{BigInt, BigUint};
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use starknet::core::types::Felt;
+use tracing::info;
+
+use crate::destination::Destination;
+
+/// token -> account -> raw balance
+pub type Balances = BTreeMap<Felt, BTreeMap<Felt, BigUint>>;
+
+/// Compare two snapshots written by earlier runs, without reading the Pathfinder DB
+#[derive(Debug, clap::Args)]
+pub struct CompareArgs {
+    /// Earlier snapshot: a SQLite archive, or a CSV, JSON or NDJSON file (optionally .gz or .zst)
+    old: PathBuf,
+
+    /// Later snapshot
+    new: PathBuf,
+
+    /// Run of the earlier SQLite archive, by default the run before the later one when both
+    /// snapshots are the same archive, otherwise the latest
+    #[arg(long)]
+    old_run: Option<i64>,
+
+    /// Run of the later SQLite archive, the latest by default
+    #[arg(long)]
+    new_run: Option<i64>,
+
+    /// Format of the change report
+    #[arg(long, value_enum, default_value = "csv")]
+    format: ReportFormat,
+
+    /// Path of the change report, `-` for stdout (default: token_map_changes.<format>)
+    #[arg(long)]
+    output: Option<Destination>,
+
+    /// Overwrite an existing report
+    #[arg(long)]
+    force: bool,
+}
+
+/// File format of the change report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    Csv,
+    Json,
+}
+
+/// How a holder's balance changed between two snapshots
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    /// No balance in the earlier snapshot
+    New,
+    /// No balance in the later snapshot
+    Exited,
+    Changed,
+}
+
+/// A holder whose balance differs between the snapshots
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HolderChange {
+    pub account: Felt,
+    pub change: Change,
+    pub old: BigUint,
+    pub new: BigUint,
+}
+
+impl HolderChange {
+    pub fn delta(&self) -> BigInt {
+        BigInt::from(self.new.clone()) - BigInt::from(self.old.clone())
+    }
+}
+
+/// Changed holders and totals of a token
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenChanges {
+    pub old_total: BigUint,
+    pub new_total: BigUint,
+    /// Holders in account order, unchanged holders are left out
+    pub holders: Vec<HolderChange>,
+}
+
+impl TokenChanges {
+    pub fn count(&self, change: Change) -> usize {
+        self.holders.iter().filter(|h| h.change == change).count()
+    }
+
+    pub fn total_delta(&self) -> BigInt {
+        BigInt::from(self.new_total.clone()) - BigInt::from(self.old_total.clone())
+    }
+}
+
+/// Compare the balances of every token of either snapshot
+///
+/// A zero balance counts as no balance, so holders that emptied their account are exited.
+pub fn compare_snapshots(old: &Balances, new: &Balances) -> BTreeMap<Felt, TokenChanges> {
+    let empty = BTreeMap::new();
+    let zero = BigUint::default();
+    let mut changes = BTreeMap::new();
+
+    for token in old.keys().chain(new.keys()) {
+        if changes.contains_key(token) {
+            continue;
+        }
+        let old = old.get(token).unwrap_or(&empty);
+        let new = new.get(token).unwrap_or(&empty);
+
+        let mut accounts: Vec<&Felt> = old.keys().chain(new.keys()).collect();
+        accounts.sort();
+        accounts.dedup();
+
+        let mut token_changes = TokenChanges::default();
+        for account in accounts {
+            let before = old.get(account).unwrap_or(&zero);
+            let after = new.get(account).unwrap_or(&zero);
+            token_changes.old_total += before;
+            token_changes.new_total += after;
+
+            let change = match (before == &zero, after == &zero) {
+                _ if before == after => continue,
+                (true, _) => Change::New,
+                (_, true) => Change::Exited,
+                _ => Change::Changed,
+            };
+            token_changes.holders.push(HolderChange {
+                account: *account,
+                change,
+                old: before.clone(),
+                new: after.clone(),
+            });
+        }
+        changes.insert(*token, token_changes);
+    }
+
+    changes
+}
+
+/// Run the `compare` subcommand
+pub fn run_compare(args: &CompareArgs) -> Result<()> {
+    let (new_name, new, new_run) = load_snapshot(&args.new, args.new_run)?;
+    let old_run = match (args.old_run, new_run) {
+        (Some(run), _) => Some(run),
+        (None, Some(new_run)) if same_file(&args.old, &args.new) => {
+            Some(previous_run(&args.old, new_run)?)
+        }
+        _ => None,
+    };
+    let (old_name, old, _) = load_snapshot(&args.old, old_run)?;
+
+    let changes = compare_snapshots(&old, &new);
+    info!(old = %old_name, new = %new_name, "Compared snapshots");
+    for (token, token_changes) in &changes {
+        info!(
+            token = %format!("{token:#064x}"),
+            new_holders = token_changes.count(Change::New),
+            exited_holders = token_changes.count(Change::Exited),
+            changed_holders = token_changes.count(Change::Changed),
+            old_total = %token_changes.old_total,
+            new_total = %token_changes.new_total,
+            total_delta = %token_changes.total_delta(),
+            "Token changes"
+        );
+    }
+
+    let destination = args.output.clone().unwrap_or_else(|| {
+        Destination::File(PathBuf::from(match args.format {
+            ReportFormat::Csv => "token_map_changes.csv",
+            ReportFormat::Json => "token_map_changes.json",
+        }))
+    });
+    destination
+        .write_with(args.force, |w| match args.format {
+            ReportFormat::Csv => write_changes_as_csv(&changes, w),
+            ReportFormat::Json => write_changes_as_json(&old_name, &new_name, &changes, w),
+        })
+        .map_err(|e| eyre::eyre!("Failed to store change report: {}", e))?;
+    info!(path = %destination, "Change report written");
+    Ok(())
+}
+
+/// One row per changed holder
+fn write_changes_as_csv(
+    changes: &BTreeMap<Felt, TokenChanges>,
+    writer: &mut (dyn Write + Send),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record([
+        "Token",
+        "Account",
+        "Change",
+        "Old Balance",
+        "New Balance",
+        "Delta",
+    ])?;
+    for (token, token_changes) in changes {
+        for holder in &token_changes.holders {
+            wtr.write_record([
+                format!("{token:#064x}"),
+                format!("{:#064x}", holder.account),
+                change_name(holder.change).to_string(),
+                holder.old.to_string(),
+                holder.new.to_string(),
+                holder.delta().to_string(),
+            ])?;
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Totals and changed holders per token
+fn write_changes_as_json(
+    old_name: &str,
+    new_name: &str,
+    changes: &BTreeMap<Felt, TokenChanges>,
+    writer: &mut (dyn Write + Send),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tokens: serde_json::Map<String, serde_json::Value> = changes
+        .iter()
+        .map(|(token, token_changes)| {
+            let holders: Vec<serde_json::Value> = token_changes
+                .holders
+                .iter()
+                .map(|holder| {
+                    serde_json::json!({
+                        "account": format!("{:#064x}", holder.account),
+                        "change": holder.change,
+                        "old": holder.old.to_string(),
+                        "new": holder.new.to_string(),
+                        "delta": holder.delta().to_string(),
+                    })
+                })
+                .collect();
+            let summary = serde_json::json!({
+                "old_total": token_changes.old_total.to_string(),
+                "new_total": token_changes.new_total.to_string(),
+                "total_delta": token_changes.total_delta().to_string(),
+                "new_holders": token_changes.count(Change::New),
+                "exited_holders": token_changes.count(Change::Exited),
+                "changed_holders": token_changes.count(Change::Changed),
+                "holders": holders,
+            });
+            (format!("{token:#064x}"), summary)
+        })
+        .collect();
+
+    serde_json::to_writer_pretty(
+        writer,
+        &serde_json::json!({ "old": old_name, "new": new_name, "tokens": tokens }),
+    )?;
+    Ok(())
+}
+
+fn change_name(change: Change) -> &'static str {
+    match change {
+        Change::New => "new",
+        Change::Exited => "exited",
+        Change::Changed => "changed",
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Load a snapshot, returning a description, its balances and the SQLite run it was read from
+fn load_snapshot(path: &Path, run: Option<i64>) -> Result<(String, Balances, Option<i64>)> {
+    let name = path.display().to_string();
+    let mut extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let compression = match extension {
+        "gz" | "zst" => Some(extension),
+        _ => None,
+    };
+    if compression.is_some() {
+        extension = Path::new(path.file_stem().unwrap_or_default())
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("");
+    }
+
+    if matches!(extension, "db" | "sqlite" | "sqlite3") && compression.is_none() {
+        let (run, balances) = load_sqlite_run(path, run)?;
+        return Ok((format!("{name} (run {run})"), balances, Some(run)));
+    }
+    if run.is_some() {
+        return Err(eyre::eyre!("'{}' is not a SQLite archive with runs", name));
+    }
+
+    let file =
+        File::open(path).map_err(|e| eyre::eyre!("Failed to open snapshot '{}': {}", name, e))?;
+    let reader: Box<dyn Read> = match compression {
+        Some("gz") => Box::new(flate2::read::GzDecoder::new(file)),
+        Some(_) => Box::new(zstd::Decoder::new(file)?),
+        None => Box::new(file),
+    };
+    let reader = BufReader::new(reader);
+    let balances = match extension {
+        "csv" => load_csv(reader),
+        "json" => load_json(reader),
+        "ndjson" => load_ndjson(reader),
+        _ => Err(eyre::eyre!(
+            "unknown snapshot format, expected .db, .csv, .json or .ndjson"
+        )),
+    }
+    .map_err(|e| eyre::eyre!("Failed to load snapshot '{}': {}", name, e))?;
+    Ok((name, balances, None))
+}
+
+/// Balances of a run of the SQLite archive, the latest run by default
+fn load_sqlite_run(path: &Path, run: Option<i64>) -> Result<(i64, Balances)> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| eyre::eyre!("Failed to open archive '{}': {}", path.display(), e))?;
+    let run = match run {
+        Some(run) => run,
+        None => conn
+            .query_row("SELECT MAX(run_id) FROM runs", [], |row| {
+                row.get::<_, Option<i64>>(0)
+            })
+            .map_err(|e| eyre::eyre!("Failed to read runs of '{}': {}", path.display(), e))?
+            .ok_or_else(|| eyre::eyre!("Archive '{}' has no runs", path.display()))?,
+    };
+
+    let mut stmt = conn
+        .prepare("SELECT token, account, balance FROM token_map WHERE run_id = ?1")
+        .map_err(|e| eyre::eyre!("Failed to read '{}': {}", path.display(), e))?;
+    let rows = stmt.query_map([run], |row| {
+        Ok((
+            row.get::<_, Vec<u8>>(0)?,
+            row.get::<_, Vec<u8>>(1)?,
+            row.get::<_, Vec<u8>>(2)?,
+        ))
+    })?;
+
+    let mut balances = Balances::new();
+    for row in rows {
+        let (token, account, balance) = row?;
+        balances
+            .entry(Felt::from_bytes_be_slice(&token))
+            .or_default()
+            .insert(
+                Felt::from_bytes_be_slice(&account),
+                BigUint::from_bytes_be(&balance),
+            );
+    }
+    if balances.is_empty() {
+        let exists: Option<i64> = conn
+            .query_row("SELECT run_id FROM runs WHERE run_id = ?1", [run], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        if exists.is_none() {
+            return Err(eyre::eyre!(
+                "Archive '{}' has no run {}",
+                path.display(),
+                run
+            ));
+        }
+    }
+    Ok((run, balances))
+}
+
+/// The run archived before `run`
+fn previous_run(path: &Path, run: i64) -> Result<i64> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| eyre::eyre!("Failed to open archive '{}': {}", path.display(), e))?;
+    conn.query_row(
+        "SELECT MAX(run_id) FROM runs WHERE run_id < ?1",
+        [run],
+        |row| row.get::<_, Option<i64>>(0),
+    )?
+    .ok_or_else(|| eyre::eyre!("Archive '{}' has no run before {}", path.display(), run))
+}
+
+/// Long-layout CSV with `Token`, `Account` and `Balance` columns
+fn load_csv(reader: impl Read) -> Result<Balances> {
+    let mut rdr = csv::Reader::from_reader(reader);
+    let headers = rdr.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header == name)
+            .ok_or_else(|| {
+                eyre::eyre!(
+                    "missing '{}' column, only the long layout is supported",
+                    name
+                )
+            })
+    };
+    let (token, account, balance) = (column("Token")?, column("Account")?, column("Balance")?);
+
+    let mut balances = Balances::new();
+    for record in rdr.records() {
+        let record = record?;
+        insert_balance(
+            &mut balances,
+            &record[token],
+            &record[account],
+            &record[balance],
+        )?;
+    }
+    Ok(balances)
+}
+
+/// JSON in the default `token -> account -> balance` layout
+fn load_json(reader: impl Read) -> Result<Balances> {
+    let raw: HashMap<String, HashMap<String, String>> = serde_json::from_reader(reader)?;
+    let mut balances = Balances::new();
+    for (token, accounts) in &raw {
+        for (account, balance) in accounts {
+            insert_balance(&mut balances, token, account, balance)?;
+        }
+    }
+    Ok(balances)
+}
+
+#[derive(Deserialize)]
+struct NdjsonLine {
+    token: String,
+    account: String,
+    balance: String,
+}
+
+fn load_ndjson(reader: impl BufRead) -> Result<Balances> {
+    let mut balances = Balances::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line: NdjsonLine = serde_json::from_str(&line)?;
+        insert_balance(&mut balances, &line.token, &line.account, &line.balance)?;
+    }
+    Ok(balances)
+}
+
+fn insert_balance(
+    balances: &mut Balances,
+    token: &str,
+    account: &str,
+    balance: &str,
+) -> Result<()> {
+    let address = |text: &str| {
+        Felt::from_hex(&text.to_ascii_lowercase())
+            .map_err(|e| eyre::eyre!("invalid address '{}': {}", text, e))
+    };
+    balances
+        .entry(address(token)?)
+        .or_default()
+        .insert(address(account)?, parse_amount(balance)?);
+    Ok(())
+}
+
+/// Parse a raw decimal or `0x`-prefixed hex amount
+///
+/// Amounts in token units can't be compared exactly without the token decimals.
+fn parse_amount(text: &str) -> Result<BigUint> {
+    let amount = match text.strip_prefix("0x") {
+        Some(hex) => BigUint::parse_bytes(hex.as_bytes(), 16),
+        None => BigUint::parse_bytes(text.as_bytes(), 10),
+    };
+    amount.ok_or_else(|| {
+        eyre::eyre!(
+            "balance '{}' is not a raw or hex amount, compare snapshots written with --amount-format raw or hex",
+            text
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::BalanceSnapshot;
+    use crate::output::{write_results, OutputConfig, SinkRegistry};
+
+    fn balances(entries: &[(u64, u64, u64)]) -> Balances {
+        let mut balances = Balances::new();
+        for (token, account, balance) in entries {
+            balances
+                .entry(Felt::from(*token))
+                .or_default()
+                .insert(Felt::from(*account), BigUint::from(*balance));
+        }
+        balances
+    }
+
+    #[test]
+    fn test_compare_snapshots() {
+        let old = balances(&[
+            (1, 10, 100),
+            (1, 11, 50),
+            (1, 12, 7),
+            (1, 13, 5),
+            (2, 10, 1),
+        ]);
+        let new = balances(&[(1, 10, 120), (1, 12, 7), (1, 13, 0), (1, 14, 30)]);
+
+        let changes = compare_snapshots(&old, &new);
+        let token = &changes[&Felt::from(1u64)];
+        let summary: Vec<(Felt, Change)> = token
+            .holders
+            .iter()
+            .map(|h| (h.account, h.change))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Felt::from(10u64), Change::Changed),
+                (Felt::from(11u64), Change::Exited),
+                (Felt::from(13u64), Change::Exited),
+                (Felt::from(14u64), Change::New),
+            ]
+        );
+        assert_eq!(token.holders[0].delta(), BigInt::from(20));
+        assert_eq!(token.total_delta(), BigInt::from(-5));
+
+        // Tokens missing from one snapshot are compared against no balances
+        let gone = &changes[&Felt::from(2u64)];
+        assert_eq!(gone.count(Change::Exited), 1);
+        assert_eq!(gone.new_total, BigUint::default());
+    }
+
+    #[test]
+    fn test_load_written_snapshots() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let write = |entries: &[(u64, u64, u64)]| -> Result<()> {
+            let mut snapshot = BalanceSnapshot::default();
+            for (token, account, balance) in entries {
+                snapshot
+                    .balances
+                    .entry(Felt::from(*token))
+                    .or_default()
+                    .insert(Felt::from(*account), Felt::from(*balance));
+            }
+            let config = OutputConfig {
+                output_dir: dir.path().to_path_buf(),
+                force: true,
+                ..OutputConfig::new()
+            };
+            let specs = ["csv", "json", "ndjson", "sqlite"].map(|name| name.parse().unwrap());
+            let mut sinks = SinkRegistry::new().create(&specs)?;
+            write_results(&snapshot, &config, &mut sinks).map(|_| ())
+        };
+        write(&[(1, 10, 100)])?;
+        write(&[(1, 10, 150), (1, 11, 0x20)])?;
+
+        let expected = balances(&[(1, 10, 150), (1, 11, 0x20)]);
+        for name in ["token_map.csv", "token_map.json", "token_map.ndjson"] {
+            let (_, loaded, run) = load_snapshot(&dir.path().join(name), None)?;
+            assert_eq!(loaded, expected, "{name}");
+            assert_eq!(run, None);
+        }
+
+        let archive = dir.path().join("token_map.db");
+        let (_, latest, run) = load_snapshot(&archive, None)?;
+        assert_eq!((latest, run), (expected, Some(2)));
+        assert_eq!(previous_run(&archive, 2)?, 1);
+        let (_, first, _) = load_snapshot(&archive, Some(1))?;
+        assert_eq!(first, balances(&[(1, 10, 100)]));
+        assert!(load_snapshot(&archive, Some(3)).is_err());
+
+        assert_eq!(parse_amount("0x20")?, BigUint::from(32u64));
+        assert!(parse_amount("1.5").is_err());
+        Ok(())
+    }
+}
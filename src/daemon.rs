@@ -0,0 +1,212 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use eyre::Result;
+use rusqlite::{Connection, OpenFlags};
+use tracing::{error, info};
+
+use crate::balance::head_block_number;
+use crate::output::{archived_runs, delete_runs};
+
+const HOUR: i64 = 60 * 60;
+const DAY: i64 = 24 * HOUR;
+const WEEK: i64 = 7 * DAY;
+
+/// When the daemon takes a snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// Whenever the head block advanced by `every` blocks, checked every `poll`
+    Blocks { every: u64, poll: Duration },
+    /// At a fixed wall-clock interval
+    Interval(Duration),
+}
+
+/// Runs of the snapshot archive to keep, e.g. `hourly=24,daily=365`
+///
+/// `hourly=24` keeps the latest run of every hour of the last 24 hours, `daily` and `weekly`
+/// do the same per day and week, and `last=N` keeps the N latest runs. The latest run is
+/// always kept, every other run is pruned unless a rule keeps it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub last: usize,
+    pub hourly: i64,
+    pub daily: i64,
+    pub weekly: i64,
+}
+
+impl FromStr for Retention {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut retention = Retention::default();
+        for rule in value.split(',') {
+            let (key, count) = rule
+                .split_once('=')
+                .ok_or_else(|| format!("expected <period>=<count>, got '{rule}'"))?;
+            // Bounded, so that a count of weeks in seconds fits an i64
+            let count: u32 = count
+                .parse()
+                .map_err(|e| format!("invalid count in '{rule}': {e}"))?;
+            match key {
+                "last" => retention.last = count as usize,
+                "hourly" => retention.hourly = i64::from(count),
+                "daily" => retention.daily = i64::from(count),
+                "weekly" => retention.weekly = i64::from(count),
+                _ => {
+                    return Err(format!(
+                        "unknown retention period '{key}', expected last, hourly, daily or weekly"
+                    ))
+                }
+            }
+        }
+        Ok(retention)
+    }
+}
+
+impl Retention {
+    /// Ids of the runs to prune out of `(run_id, created_at)` runs at unix time `now`
+    pub fn runs_to_prune(&self, runs: &[(i64, i64)], now: i64) -> Vec<i64> {
+        let mut newest_first = runs.to_vec();
+        newest_first.sort_by(|a, b| b.1.cmp(&a.1).then(b.0.cmp(&a.0)));
+
+        let mut keep: HashSet<i64> = newest_first
+            .iter()
+            .take(self.last.max(1))
+            .map(|(id, _)| *id)
+            .collect();
+        for (period, count) in [(HOUR, self.hourly), (DAY, self.daily), (WEEK, self.weekly)] {
+            let mut buckets = HashSet::new();
+            for (id, created_at) in &newest_first {
+                if now - created_at < period * count && buckets.insert(created_at / period) {
+                    keep.insert(*id);
+                }
+            }
+        }
+
+        let mut prune: Vec<i64> = runs
+            .iter()
+            .map(|(id, _)| *id)
+            .filter(|id| !keep.contains(id))
+            .collect();
+        prune.sort();
+        prune
+    }
+}
+
+/// Delete the runs of the archive that the retention rules don't keep
+pub fn prune_archive(path: &Path, retention: &Retention) -> Result<usize> {
+    let runs = archived_runs(path)?;
+    let prune = retention.runs_to_prune(&runs, unix_time());
+    if !prune.is_empty() {
+        delete_runs(path, &prune)?;
+    }
+    info!(
+        path = %path.display(),
+        kept = runs.len() - prune.len(),
+        pruned = prune.len(),
+        "Pruned snapshot archive"
+    );
+    Ok(prune.len())
+}
+
+/// Take a snapshot on every tick of the schedule until the process is stopped
+///
+/// A failed snapshot is logged and retried at the next tick.
+pub fn run_daemon(
+    schedule: Schedule,
+    db_path: &str,
+    mut snapshot: impl FnMut() -> Result<()>,
+) -> Result<()> {
+    let mut take = |reason: &str| {
+        info!(reason, "Taking snapshot");
+        snapshot()
+            .map_err(|e| error!("Snapshot failed: {:#}", e))
+            .is_ok()
+    };
+
+    match schedule {
+        Schedule::Interval(interval) => {
+            info!(interval_s = interval.as_secs(), "Taking snapshots");
+            loop {
+                let start = Instant::now();
+                take("interval");
+                std::thread::sleep(interval.saturating_sub(start.elapsed()));
+            }
+        }
+        Schedule::Blocks { every, poll } => {
+            let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .map_err(|e| eyre::eyre!("Failed to open database '{}': {}", db_path, e))?;
+            info!(every_blocks = every, "Taking snapshots");
+            let mut last: Option<u64> = None;
+            loop {
+                let head = head_block_number(&conn).ok_or_else(|| {
+                    eyre::eyre!("--every-blocks needs a database with block headers")
+                })?;
+                if last.is_none_or(|last| head >= last + every) && take(&format!("block {head}")) {
+                    last = Some(head);
+                }
+                std::thread::sleep(poll);
+            }
+        }
+    }
+}
+
+fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retention() {
+        let retention: Retention = "hourly=24,daily=365".parse().unwrap();
+        assert_eq!(
+            retention,
+            Retention {
+                last: 0,
+                hourly: 24,
+                daily: 365,
+                weekly: 0,
+            }
+        );
+        assert!("monthly=12".parse::<Retention>().is_err());
+        assert!("hourly".parse::<Retention>().is_err());
+        assert!("hourly=3000000000000000".parse::<Retention>().is_err());
+        let longest: Retention = "weekly=4294967295".parse().unwrap();
+        assert!(longest
+            .runs_to_prune(&[(1, 0), (2, WEEK)], 2 * WEEK)
+            .is_empty());
+
+        // A run every 15 minutes for 3 days, ids in creation order
+        let now = 1_000 * DAY;
+        let runs: Vec<(i64, i64)> = (0..3 * 24 * 4)
+            .map(|i| (i + 1, now - 3 * DAY + i * 15 * 60 + 60))
+            .collect();
+        let prune = retention.runs_to_prune(&runs, now);
+        let kept: Vec<i64> = runs
+            .iter()
+            .filter(|(id, _)| !prune.contains(id))
+            .map(|(_, created_at)| *created_at)
+            .collect();
+
+        // The latest run of each of the last 24 hours, and of each of the 2 earlier days
+        let recent = kept.iter().filter(|t| now - **t < DAY).count();
+        assert_eq!(recent, 24);
+        assert_eq!(kept.len(), 24 + 2);
+        assert!(kept.contains(&runs.last().unwrap().1));
+
+        // Runs past every window are pruned, except the latest
+        let old = [(1, 0), (2, HOUR)];
+        assert_eq!(retention.runs_to_prune(&old, now), [1]);
+        assert!(Retention::default()
+            .runs_to_prune(&[(1, 0)], now)
+            .is_empty());
+    }
+}
@@ -0,0 +1,206 @@
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use eyre::Result;
+use tempfile::NamedTempFile;
+
+/// Where a single output is written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Stdout,
+    File(PathBuf),
+}
+
+impl FromStr for Destination {
+    type Err = Infallible;
+
+    /// Parse a path option, where `-` stands for stdout
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "-" {
+            Ok(Destination::Stdout)
+        } else {
+            Ok(Destination::File(PathBuf::from(value)))
+        }
+    }
+}
+
+impl Destination {
+    /// Destination of a companion output, e.g. `report_groups.csv` next to `report.csv`
+    ///
+    /// Companions of stdout outputs go to `fallback_dir` under the default file name,
+    /// since stdout can only carry one document.
+    pub fn companion(&self, suffix: &str, fallback_dir: &Path, default_name: &str) -> Destination {
+        match self {
+            Destination::File(path) => {
+                let stem = path
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let name = match path.extension() {
+                    Some(ext) => format!("{stem}_{suffix}.{}", ext.to_string_lossy()),
+                    None => format!("{stem}_{suffix}"),
+                };
+                Destination::File(path.with_file_name(name))
+            }
+            Destination::Stdout => Destination::File(fallback_dir.join(default_name)),
+        }
+    }
+
+    /// This destination with `.<extension>` appended to the file name, e.g. `token_map.csv.gz`
+    pub fn appending_extension(&self, extension: &str) -> Destination {
+        match self {
+            Destination::File(path) => {
+                let mut name = path.clone().into_os_string();
+                name.push(format!(".{extension}"));
+                Destination::File(PathBuf::from(name))
+            }
+            Destination::Stdout => Destination::Stdout,
+        }
+    }
+
+    /// Run `write` against this destination
+    ///
+    /// Files are written to a temporary file in the same directory which is renamed
+    /// over the target only once `write` succeeded, so a crash never leaves a
+    /// half-written file behind. Existing files are only replaced when `force` is set,
+    /// including files created by someone else while `write` ran.
+    pub fn write_with<F>(&self, force: bool, write: F) -> Result<()>
+    where
+        F: FnOnce(&mut (dyn Write + Send)) -> Result<(), Box<dyn Error>>,
+    {
+        match self {
+            Destination::Stdout => {
+                let mut writer = BufWriter::new(std::io::stdout());
+                write(&mut writer).map_err(|e| eyre::eyre!("Failed to write to stdout: {}", e))?;
+                writer
+                    .flush()
+                    .map_err(|e| eyre::eyre!("Failed to write to stdout: {}", e))?;
+                Ok(())
+            }
+            Destination::File(path) => {
+                if path.exists() && !force {
+                    return Err(eyre::eyre!(
+                        "'{}' already exists, use --force to overwrite it",
+                        path.display()
+                    ));
+                }
+
+                let dir = match path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => Path::new("."),
+                };
+                std::fs::create_dir_all(dir).map_err(|e| {
+                    eyre::eyre!("Failed to create directory '{}': {}", dir.display(), e)
+                })?;
+
+                let temp = NamedTempFile::new_in(dir).map_err(|e| {
+                    eyre::eyre!(
+                        "Failed to create temporary file in '{}': {}",
+                        dir.display(),
+                        e
+                    )
+                })?;
+                let mut writer = BufWriter::new(temp);
+                write(&mut writer)
+                    .map_err(|e| eyre::eyre!("Failed to write '{}': {}", path.display(), e))?;
+                let temp = writer
+                    .into_inner()
+                    .map_err(|e| eyre::eyre!("Failed to write '{}': {}", path.display(), e))?;
+                let persisted = if force {
+                    temp.persist(path)
+                } else {
+                    temp.persist_noclobber(path)
+                };
+                persisted.map_err(|e| match e.error.kind() {
+                    std::io::ErrorKind::AlreadyExists => eyre::eyre!(
+                        "'{}' already exists, use --force to overwrite it",
+                        path.display()
+                    ),
+                    _ => eyre::eyre!("Failed to move '{}' in place: {}", path.display(), e),
+                })?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Stdout => write!(f, "stdout"),
+            Destination::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_companion() -> Result<()> {
+        let dir = Path::new("out");
+        let file: Destination = "reports/week.csv".parse()?;
+        let stdout: Destination = "-".parse()?;
+        assert_eq!(
+            file.companion("groups", dir, "token_map_groups.csv"),
+            Destination::File(PathBuf::from("reports/week_groups.csv"))
+        );
+        assert_eq!(
+            stdout.companion("groups", dir, "token_map_groups.csv"),
+            Destination::File(PathBuf::from("out/token_map_groups.csv"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_refuses_to_overwrite_without_force() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("token_map.csv");
+        let destination = Destination::File(path.clone());
+
+        destination.write_with(false, |w| Ok(w.write_all(b"first")?))?;
+        assert!(destination
+            .write_with(false, |w| Ok(w.write_all(b"second")?))
+            .is_err());
+        assert_eq!(std::fs::read_to_string(&path)?, "first");
+
+        destination.write_with(true, |w| Ok(w.write_all(b"second")?))?;
+        assert_eq!(std::fs::read_to_string(&path)?, "second");
+
+        // A file appearing while the output is written is not replaced either
+        let late = Destination::File(dir.path().join("late.csv"));
+        let result = late.write_with(false, |w| {
+            std::fs::write(dir.path().join("late.csv"), "other")?;
+            Ok(w.write_all(b"ours")?)
+        });
+        assert!(result.is_err());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("late.csv"))?,
+            "other"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_write_keeps_previous_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("token_map.json");
+        std::fs::write(&path, "previous")?;
+
+        let result = Destination::File(path.clone()).write_with(true, |w| {
+            w.write_all(b"half")?;
+            Err("interrupted".into())
+        });
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&path)?, "previous");
+        // The temporary file is cleaned up
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+}
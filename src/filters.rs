@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet};
+
+use bigdecimal::BigDecimal;
+use eyre::Result;
+use starknet::core::types::Felt;
+
+use crate::balance::BalanceSnapshot;
+use crate::valuation::to_units;
+
+/// Filters applied to the resolved balances before valuation, statistics and output
+#[derive(Debug, Clone, Default)]
+pub struct HolderFilters {
+    /// Drop holders whose balance is below this amount, in whole token units
+    pub min_balance: Option<BigDecimal>,
+    /// Keep only the N largest holders of each token
+    pub top_n: Option<usize>,
+    /// Accounts that are always dropped (bridges, DEX pools, burn addresses, ...)
+    pub exclude: HashSet<Felt>,
+}
+
+impl HolderFilters {
+    /// Returns true if no filter is configured
+    pub fn is_empty(&self) -> bool {
+        self.min_balance.is_none() && self.top_n.is_none() && self.exclude.is_empty()
+    }
+}
+
+/// Load an exclusion list with one address per line
+///
+/// Empty lines and everything after a `#` are ignored, so lists can be annotated.
+pub fn load_exclusion_list(path: &str) -> Result<HashSet<Felt>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| eyre::eyre!("Failed to read exclusion list '{}': {}", path, e))?;
+
+    let mut exclude = HashSet::new();
+    for (line_number, line) in content.lines().enumerate() {
+        let address = line.split('#').next().unwrap_or_default().trim();
+        if address.is_empty() {
+            continue;
+        }
+        let address = Felt::from_hex(address).map_err(|e| {
+            eyre::eyre!(
+                "Invalid address '{}' in exclusion list '{}' line {}: {}",
+                address,
+                path,
+                line_number + 1,
+                e
+            )
+        })?;
+        exclude.insert(address);
+    }
+    Ok(exclude)
+}
+
+/// Apply the holder filters to every token of the snapshot
+///
+/// Exclusions are applied first, then the minimum balance, then the top-N cut, so
+/// excluded accounts never take a top-N slot.
+pub fn apply_filters(snapshot: &mut BalanceSnapshot, filters: &HolderFilters) {
+    if filters.is_empty() {
+        return;
+    }
+
+    let decimals: HashMap<Felt, u8> = snapshot
+        .balances
+        .keys()
+        .map(|token| (*token, snapshot.decimals_of(token)))
+        .collect();
+
+    for (token, balances) in snapshot.balances.iter_mut() {
+        balances.retain(|account, _| !filters.exclude.contains(account));
+
+        if let Some(min_balance) = &filters.min_balance {
+            let decimals = decimals[token];
+            balances.retain(|_, balance| &to_units(balance, decimals) >= min_balance);
+        }
+
+        if let Some(top_n) = filters.top_n {
+            if balances.len() > top_n {
+                let mut holders: Vec<(Felt, Felt)> = balances.drain().collect();
+                // Largest balance first, ties broken by address so the cut is deterministic
+                holders.sort_by(|(a_account, a_balance), (b_account, b_balance)| {
+                    b_balance
+                        .cmp(a_balance)
+                        .then_with(|| a_account.cmp(b_account))
+                });
+                holders.truncate(top_n);
+                balances.extend(holders);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::TokenInfo;
+    use std::str::FromStr;
+
+    fn snapshot() -> BalanceSnapshot {
+        let token = Felt::from(1u64);
+        BalanceSnapshot {
+            balances: HashMap::from([(
+                token,
+                HashMap::from([
+                    (Felt::from(10u64), Felt::from(500u64)),
+                    (Felt::from(11u64), Felt::from(300u64)),
+                    (Felt::from(12u64), Felt::from(300u64)),
+                    (Felt::from(13u64), Felt::from(50u64)),
+                    (Felt::from(14u64), Felt::from(9_000u64)),
+                ]),
+            )]),
+            token_info: HashMap::from([(
+                token,
+                TokenInfo {
+                    symbol: None,
+                    decimals: Some(2),
+                },
+            )]),
+            ..Default::default()
+        }
+    }
+
+    fn holders(snapshot: &BalanceSnapshot) -> Vec<u64> {
+        let mut holders: Vec<u64> = snapshot.balances[&Felt::from(1u64)]
+            .keys()
+            .map(|account| account.to_biguint().try_into().unwrap())
+            .collect();
+        holders.sort();
+        holders
+    }
+
+    #[test]
+    fn test_apply_filters() -> Result<()> {
+        let mut snapshot = snapshot();
+        let filters = HolderFilters {
+            // 1.00 token units = 100 raw with 2 decimals
+            min_balance: Some(BigDecimal::from_str("1")?),
+            top_n: Some(2),
+            exclude: HashSet::from([Felt::from(14u64)]),
+        };
+
+        apply_filters(&mut snapshot, &filters);
+
+        // 14 is excluded, 13 is below the minimum, and 11 wins the tie with 12 on address
+        assert_eq!(holders(&snapshot), vec![10, 11]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_filters_keep_everything() {
+        let mut snapshot = snapshot();
+        apply_filters(&mut snapshot, &HolderFilters::default());
+        assert_eq!(holders(&snapshot), vec![10, 11, 12, 13, 14]);
+    }
+}
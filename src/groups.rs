@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use num_bigint::BigUint;
+use serde::Serialize;
+use starknet::core::types::Felt;
+
+use crate::balance::BalanceSnapshot;
+
+/// Group name used for accounts that were not assigned a group in the input
+pub const UNGROUPED: &str = "ungrouped";
+
+/// Summed balances of all accounts belonging to a single group
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct GroupSummary {
+    /// Accounts in this group that hold a balance of at least one token
+    pub accounts: BTreeSet<Felt>,
+    /// token -> accounts in this group with a non-zero balance of the token
+    #[serde(serialize_with = "serialize_holders")]
+    pub holders: BTreeMap<Felt, usize>,
+    /// token -> sum of balances, as a decimal string
+    #[serde(serialize_with = "serialize_totals")]
+    pub totals: BTreeMap<Felt, BigUint>,
+}
+
+/// Sum balances per group per token
+///
+/// Sums are kept as `BigUint` since adding many felts can exceed the field size.
+pub fn aggregate_by_group(snapshot: &BalanceSnapshot) -> BTreeMap<String, GroupSummary> {
+    let mut groups: BTreeMap<String, GroupSummary> = BTreeMap::new();
+
+    for (token, balances) in &snapshot.balances {
+        for (account, balance) in balances {
+            let group = snapshot
+                .label_of(account)
+                .and_then(|l| l.group.clone())
+                .unwrap_or_else(|| UNGROUPED.to_string());
+
+            let summary = groups.entry(group).or_default();
+            let holders = summary.holders.entry(*token).or_default();
+            if *balance != Felt::ZERO {
+                summary.accounts.insert(*account);
+                *holders += 1;
+            }
+            *summary.totals.entry(*token).or_default() += balance.to_biguint();
+        }
+    }
+
+    groups
+}
+
+fn serialize_totals<S>(totals: &BTreeMap<Felt, BigUint>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_map(
+        totals
+            .iter()
+            .map(|(token, total)| (format!("{token:#064x}"), total.to_string())),
+    )
+}
+
+fn serialize_holders<S>(holders: &BTreeMap<Felt, usize>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_map(
+        holders
+            .iter()
+            .map(|(token, count)| (format!("{token:#064x}"), count)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::AccountLabel;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_aggregate_by_group() {
+        let token = Felt::from(1u64);
+        let other_token = Felt::from(2u64);
+        let treasury_a = Felt::from(10u64);
+        let treasury_b = Felt::from(11u64);
+        let other = Felt::from(12u64);
+        let dormant = Felt::from(13u64);
+
+        let mut balances = HashMap::new();
+        balances.insert(
+            token,
+            HashMap::from([
+                (treasury_a, Felt::from(100u64)),
+                (treasury_b, Felt::from(250u64)),
+                (other, Felt::from(7u64)),
+                (dormant, Felt::ZERO),
+            ]),
+        );
+        balances.insert(
+            other_token,
+            HashMap::from([(treasury_a, Felt::from(5u64)), (treasury_b, Felt::ZERO)]),
+        );
+
+        let treasury = AccountLabel {
+            label: None,
+            group: Some("treasury".to_string()),
+        };
+        let snapshot = BalanceSnapshot {
+            balances,
+            labels: HashMap::from([
+                (treasury_a, treasury.clone()),
+                (treasury_b, treasury.clone()),
+                (dormant, treasury),
+            ]),
+            ..Default::default()
+        };
+
+        let groups = aggregate_by_group(&snapshot);
+        assert_eq!(groups.len(), 2);
+
+        // Accounts without any balance are left out of the group's accounts
+        let treasury = &groups["treasury"];
+        assert_eq!(treasury.accounts, BTreeSet::from([treasury_a, treasury_b]));
+        assert_eq!(treasury.totals[&token], BigUint::from(350u64));
+        // Holders are counted per token, zero balances don't count
+        assert_eq!(treasury.holders[&token], 2);
+        assert_eq!(treasury.holders[&other_token], 1);
+        assert_eq!(treasury.totals[&other_token], BigUint::from(5u64));
+
+        let ungrouped = &groups[UNGROUPED];
+        assert_eq!(ungrouped.accounts, BTreeSet::from([other]));
+        assert_eq!(ungrouped.holders[&token], 1);
+        assert_eq!(ungrouped.totals[&token], BigUint::from(7u64));
+    }
+
+    #[test]
+    fn test_parse_labelled_accounts() -> eyre::Result<()> {
+        let input = r#"{
+            "accounts": [
+                "0x1",
+                { "address": "0x2", "label": "hot wallet", "group": "treasury" },
+                { "address": "0x3", "group": "market-maker" }
+            ],
+            "tokens": ["0x10"]
+        }"#;
+        let addresses: crate::balance::Addresses = serde_json::from_str(input)?;
+
+        assert_eq!(
+            addresses.accounts,
+            vec![Felt::from(1u64), Felt::from(2u64), Felt::from(3u64)]
+        );
+        assert_eq!(addresses.labels.len(), 2);
+        assert_eq!(
+            addresses.labels[&Felt::from(2u64)],
+            AccountLabel {
+                label: Some("hot wallet".to_string()),
+                group: Some("treasury".to_string()),
+            }
+        );
+        assert!(!addresses.labels.contains_key(&Felt::from(1u64)));
+        Ok(())
+    }
+}
@@ -0,0 +1,23 @@
+//! Balances of Starknet ERC20 tokens read from a Pathfinder database
+//!
+//! The `balance_gettor` binary is built on this library. Further outputs implement
+//! [`output::OutputSink`] and are added to an [`output::SinkRegistry`].
+
+pub mod airdrop;
+pub mod alerts;
+pub mod balance;
+pub mod closes;
+pub mod compare;
+pub mod daemon;
+pub mod destination;
+pub mod filters;
+pub mod groups;
+pub mod logging;
+pub mod metrics;
+pub mod output;
+pub mod progress;
+pub mod proof;
+pub mod report;
+pub mod server;
+pub mod stats;
+pub mod valuation;
@@ -0,0 +1,31 @@
+use std::io::IsTerminal;
+
+use tracing::Level;
+
+/// Format of the log lines written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, with the event fields and enclosing spans
+    Json,
+}
+
+/// Install the global subscriber, logging to stderr so stdout only carries output data
+///
+/// `quiet` keeps warnings and errors only, every `verbose` step adds a level of detail
+/// over `info`.
+pub fn init_logging(quiet: bool, verbose: u8, format: LogFormat) {
+    let level = match (quiet, verbose) {
+        (true, _) => Level::WARN,
+        (false, 0) => Level::INFO,
+        (false, 1) => Level::DEBUG,
+        (false, _) => Level::TRACE,
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => subscriber.with_ansi(std::io::stderr().is_terminal()).init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
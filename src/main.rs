@@ -1,24 +1,46 @@
 use clap::Parser;
 
 use rusqlite::Connection;
+use starknet::core::types::Felt;
+use tracing::info;
 
-mod balance;
-use balance::{get_balance_map, Addresses};
-
-mod output;
-use output::{write_results, OutputConfig};
+use balance_gettor::{
+    airdrop::{build_airdrop, write_airdrop, AllocationRule, MerkleHash},
+    alerts::{run_alerts, AlertArgs},
+    balance::{get_balance_map, Addresses},
+    closes::{run_closes, ClosesArgs},
+    compare::{run_compare, CompareArgs},
+    daemon::{prune_archive, run_daemon, Retention, Schedule},
+    destination::Destination,
+    filters::{apply_filters, load_exclusion_list, HolderFilters},
+    logging::{init_logging, LogFormat},
+    metrics::{run_metrics, MetricsArgs},
+    output::{
+        archive_path, write_results, AddressFormat, AmountFormat, Compression, Formatting,
+        OutputConfig, Rounding, SinkRegistry, SinkSpec,
+    },
+    proof::{export_snapshot_proofs, verify_snapshot, write_proof_export, VerificationStatus},
+    report::{previous_rows_scanned, RunReport},
+    server::{run_serve, ServeArgs},
+    stats::{compute_stats, print_summary},
+    valuation::{load_prices, value_snapshot},
+};
 
 #[derive(Parser)]
 #[command(name = "balance_gettor")]
 #[command(about = "A CLI tool to get balance information from StarkNet")]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path to the addresses JSON file
-    #[arg(short, long, env = "INPUT_FILE")]
-    input_file: String,
+    #[arg(short, long, env = "INPUT_FILE", required = true)]
+    input_file: Option<String>,
 
     /// Path to the database
-    #[arg(short, long, env = "DB_PATH")]
-    db_path: String,
+    #[arg(short, long, env = "DB_PATH", required = true)]
+    db_path: Option<String>,
 
     /// Output results as CSV
     #[arg(long)]
@@ -28,9 +50,176 @@ struct Args {
     #[arg(long)]
     json: bool,
 
+    /// Output results as newline-delimited JSON, one balance per line
+    #[arg(long)]
+    ndjson: bool,
+
     /// Output results to SQLite database
     #[arg(long)]
     sqlite: bool,
+
+    /// Directory of the output files without an explicit path
+    #[arg(long, env = "OUTPUT_DIR", default_value = ".")]
+    output_dir: std::path::PathBuf,
+
+    /// Path of the CSV output, `-` for stdout (implies --csv)
+    #[arg(long)]
+    csv_path: Option<String>,
+
+    /// Path of the JSON output, `-` for stdout (implies --json)
+    #[arg(long)]
+    json_path: Option<String>,
+
+    /// Path of the NDJSON output, `-` for stdout (implies --ndjson)
+    #[arg(long)]
+    ndjson_path: Option<String>,
+
+    /// Path of the SQLite output (implies --sqlite)
+    #[arg(long)]
+    sqlite_path: Option<String>,
+
+    /// Output by name with options, e.g. `csv:path=week.csv,delimiter=;`, can be repeated
+    #[arg(long, value_name = "NAME[:KEY=VALUE,...]")]
+    output: Vec<SinkSpec>,
+
+    /// Split the CSV, JSON and NDJSON balances into one file per token
+    #[arg(long)]
+    partition_by_token: bool,
+
+    /// Compress the CSV, JSON and NDJSON outputs
+    #[arg(long, value_enum)]
+    compress: Option<Compression>,
+
+    /// Format of balances: raw integer, hex integer or decimal token units
+    #[arg(long, value_enum, default_value = "raw")]
+    amount_format: AmountFormat,
+
+    /// Decimal places of balances in token units, all token decimals by default
+    #[arg(long)]
+    precision: Option<u32>,
+
+    /// Rounding of balances in token units to --precision
+    #[arg(long, value_enum, default_value = "half-even", requires = "precision")]
+    rounding: Rounding,
+
+    /// Format of token and account addresses
+    #[arg(long, value_enum, default_value = "padded")]
+    address_format: AddressFormat,
+
+    /// Overwrite existing output files
+    #[arg(long)]
+    force: bool,
+
+    /// Also write balances summed per account group
+    #[arg(long)]
+    group_summary: bool,
+
+    /// Path to a JSON price file (token -> price) used to compute fiat values
+    #[arg(long, env = "PRICE_FILE")]
+    prices: Option<String>,
+
+    /// Block number or timestamp to select prices at, for price files keyed by block or time
+    #[arg(long, requires = "prices")]
+    price_at: Option<u64>,
+
+    /// Drop holders with a balance below this amount, in whole token units
+    #[arg(long)]
+    min_balance: Option<bigdecimal::BigDecimal>,
+
+    /// Keep only the N largest holders of each token
+    #[arg(long)]
+    top: Option<usize>,
+
+    /// Account to drop from the results (bridges, DEX pools, burn addresses), can be repeated
+    #[arg(long, value_parser = parse_felt)]
+    exclude: Vec<Felt>,
+
+    /// Path to a file with accounts to drop, one address per line
+    #[arg(long, env = "EXCLUDE_FILE")]
+    exclude_file: Option<String>,
+
+    /// Build an airdrop merkle tree with this allocation rule:
+    /// balance, fixed:<amount> or proportional:<total> (raw amounts)
+    #[arg(long)]
+    airdrop: Option<AllocationRule>,
+
+    /// Token whose holders receive the airdrop, defaults to the only token of the input
+    #[arg(long, value_parser = parse_felt, requires = "airdrop")]
+    airdrop_token: Option<Felt>,
+
+    /// Hash function of the airdrop merkle tree
+    #[arg(long, value_enum, default_value = "pedersen", requires = "airdrop")]
+    merkle_hash: MerkleHash,
+
+    /// Path of the airdrop file with the merkle root and per-account proofs
+    #[arg(long, default_value = "airdrop.json", requires = "airdrop")]
+    airdrop_file: std::path::PathBuf,
+
+    /// Verify every balance with a storage proof against the head block's state root
+    #[arg(long)]
+    verify: bool,
+
+    /// Write the storage proof of every balance to this file, in the shape of a
+    /// starknet_getStorageProof response
+    #[arg(long)]
+    export_proofs: Option<std::path::PathBuf>,
+
+    /// Write a JSON run report with phase timings, rows scanned per shard and output sizes
+    #[arg(long)]
+    report: Option<std::path::PathBuf>,
+
+    /// Keep running, adding a snapshot to the SQLite archive whenever the head block
+    /// advanced by this many blocks (implies --sqlite)
+    #[arg(long, conflicts_with = "interval")]
+    every_blocks: Option<u64>,
+
+    /// Keep running, adding a snapshot to the SQLite archive every this many seconds
+    /// (implies --sqlite)
+    #[arg(long)]
+    interval: Option<u64>,
+
+    /// Seconds between two checks of the head block with --every-blocks
+    #[arg(long, default_value_t = 30, requires = "every_blocks")]
+    poll_interval: u64,
+
+    /// Prune the SQLite archive after every run, e.g. `hourly=24,daily=365`:
+    /// last=N, hourly=N, daily=N and weekly=N
+    #[arg(long, value_name = "PERIOD=COUNT,...")]
+    retention: Option<Retention>,
+
+    /// Only log warnings and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log more detail, repeat for even more (`-vv`)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Format of the log lines written to stderr
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    log_format: LogFormat,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Compare two snapshots written by earlier runs: new and exited holders, balance
+    /// deltas and token totals
+    Compare(CompareArgs),
+    /// Serve balances, holders and token metadata from the database over a JSON HTTP API,
+    /// and storage and balanceOf calls over the Starknet JSON-RPC API
+    Serve(ServeArgs),
+    /// Export the balances of a watch list of (token, account) pairs as Prometheus gauges,
+    /// read again every interval, with the head block and query latency
+    Metrics(MetricsArgs),
+    /// Evaluate balance alert rules against the database and post the alerts to a webhook
+    Alerts(AlertArgs),
+    /// Write the balance of every account at the last block of every UTC day, or of
+    /// another period, of a date range
+    Closes(ClosesArgs),
+}
+
+fn parse_felt(value: &str) -> Result<Felt, String> {
+    Felt::from_hex(value).map_err(|e| format!("invalid address '{value}': {e}"))
 }
 
 fn main() -> eyre::Result<()> {
@@ -39,28 +228,221 @@ fn main() -> eyre::Result<()> {
 
     // Parse command line arguments
     let args = Args::parse();
+    init_logging(args.quiet, args.verbose, args.log_format);
+    match &args.command {
+        Some(Command::Compare(compare)) => return run_compare(compare),
+        Some(Command::Serve(serve)) => return run_serve(serve),
+        Some(Command::Metrics(metrics)) => return run_metrics(metrics),
+        Some(Command::Alerts(alerts)) => return run_alerts(alerts),
+        Some(Command::Closes(closes)) => return run_closes(closes),
+        None => {}
+    }
+
+    let schedule = match (args.every_blocks, args.interval) {
+        (Some(every), _) => Some(Schedule::Blocks {
+            every,
+            poll: std::time::Duration::from_secs(args.poll_interval),
+        }),
+        (None, Some(interval)) => {
+            Some(Schedule::Interval(std::time::Duration::from_secs(interval)))
+        }
+        (None, None) => None,
+    };
+    match (schedule, &args.db_path) {
+        (Some(schedule), Some(db_path)) => {
+            // Every snapshot writes the outputs again, only the archive adds to its file
+            let rewrites = sink_specs(&args).iter().any(|spec| spec.name != "sqlite")
+                || args.partition_by_token
+                || args.compress.is_some()
+                || args.airdrop.is_some()
+                || args.export_proofs.is_some();
+            if rewrites && !args.force {
+                eyre::bail!(
+                    "--every-blocks and --interval write every output except SQLite again on each snapshot, which needs --force"
+                );
+            }
+            run_daemon(schedule, db_path, || run_reported(&args))
+        }
+        _ => run_reported(&args),
+    }
+}
+
+/// Run once, writing the run report if one was requested
+fn run_reported(args: &Args) -> eyre::Result<()> {
+    let mut report = RunReport::new();
+    let result = run(args, &mut report);
+    if let Some(path) = &args.report {
+        report.finish(&result);
+        report.write(path)?;
+        info!(path = %path.display(), "Run report written");
+    }
+    result
+}
+
+/// Outputs selected by the format flags and `--output`, with the SQLite archive first in
+/// daemon mode so a failing output doesn't keep a snapshot out of it
+fn sink_specs(args: &Args) -> Vec<SinkSpec> {
+    // The format flags are shorthands for the built-in sinks
+    let mut sink_specs = Vec::new();
+    for (name, enabled, path) in [
+        ("csv", args.csv, &args.csv_path),
+        ("json", args.json, &args.json_path),
+        ("ndjson", args.ndjson, &args.ndjson_path),
+        ("sqlite", args.sqlite, &args.sqlite_path),
+    ] {
+        if enabled || path.is_some() {
+            sink_specs.push(SinkSpec::new(name).with_path(path.as_deref()));
+        }
+    }
+    sink_specs.extend(args.output.iter().cloned());
+
+    let daemon = args.every_blocks.is_some() || args.interval.is_some();
+    if daemon {
+        match sink_specs.iter().position(|spec| spec.name == "sqlite") {
+            Some(index) => {
+                let archive = sink_specs.remove(index);
+                sink_specs.insert(0, archive);
+            }
+            None => sink_specs.insert(0, SinkSpec::new("sqlite")),
+        }
+    }
+    sink_specs
+}
+
+/// Read the balances of the input, process them and write every output
+fn run(args: &Args, report: &mut RunReport) -> eyre::Result<()> {
+    let (Some(input_file), Some(db_path)) = (&args.input_file, &args.db_path) else {
+        eyre::bail!("--input-file and --db-path are required");
+    };
 
     // Create output configuration from CLI arguments
-    let output_config = OutputConfig {
-        csv: args.csv,
-        json: args.json,
-        sqlite: args.sqlite,
+    let mut output_config = OutputConfig {
+        group_summary: args.group_summary,
+        output_dir: args.output_dir.clone(),
+        force: args.force,
+        input_hash: None,
+        partition_by_token: args.partition_by_token,
+        compression: args.compress,
+        formatting: Formatting {
+            amounts: args.amount_format,
+            precision: args.precision,
+            rounding: args.rounding,
+            addresses: args.address_format,
+        },
     };
 
+    let sink_specs = sink_specs(args);
+    let mut sinks = SinkRegistry::new().create(&sink_specs)?;
+    let archive = archive_path(&sink_specs, &output_config.output_dir);
+    if args.retention.is_some() && archive.is_none() {
+        eyre::bail!("--retention needs the SQLite output");
+    }
+
     // Read and parse the JSON file
-    let file_content = std::fs::read_to_string(&args.input_file)
-        .map_err(|e| eyre::eyre!("Failed to read JSON file '{}': {}", args.input_file, e))?;
-    let addresses: Addresses = serde_json::from_str(&file_content)
-        .map_err(|e| eyre::eyre!("Failed to parse JSON file '{}': {}", args.input_file, e))?;
+    let (addresses, input_hash) = Addresses::load_with_hash(input_file)?;
+    output_config.input_hash = Some(input_hash);
+    report.input_hash = output_config.input_hash.clone();
 
     // Open a connection to the SQLite database
-    let conn = Connection::open(&args.db_path)
-        .map_err(|e| eyre::eyre!("Failed to open database '{}': {}", args.db_path, e))?;
+    let conn = Connection::open(db_path)
+        .map_err(|e| eyre::eyre!("Failed to open database '{}': {}", db_path, e))?;
+
+    // The previous report refines the progress estimates of the table statistics per token
+    let estimates = args
+        .report
+        .as_deref()
+        .map(previous_rows_scanned)
+        .unwrap_or_default();
+    let mut snapshot = report.phase("read_balances", || {
+        get_balance_map(&conn, &addresses, &estimates)
+    })?;
+    report.block_number = snapshot.block_number;
+    report.record_scan(&snapshot.scan);
+
+    // Drop filtered holders before anything is computed from the balances
+    let mut filters = HolderFilters {
+        min_balance: args.min_balance.clone(),
+        top_n: args.top,
+        exclude: args.exclude.iter().copied().collect(),
+    };
+    if let Some(exclude_file) = &args.exclude_file {
+        filters.exclude.extend(load_exclusion_list(exclude_file)?);
+    }
+    report.phase("filter", || apply_filters(&mut snapshot, &filters));
+
+    // Value the balances in fiat if a price file was given
+    if let Some(price_file) = &args.prices {
+        let prices = load_prices(price_file)?;
+        let valuation = report.phase("value", || {
+            value_snapshot(&snapshot, &prices, args.price_at)
+        });
+        snapshot.valuation = Some(valuation);
+    }
 
-    let token_map = get_balance_map(&conn, &addresses)?;
+    // Check the balances against the state commitment
+    if args.verify {
+        let verification = report.phase("verify", || verify_snapshot(db_path, &snapshot))?;
+        let total: usize = verification.values().map(|m| m.len()).sum();
+        let verified = verification
+            .values()
+            .flat_map(|m| m.values())
+            .filter(|status| **status == VerificationStatus::Verified)
+            .count();
+        info!(verified, total, "Verified balances");
+        snapshot.verification = Some(verification);
+    }
+
+    // Export the proofs of the final balances for third party verification
+    if let Some(proof_file) = &args.export_proofs {
+        let export = report.phase("export_proofs", || {
+            export_snapshot_proofs(db_path, &snapshot)
+        })?;
+        let destination = Destination::File(proof_file.clone());
+        write_proof_export(&export, &destination, args.force)?;
+        info!(
+            balances = export.balances.len(),
+            block_number = export.block_number,
+            path = %destination,
+            "Storage proofs written"
+        );
+    }
+
+    // Summarize the holder distribution of every token
+    let stats = report.phase("stats", || compute_stats(&snapshot));
+    print_summary(&snapshot, &stats);
+    snapshot.stats = Some(stats);
 
     // Write results using the new output module
-    write_results(&token_map, &output_config)?;
+    report.outputs = report.phase("write_outputs", || {
+        write_results(&snapshot, &output_config, &mut sinks)
+    })?;
+
+    // Drop the archived runs the retention rules don't keep
+    if let (Some(retention), Some(archive)) = (&args.retention, &archive) {
+        report.phase("prune", || prune_archive(archive, retention))?;
+    }
+
+    // Build the airdrop merkle tree from the final balances
+    if let Some(rule) = &args.airdrop {
+        let token = match args.airdrop_token {
+            Some(token) => token,
+            None => match addresses.tokens.as_slice() {
+                [token] => *token,
+                _ => eyre::bail!("--airdrop-token is required when the input has several tokens"),
+            },
+        };
+        let airdrop = report.phase("airdrop", || {
+            build_airdrop(&snapshot, &token, rule, args.merkle_hash)
+        })?;
+        let destination = Destination::File(args.airdrop_file.clone());
+        write_airdrop(&airdrop, &destination, args.force)?;
+        info!(
+            root = %format!("{:#064x}", airdrop.root),
+            claims = airdrop.claims.len(),
+            path = %destination,
+            "Airdrop merkle tree written"
+        );
+    }
 
     Ok(())
 }
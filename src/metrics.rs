@@ -0,0 +1,368 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bigdecimal::ToPrimitive;
+use eyre::Result;
+use rusqlite::Connection;
+use serde::Deserialize;
+use starknet::core::types::Felt;
+use tracing::{debug, info, warn};
+
+use crate::balance::{balance_of, head_block_number, Addresses, DEFAULT_DECIMALS};
+use crate::server::ConnectionPool;
+use crate::valuation::to_units;
+
+/// Export the balances of a watch list as Prometheus gauges
+#[derive(Debug, clap::Args)]
+pub struct MetricsArgs {
+    /// Path to the JSON watch list, the (token, account) pairs to export
+    #[arg(long, env = "METRICS_WATCH")]
+    watch: PathBuf,
+
+    /// Path to an addresses JSON file with the token symbols and decimals and the account
+    /// labels and groups added to the gauges
+    #[arg(short, long, env = "INPUT_FILE")]
+    input_file: Option<String>,
+
+    /// Path to the database
+    #[arg(short, long, env = "DB_PATH")]
+    db_path: String,
+
+    /// Address and port of the `/metrics` endpoint
+    #[arg(long, env = "METRICS_LISTEN", default_value = "127.0.0.1:9100")]
+    listen: String,
+
+    /// Seconds between two evaluations of the watch list
+    #[arg(long, default_value_t = 60)]
+    interval: u64,
+}
+
+/// A balance exported as a gauge
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub struct WatchedBalance {
+    pub token: Felt,
+    pub account: Felt,
+}
+
+/// Load the watch list, rejecting pairs listed twice
+pub fn load_watch_list(path: &Path) -> Result<Vec<WatchedBalance>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| eyre::eyre!("Failed to read watch list '{}': {}", path.display(), e))?;
+    let watched: Vec<WatchedBalance> = serde_json::from_str(&content)
+        .map_err(|e| eyre::eyre!("Failed to parse watch list '{}': {}", path.display(), e))?;
+    let mut seen = HashSet::new();
+    if let Some(pair) = watched.iter().find(|pair| !seen.insert(**pair)) {
+        eyre::bail!(
+            "Balance of {:#064x} for token {:#064x} is watched twice",
+            pair.account,
+            pair.token
+        );
+    }
+    Ok(watched)
+}
+
+/// Watched balances read at one block
+#[derive(Debug, Clone, PartialEq)]
+pub struct Evaluation {
+    pub block_number: Option<u64>,
+    /// (token, account, balance) of every watched pair
+    pub balances: Vec<(Felt, Felt, Felt)>,
+    pub elapsed: Duration,
+    pub finished_at: SystemTime,
+}
+
+/// Last evaluation and the evaluation counters, rendered on every scrape
+#[derive(Debug, Default)]
+pub struct Exporter {
+    last: Option<Evaluation>,
+    evaluations: u64,
+    errors: u64,
+}
+
+impl Exporter {
+    pub fn record(&mut self, evaluation: Result<Evaluation>) {
+        self.evaluations += 1;
+        match evaluation {
+            Ok(evaluation) => self.last = Some(evaluation),
+            Err(e) => {
+                self.errors += 1;
+                warn!("Failed to evaluate the watch list: {}", e);
+            }
+        }
+    }
+
+    /// Metrics in the Prometheus text exposition format, labelled with the metadata of
+    /// `addresses` if given
+    pub fn render(&self, addresses: Option<&Addresses>) -> String {
+        let mut out = String::new();
+        if let Some(last) = &self.last {
+            gauge_header(
+                &mut out,
+                "balance_gettor_balance",
+                "Balance of a watched account in token units",
+            );
+            for (token, account, balance) in &last.balances {
+                let info = addresses.and_then(|a| a.token_info.get(token));
+                let label = addresses.and_then(|a| a.labels.get(account));
+                let labels = [
+                    ("token", Some(format!("{token:#064x}"))),
+                    ("symbol", info.and_then(|i| i.symbol.clone())),
+                    ("account", Some(format!("{account:#064x}"))),
+                    ("label", label.and_then(|l| l.label.clone())),
+                    ("group", label.and_then(|l| l.group.clone())),
+                ];
+                let decimals = info.map_or(DEFAULT_DECIMALS, |i| i.decimals_or_default());
+                let units = to_units(balance, decimals).to_f64().unwrap_or(f64::NAN);
+                sample(&mut out, "balance_gettor_balance", &labels, units);
+            }
+
+            if let Some(block_number) = last.block_number {
+                gauge_header(
+                    &mut out,
+                    "balance_gettor_head_block",
+                    "Head block of the database at the last evaluation",
+                );
+                sample(
+                    &mut out,
+                    "balance_gettor_head_block",
+                    &[],
+                    block_number as f64,
+                );
+            }
+            gauge_header(
+                &mut out,
+                "balance_gettor_query_duration_seconds",
+                "Time taken to read the watched balances at the last evaluation",
+            );
+            sample(
+                &mut out,
+                "balance_gettor_query_duration_seconds",
+                &[],
+                last.elapsed.as_secs_f64(),
+            );
+            gauge_header(
+                &mut out,
+                "balance_gettor_last_evaluation_timestamp_seconds",
+                "Unix time of the last successful evaluation",
+            );
+            let finished_at = last
+                .finished_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            sample(
+                &mut out,
+                "balance_gettor_last_evaluation_timestamp_seconds",
+                &[],
+                finished_at.as_secs_f64(),
+            );
+        }
+
+        for (name, help, value) in [
+            (
+                "balance_gettor_evaluations_total",
+                "Evaluations of the watch list",
+                self.evaluations,
+            ),
+            (
+                "balance_gettor_evaluation_errors_total",
+                "Evaluations of the watch list that failed",
+                self.errors,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+            sample(&mut out, name, &[], value as f64);
+        }
+        out
+    }
+}
+
+fn gauge_header(out: &mut String, name: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} gauge");
+}
+
+/// One sample line, skipping labels without a value
+fn sample(out: &mut String, name: &str, labels: &[(&str, Option<String>)], value: f64) {
+    let labels: Vec<String> = labels
+        .iter()
+        .filter_map(|(key, value)| {
+            let value = value.as_ref()?;
+            let escaped = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            Some(format!("{key}=\"{escaped}\""))
+        })
+        .collect();
+    if labels.is_empty() {
+        let _ = writeln!(out, "{name} {value}");
+    } else {
+        let _ = writeln!(out, "{name}{{{}}} {value}", labels.join(","));
+    }
+}
+
+/// Read the balance of every watched (token, account) pair at the head block
+pub fn evaluate(conn: &Connection, watched: &[WatchedBalance]) -> Result<Evaluation> {
+    let start = Instant::now();
+    let block_number = head_block_number(conn);
+    let at = block_number.unwrap_or(i64::MAX as u64);
+    let balances = watched
+        .iter()
+        .map(|pair| {
+            let balance = balance_of(conn, &pair.token, &pair.account, at)?;
+            Ok((pair.token, pair.account, balance))
+        })
+        .collect::<Result<_>>()?;
+    Ok(Evaluation {
+        block_number,
+        balances,
+        elapsed: start.elapsed(),
+        finished_at: SystemTime::now(),
+    })
+}
+
+/// Evaluate the watch list every interval and serve the metrics until the process is stopped
+pub fn run_metrics(args: &MetricsArgs) -> Result<()> {
+    let watched = load_watch_list(&args.watch)?;
+    let addresses = args.input_file.as_ref().map(Addresses::load).transpose()?;
+
+    let pool = ConnectionPool::open(&args.db_path, 1)?;
+    let server = tiny_http::Server::http(&args.listen)
+        .map_err(|e| eyre::eyre!("Failed to listen on '{}': {}", args.listen, e))?;
+    let exporter = Mutex::new(Exporter::default());
+    info!(
+        address = %args.listen,
+        interval_s = args.interval,
+        watched = watched.len(),
+        "Exporting metrics"
+    );
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| loop {
+            let evaluation = evaluate(&pool.get(), &watched);
+            if let Ok(evaluation) = &evaluation {
+                debug!(
+                    block_number = evaluation.block_number,
+                    elapsed_ms = evaluation.elapsed.as_millis() as u64,
+                    "Evaluated the watch list"
+                );
+            }
+            exporter.lock().unwrap().record(evaluation);
+            std::thread::sleep(Duration::from_secs(args.interval));
+        });
+
+        for request in server.incoming_requests() {
+            let response = if request.url() == "/metrics" {
+                let header = tiny_http::Header::from_bytes(
+                    "Content-Type",
+                    "text/plain; version=0.0.4; charset=utf-8",
+                )
+                .unwrap();
+                tiny_http::Response::from_string(
+                    exporter.lock().unwrap().render(addresses.as_ref()),
+                )
+                .with_header(header)
+            } else {
+                tiny_http::Response::from_string("Not found").with_status_code(404)
+            };
+            if let Err(e) = request.respond(response) {
+                warn!("Failed to send metrics: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::balance::{create_storage_tables, insert_balance, AccountLabel, TokenInfo};
+
+    #[test]
+    fn test_evaluate_and_render() -> eyre::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE block_headers (number INTEGER PRIMARY KEY);
+            INSERT INTO block_headers VALUES (7);",
+        )?;
+        create_storage_tables(&conn)?;
+        let token = Felt::from(0x100u64);
+        let (treasury, hot) = (Felt::from(1u64), Felt::from(2u64));
+        insert_balance(&conn, &token, &treasury, 1_500_000, 7)?;
+        let addresses = Addresses {
+            accounts: vec![treasury, hot],
+            tokens: vec![token],
+            labels: HashMap::from([(
+                treasury,
+                AccountLabel {
+                    label: Some("treasury \"main\"".to_string()),
+                    group: Some("ops".to_string()),
+                },
+            )]),
+            token_info: HashMap::from([(
+                token,
+                TokenInfo {
+                    symbol: Some("USDC".to_string()),
+                    decimals: Some(6),
+                },
+            )]),
+        };
+
+        let watched = [
+            WatchedBalance {
+                token,
+                account: treasury,
+            },
+            WatchedBalance {
+                token,
+                account: hot,
+            },
+        ];
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("watch.json");
+        std::fs::write(
+            &path,
+            r#"[{"token": "0x100", "account": "0x1"}, {"token": "0x100", "account": "0x2"}]"#,
+        )?;
+        assert_eq!(load_watch_list(&path)?, watched);
+        std::fs::write(
+            &path,
+            r#"[{"token": "0x100", "account": "0x1"}, {"token": "0x100", "account": "0x01"}]"#,
+        )?;
+        assert!(load_watch_list(&path).is_err());
+
+        let evaluation = evaluate(&conn, &watched)?;
+        assert_eq!(evaluation.block_number, Some(7));
+        assert_eq!(evaluation.balances[0].2, Felt::from(1_500_000u64));
+        assert_eq!(evaluation.balances[1].2, Felt::ZERO);
+
+        let mut exporter = Exporter::default();
+        exporter.record(Err(eyre::eyre!("database is locked")));
+        exporter.record(Ok(evaluation));
+        let metrics = exporter.render(Some(&addresses));
+        let token_label = format!("token=\"{token:#064x}\"");
+        assert!(metrics.contains(&format!(
+            "balance_gettor_balance{{{token_label},symbol=\"USDC\",account=\"{treasury:#064x}\",\
+             label=\"treasury \\\"main\\\"\",group=\"ops\"}} 1.5\n"
+        )));
+        assert!(metrics.contains(&format!(
+            "balance_gettor_balance{{{token_label},symbol=\"USDC\",account=\"{hot:#064x}\"}} 0\n"
+        )));
+        assert!(metrics
+            .contains("# TYPE balance_gettor_head_block gauge\nbalance_gettor_head_block 7\n"));
+        assert!(metrics.contains("balance_gettor_evaluations_total 2\n"));
+        assert!(metrics.contains("balance_gettor_evaluation_errors_total 1\n"));
+
+        // Only the watched pairs are exported, with bare labels without an input file
+        let metrics = exporter.render(None);
+        assert_eq!(metrics.matches("balance_gettor_balance{").count(), 2);
+        assert!(metrics.contains(&format!(
+            "balance_gettor_balance{{{token_label},account=\"{hot:#064x}\"}} 0\n"
+        )));
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+use arrow::array::{
+    ArrayRef, BooleanArray, Decimal128Array, FixedSizeBinaryBuilder, StringArray, UInt64Array,
+    UInt8Array,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use bigdecimal::ToPrimitive;
+use eyre::Result;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use starknet::core::types::Felt;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::sync::Arc;
+use tracing::info;
+
+use super::{
+    sorted_balances, sorted_tokens, BalanceRecord, Formatting, OutputContext, OutputSink,
+    SinkOptions, WRITE_CHUNK,
+};
+use crate::balance::BalanceSnapshot;
+use crate::destination::Destination;
+use crate::proof::VerificationStatus;
+use crate::valuation::FIAT_SCALE;
+
+/// Precision of the fiat `value` column, the widest 128-bit decimal
+const VALUE_PRECISION: u8 = 38;
+
+/// File format of a [`ColumnarSink`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnarFormat {
+    Parquet,
+    /// Arrow IPC file format
+    Arrow,
+}
+
+impl ColumnarFormat {
+    fn name(&self) -> &'static str {
+        match self {
+            ColumnarFormat::Parquet => "parquet",
+            ColumnarFormat::Arrow => "arrow",
+        }
+    }
+}
+
+/// How token and account addresses are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AddressEncoding {
+    /// `0x`-prefixed hex strings in the `--address-format`, as in the CSV output
+    Hex,
+    /// 32-byte big-endian fixed-size binary
+    Binary,
+}
+
+/// Writes the balances as a typed Parquet or Arrow IPC file, one row per balance
+///
+/// Options: `path` (`-` for stdout), `addresses` (`hex` or `binary`, default `hex`) and,
+/// for Parquet, `compression` (`snappy`, `zstd` or `none`, default `snappy`).
+pub struct ColumnarSink {
+    format: ColumnarFormat,
+    path: Option<Destination>,
+    addresses: AddressEncoding,
+    compression: Compression,
+}
+
+impl ColumnarSink {
+    pub fn from_options(format: ColumnarFormat, options: &SinkOptions) -> Result<Self> {
+        match format {
+            ColumnarFormat::Parquet => {
+                options.check_known("parquet", &["path", "addresses", "compression"])?
+            }
+            ColumnarFormat::Arrow => options.check_known("arrow", &["path", "addresses"])?,
+        }
+        let addresses = match options.get("addresses") {
+            None | Some("hex") => AddressEncoding::Hex,
+            Some("binary") => AddressEncoding::Binary,
+            Some(value) => {
+                return Err(eyre::eyre!(
+                    "Option 'addresses' must be hex or binary, got '{}'",
+                    value
+                ))
+            }
+        };
+        let compression = match options.get("compression") {
+            None | Some("snappy") => Compression::SNAPPY,
+            Some("zstd") => Compression::ZSTD(ZstdLevel::default()),
+            Some("none") => Compression::UNCOMPRESSED,
+            Some(value) => {
+                return Err(eyre::eyre!(
+                    "Option 'compression' must be snappy, zstd or none, got '{}'",
+                    value
+                ))
+            }
+        };
+        Ok(Self {
+            format,
+            path: options.destination(),
+            addresses,
+            compression,
+        })
+    }
+
+    fn schema(&self, snapshot: &BalanceSnapshot, context: &OutputContext) -> SchemaRef {
+        let address = match self.addresses {
+            AddressEncoding::Hex => DataType::Utf8,
+            AddressEncoding::Binary => DataType::FixedSizeBinary(32),
+        };
+        let fields = vec![
+            Field::new("token", address.clone(), false),
+            Field::new("account", address, false),
+            // Amount in the `--amount-format`, a felt can exceed the 38 digits of most
+            // engines' decimals
+            Field::new("balance", DataType::Utf8, false),
+            Field::new("decimals", DataType::UInt8, false),
+            Field::new("symbol", DataType::Utf8, true),
+            Field::new("block_number", DataType::UInt64, true),
+            Field::new("label", DataType::Utf8, true),
+            Field::new("account_group", DataType::Utf8, true),
+            Field::new(
+                "value",
+                DataType::Decimal128(VALUE_PRECISION, FIAT_SCALE as i8),
+                true,
+            ),
+            Field::new("verified", DataType::Boolean, true),
+        ];
+
+        let mut metadata = HashMap::new();
+        if let Some(block_number) = snapshot.block_number {
+            metadata.insert("block_number".to_string(), block_number.to_string());
+        }
+        if let Some(input_hash) = context.input_hash {
+            metadata.insert("input_hash".to_string(), input_hash.to_string());
+        }
+        Arc::new(Schema::new_with_metadata(fields, metadata))
+    }
+
+    fn addresses_column<'a>(
+        &self,
+        addresses: impl ExactSizeIterator<Item = &'a Felt>,
+        formatting: &Formatting,
+    ) -> Result<ArrayRef, Box<dyn Error>> {
+        Ok(match self.addresses {
+            AddressEncoding::Hex => Arc::new(StringArray::from_iter_values(
+                addresses.map(|address| formatting.address(address)),
+            )),
+            AddressEncoding::Binary => {
+                let mut builder = FixedSizeBinaryBuilder::with_capacity(addresses.len(), 32);
+                for address in addresses {
+                    builder.append_value(address.to_bytes_be())?;
+                }
+                Arc::new(builder.finish())
+            }
+        })
+    }
+
+    /// Build the record batch of a chunk of sorted records
+    fn record_batch(
+        &self,
+        schema: &SchemaRef,
+        snapshot: &BalanceSnapshot,
+        formatting: &Formatting,
+        chunk: &[BalanceRecord],
+    ) -> Result<RecordBatch, Box<dyn Error>> {
+        let values = chunk
+            .iter()
+            .map(|(token, account, _)| {
+                let value = snapshot
+                    .valuation
+                    .as_ref()
+                    .and_then(|v| v.value_of(token, account));
+                match value {
+                    None => Ok(None),
+                    Some(value) => {
+                        let (digits, _) = value
+                            .round(FIAT_SCALE)
+                            .with_scale(FIAT_SCALE)
+                            .into_bigint_and_exponent();
+                        digits.to_i128().map(Some).ok_or_else(|| {
+                            format!("value {value} of account {account:#064x} is out of range")
+                        })
+                    }
+                }
+            })
+            .collect::<Result<Decimal128Array, String>>()?
+            .with_precision_and_scale(VALUE_PRECISION, FIAT_SCALE as i8)?;
+
+        let columns: Vec<ArrayRef> = vec![
+            self.addresses_column(chunk.iter().map(|(token, _, _)| *token), formatting)?,
+            self.addresses_column(chunk.iter().map(|(_, account, _)| *account), formatting)?,
+            Arc::new(StringArray::from_iter_values(chunk.iter().map(
+                |(token, _, balance)| formatting.balance(balance, snapshot.decimals_of(token)),
+            ))),
+            Arc::new(UInt8Array::from_iter_values(
+                chunk
+                    .iter()
+                    .map(|(token, _, _)| snapshot.decimals_of(token)),
+            )),
+            Arc::new(StringArray::from_iter(chunk.iter().map(|(token, _, _)| {
+                snapshot
+                    .token_info
+                    .get(token)
+                    .and_then(|info| info.symbol.as_deref())
+            }))),
+            Arc::new(UInt64Array::from_iter(
+                chunk.iter().map(|_| snapshot.block_number),
+            )),
+            Arc::new(StringArray::from_iter(chunk.iter().map(
+                |(_, account, _)| snapshot.label_of(account).and_then(|l| l.label.as_deref()),
+            ))),
+            Arc::new(StringArray::from_iter(chunk.iter().map(
+                |(_, account, _)| snapshot.label_of(account).and_then(|l| l.group.as_deref()),
+            ))),
+            Arc::new(values),
+            Arc::new(BooleanArray::from_iter(chunk.iter().map(
+                |(token, account, _)| {
+                    snapshot
+                        .verification_of(token, account)
+                        .map(|status| *status == VerificationStatus::Verified)
+                },
+            ))),
+        ];
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+
+    /// Write the sorted balances one bounded record batch at a time
+    fn write_batches(
+        &self,
+        schema: &SchemaRef,
+        snapshot: &BalanceSnapshot,
+        formatting: &Formatting,
+        mut write: impl FnMut(&RecordBatch) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut records = sorted_balances(snapshot, sorted_tokens(snapshot));
+        loop {
+            let chunk: Vec<BalanceRecord> = records.by_ref().take(WRITE_CHUNK).collect();
+            if chunk.is_empty() {
+                return Ok(());
+            }
+            write(&self.record_batch(schema, snapshot, formatting, &chunk)?)?;
+        }
+    }
+
+    fn store(
+        &self,
+        snapshot: &BalanceSnapshot,
+        schema: &SchemaRef,
+        formatting: &Formatting,
+        writer: &mut (dyn Write + Send),
+    ) -> Result<(), Box<dyn Error>> {
+        match self.format {
+            ColumnarFormat::Parquet => {
+                let properties = WriterProperties::builder()
+                    .set_compression(self.compression)
+                    .build();
+                let mut wtr = ArrowWriter::try_new(writer, schema.clone(), Some(properties))?;
+                self.write_batches(schema, snapshot, formatting, |batch| Ok(wtr.write(batch)?))?;
+                wtr.close()?;
+            }
+            ColumnarFormat::Arrow => {
+                let mut wtr = FileWriter::try_new(writer, schema)?;
+                self.write_batches(schema, snapshot, formatting, |batch| Ok(wtr.write(batch)?))?;
+                wtr.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for ColumnarSink {
+    fn write(&mut self, snapshot: &BalanceSnapshot, context: &OutputContext) -> Result<()> {
+        let name = self.format.name();
+        let destination = context.destination(self.path.as_ref(), &format!("token_map.{name}"));
+        let schema = self.schema(snapshot, context);
+
+        let start = std::time::SystemTime::now();
+        context
+            .write_output(&destination, name, None, false, |w| {
+                self.store(snapshot, &schema, &context.formatting, w)
+            })
+            .map_err(|e| eyre::eyre!("Failed to store map as {}: {}", name, e))?;
+        let time = std::time::SystemTime::now().duration_since(start).unwrap();
+        info!(
+            path = %destination,
+            elapsed_ms = time.as_millis() as u64,
+            "Results written"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::TokenInfo;
+    use arrow::array::{Array, AsArray};
+    use arrow::datatypes::Decimal128Type;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    #[test]
+    fn test_columnar_outputs() -> Result<(), Box<dyn Error>> {
+        let token = Felt::from(1u64);
+        let snapshot = BalanceSnapshot {
+            balances: HashMap::from([(
+                token,
+                HashMap::from([
+                    (Felt::from(11u64), Felt::from(200u64)),
+                    (Felt::from(10u64), Felt::from(100u64)),
+                ]),
+            )]),
+            token_info: HashMap::from([(
+                token,
+                TokenInfo {
+                    symbol: Some("TKN".to_string()),
+                    decimals: Some(6),
+                },
+            )]),
+            block_number: Some(7),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir()?;
+        let context = OutputContext::new(dir.path());
+
+        let mut options = SinkOptions::default();
+        options.insert("addresses", "binary");
+        ColumnarSink::from_options(ColumnarFormat::Parquet, &options)?
+            .write(&snapshot, &context)?;
+        ColumnarSink::from_options(ColumnarFormat::Arrow, &SinkOptions::default())?
+            .write(&snapshot, &context)?;
+
+        let parquet = std::fs::File::open(dir.path().join("token_map.parquet"))?;
+        let batches = ParquetRecordBatchReaderBuilder::try_new(parquet)?
+            .build()?
+            .collect::<Result<Vec<_>, _>>()?;
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(
+            batch.schema().field_with_name("account")?.data_type(),
+            &DataType::FixedSizeBinary(32)
+        );
+        let accounts = batch.column(1).as_fixed_size_binary();
+        assert_eq!(accounts.value(0), Felt::from(10u64).to_bytes_be());
+        assert_eq!(
+            batch
+                .column(3)
+                .as_primitive::<arrow::datatypes::UInt8Type>()
+                .value(0),
+            6
+        );
+        assert!(batch.column(8).as_primitive::<Decimal128Type>().is_null(0));
+
+        let arrow = std::fs::File::open(dir.path().join("token_map.arrow"))?;
+        let reader = arrow::ipc::reader::FileReader::try_new(arrow, None)?;
+        assert_eq!(reader.schema().metadata()["block_number"], "7");
+        let batch = reader.into_iter().next().unwrap()?;
+        let accounts = batch.column(1).as_string::<i32>();
+        assert_eq!(accounts.value(1), format!("{:#064x}", Felt::from(11u64)));
+        assert_eq!(batch.column(2).as_string::<i32>().value(1), "200");
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use csv::{Writer, WriterBuilder};
+use eyre::Result;
+use rayon::prelude::*;
+use starknet::core::types::Felt;
+use std::collections::BTreeMap;
+use std::io::Write;
+use tracing::info;
+
+use super::{
+    balances_by_account, label_columns, sorted_balances, token_columns, value_column,
+    verification_column, BalanceRecord, Formatting, OutputContext, OutputSink, SinkOptions,
+    WRITE_CHUNK,
+};
+use crate::balance::BalanceSnapshot;
+use crate::destination::Destination;
+use crate::groups::GroupSummary;
+use crate::valuation::{format_value, Valuation};
+
+/// Writes `token_map.csv` and its group and portfolio companions
+///
+/// Options: `path` (`-` for stdout), `delimiter`, a single character, and `layout`:
+/// `long` (default) for one row per balance or `wide` for one row per account and one
+/// column per token.
+pub struct CsvSink {
+    path: Option<Destination>,
+    delimiter: u8,
+    wide: bool,
+}
+
+impl CsvSink {
+    pub fn from_options(options: &SinkOptions) -> Result<Self> {
+        options.check_known("csv", &["path", "delimiter", "layout"])?;
+        let delimiter = match options.get("delimiter") {
+            None => b',',
+            Some(value) if value.len() == 1 => value.as_bytes()[0],
+            Some(value) => {
+                return Err(eyre::eyre!(
+                    "CSV delimiter must be a single character, got '{}'",
+                    value
+                ))
+            }
+        };
+        let wide = match options.get("layout") {
+            None | Some("long") => false,
+            Some("wide") => true,
+            Some(value) => {
+                return Err(eyre::eyre!(
+                    "Option 'layout' must be long or wide, got '{}'",
+                    value
+                ))
+            }
+        };
+        Ok(Self {
+            path: options.destination(),
+            delimiter,
+            wide,
+        })
+    }
+
+    fn writer<'w>(&self, writer: &'w mut dyn Write) -> Writer<&'w mut dyn Write> {
+        WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .from_writer(writer)
+    }
+}
+
+impl OutputSink for CsvSink {
+    fn write(&mut self, snapshot: &BalanceSnapshot, context: &OutputContext) -> Result<()> {
+        let main = context.destination(self.path.as_ref(), "token_map.csv");
+        let partitions = context.partitions(snapshot);
+
+        // Every file is checked before the first one is written
+        let mut destinations: Vec<Destination> = partitions
+            .iter()
+            .map(|partition| context.partition_destination(&main, partition, "csv"))
+            .collect();
+        if context.groups.is_some() {
+            destinations.push(context.companion(&main, "groups", "csv"));
+        }
+        if snapshot.valuation.is_some() {
+            destinations.push(context.companion(&main, "portfolio", "csv"));
+        }
+        context.check_destinations(&destinations, true)?;
+
+        for partition in partitions {
+            let destination = context.partition_destination(&main, &partition, "csv");
+            let csv_start = std::time::SystemTime::now();
+            let destination = context
+                .write_output(&destination, "csv", partition.token(), true, |w| {
+                    let tokens = partition.tokens.clone();
+                    let formatting = &context.formatting;
+                    if self.wide {
+                        store_wide_csv(snapshot, &tokens, formatting, &mut self.writer(w))
+                    } else {
+                        store_map_as_csv(snapshot, tokens, formatting, &mut self.writer(w))
+                    }
+                })
+                .map_err(|e| eyre::eyre!("Failed to store map as CSV: {}", e))?;
+            let csv_end = std::time::SystemTime::now();
+            let csv_time = csv_end.duration_since(csv_start).unwrap();
+            info!(
+                path = %destination,
+                elapsed_ms = csv_time.as_millis() as u64,
+                "Results written"
+            );
+        }
+
+        if let Some(groups) = context.groups {
+            let destination = context.companion(&main, "groups", "csv");
+            let destination = context
+                .write_output(&destination, "csv", None, true, |w| {
+                    store_groups_as_csv(snapshot, groups, &context.formatting, &mut self.writer(w))
+                })
+                .map_err(|e| eyre::eyre!("Failed to store group summary as CSV: {}", e))?;
+            info!(path = %destination, "Group summary written");
+        }
+
+        if let Some(valuation) = &snapshot.valuation {
+            let destination = context.companion(&main, "portfolio", "csv");
+            let destination = context
+                .write_output(&destination, "csv", None, true, |w| {
+                    let formatting = &context.formatting;
+                    store_portfolio_as_csv(snapshot, valuation, formatting, &mut self.writer(w))
+                })
+                .map_err(|e| eyre::eyre!("Failed to store portfolio values as CSV: {}", e))?;
+            info!(path = %destination, "Portfolio values written");
+        }
+
+        Ok(())
+    }
+}
+
+/// Store the token map as a CSV file, sorted by token and account
+fn store_map_as_csv<'a>(
+    snapshot: &'a BalanceSnapshot,
+    tokens: Vec<&'a Felt>,
+    formatting: &Formatting,
+    wtr: &mut Writer<&mut dyn Write>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Write header row
+    wtr.write_record([
+        "Token", "Account", "Balance", "Label", "Group", "Value", "Verified",
+    ])?;
+
+    // Format bounded chunks of the sorted records in parallel, then write them in order
+    // (CSV writer isn't thread-safe)
+    let mut records = sorted_balances(snapshot, tokens);
+    loop {
+        let chunk: Vec<BalanceRecord> = records.by_ref().take(WRITE_CHUNK).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        let rows: Vec<[String; 7]> = chunk
+            .par_iter()
+            .map(|(token, account, balance)| {
+                let (label, group) = label_columns(snapshot, account);
+                [
+                    formatting.address(token),
+                    formatting.address(account),
+                    formatting.balance(balance, snapshot.decimals_of(token)),
+                    label,
+                    group,
+                    value_column(snapshot, token, account).unwrap_or_default(),
+                    verification_column(snapshot, token, account).unwrap_or_default(),
+                ]
+            })
+            .collect();
+        for row in rows {
+            wtr.write_record(&row)?;
+        }
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Store the balances with one row per account and one column per token
+///
+/// Cells of tokens the account has no balance entry for are left empty.
+fn store_wide_csv(
+    snapshot: &BalanceSnapshot,
+    tokens: &[&Felt],
+    formatting: &Formatting,
+    wtr: &mut Writer<&mut dyn Write>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let columns = token_columns(snapshot, tokens, formatting);
+    let mut header = vec![
+        "Account".to_string(),
+        "Label".to_string(),
+        "Group".to_string(),
+    ];
+    header.extend(columns.iter().map(|(_, column)| column.clone()));
+    wtr.write_record(&header)?;
+
+    for (account, balances) in balances_by_account(snapshot, tokens) {
+        let (label, group) = label_columns(snapshot, account);
+        let mut row = vec![formatting.address(account), label, group];
+        row.extend(columns.iter().map(|(token, _)| {
+            balances
+                .get(token)
+                .map(|balance| formatting.balance(balance, snapshot.decimals_of(token)))
+                .unwrap_or_default()
+        }));
+        wtr.write_record(&row)?;
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Store the per-group totals as a CSV file
+fn store_groups_as_csv(
+    snapshot: &BalanceSnapshot,
+    groups: &BTreeMap<String, GroupSummary>,
+    formatting: &Formatting,
+    wtr: &mut Writer<&mut dyn Write>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    wtr.write_record(["Group", "Token", "Accounts", "Total"])?;
+    for (group, summary) in groups {
+        for (token, total) in &summary.totals {
+            wtr.write_record([
+                group.clone(),
+                formatting.address(token),
+                summary.holders.get(token).copied().unwrap_or(0).to_string(),
+                formatting.total(total, snapshot.decimals_of(token)),
+            ])?;
+        }
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Store the total fiat value per account as a CSV file
+fn store_portfolio_as_csv(
+    snapshot: &BalanceSnapshot,
+    valuation: &Valuation,
+    formatting: &Formatting,
+    wtr: &mut Writer<&mut dyn Write>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    wtr.write_record(["Account", "Label", "Group", "Total Value"])?;
+    for (account, total) in &valuation.portfolio_totals {
+        let (label, group) = label_columns(snapshot, account);
+        wtr.write_record([
+            formatting.address(account),
+            label,
+            group,
+            format_value(total),
+        ])?;
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
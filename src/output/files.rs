@@ -0,0 +1,154 @@
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use starknet::core::types::Felt;
+use std::error::Error;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// On-the-fly compression of text outputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Extension appended to the file name, e.g. `token_map.csv.gz`
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        }
+    }
+}
+
+/// Run `write` through the encoder of `compression`, if any, and finish the stream
+pub fn write_compressed<F>(
+    compression: Option<Compression>,
+    writer: &mut (dyn Write + Send),
+    write: F,
+) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(&mut (dyn Write + Send)) -> Result<(), Box<dyn Error>>,
+{
+    match compression {
+        None => write(writer),
+        Some(Compression::Gzip) => {
+            let mut encoder = GzEncoder::new(writer, flate2::Compression::default());
+            write(&mut encoder)?;
+            encoder.finish()?;
+            Ok(())
+        }
+        Some(Compression::Zstd) => {
+            let mut encoder = zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            write(&mut encoder)?;
+            encoder.finish()?;
+            Ok(())
+        }
+    }
+}
+
+/// Counts and hashes the bytes passed to the inner writer
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Number of bytes written and their hex SHA-256
+    pub fn finish(self) -> (u64, String) {
+        (self.bytes, hex::encode(self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A file written by a sink, as listed in the manifest
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub format: String,
+    /// Token of a per-token partition
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_token"
+    )]
+    pub token: Option<Felt>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+/// Files produced by a run, with the run metadata needed to check them later
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest<'a> {
+    pub created_at: u64,
+    pub block_number: Option<u64>,
+    pub input_hash: Option<&'a str>,
+    pub files: &'a [ManifestEntry],
+}
+
+fn serialize_token<S>(token: &Option<Felt>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match token {
+        Some(token) => serializer.serialize_str(&format!("{token:#064x}")),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_compressed_round_trip() -> Result<(), Box<dyn Error>> {
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let mut output = Vec::new();
+            let mut hashing = HashingWriter::new(&mut output);
+            write_compressed(Some(compression), &mut hashing, |w| {
+                Ok(w.write_all(b"token,account,balance\n")?)
+            })?;
+            let (bytes, sha256) = hashing.finish();
+            assert_eq!(bytes, output.len() as u64);
+            assert_eq!(sha256, hex::encode(Sha256::digest(&output)));
+
+            let mut decoded = String::new();
+            match compression {
+                Compression::Gzip => {
+                    flate2::read::GzDecoder::new(output.as_slice()).read_to_string(&mut decoded)?
+                }
+                Compression::Zstd => {
+                    zstd::Decoder::new(output.as_slice())?.read_to_string(&mut decoded)?
+                }
+            };
+            assert_eq!(decoded, "token,account,balance\n");
+        }
+        Ok(())
+    }
+}
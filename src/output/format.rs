@@ -0,0 +1,203 @@
+use bigdecimal::{BigDecimal, RoundingMode};
+use num_bigint::{BigInt, BigUint};
+use starknet::core::types::Felt;
+use starknet::core::utils::starknet_keccak;
+
+use crate::valuation::to_units;
+
+/// How balances are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum AmountFormat {
+    /// Decimal integer in the smallest unit of the token
+    #[default]
+    Raw,
+    /// `0x`-prefixed hex integer in the smallest unit of the token
+    Hex,
+    /// Decimal number of whole tokens, using the token decimals
+    Units,
+}
+
+/// Rounding of token units to the requested precision
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Rounding {
+    /// Round half to even
+    #[default]
+    HalfEven,
+    /// Round half away from zero
+    HalfUp,
+    /// Truncate
+    Down,
+    /// Round away from zero
+    Up,
+}
+
+impl From<Rounding> for RoundingMode {
+    fn from(rounding: Rounding) -> Self {
+        match rounding {
+            Rounding::HalfEven => RoundingMode::HalfEven,
+            Rounding::HalfUp => RoundingMode::HalfUp,
+            Rounding::Down => RoundingMode::Down,
+            Rounding::Up => RoundingMode::Up,
+        }
+    }
+}
+
+/// How token and account addresses are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum AddressFormat {
+    /// Zero-padded to 64 hex digits
+    #[default]
+    Padded,
+    /// Without leading zeros
+    Unpadded,
+    /// Zero-padded with the Starknet mixed-case checksum
+    Checksummed,
+}
+
+/// Formatting of amounts and addresses, shared by every writer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Formatting {
+    pub amounts: AmountFormat,
+    /// Decimal places of amounts in token units, all decimals of the token when `None`
+    pub precision: Option<u32>,
+    pub rounding: Rounding,
+    pub addresses: AddressFormat,
+}
+
+impl Formatting {
+    /// Format a token or account address
+    pub fn address(&self, address: &Felt) -> String {
+        match self.addresses {
+            AddressFormat::Padded => format!("{address:#064x}"),
+            AddressFormat::Unpadded => format!("{address:#x}"),
+            AddressFormat::Checksummed => checksum_address(address),
+        }
+    }
+
+    /// Format a balance of a token with `decimals`
+    pub fn balance(&self, balance: &Felt, decimals: u8) -> String {
+        match self.amounts {
+            AmountFormat::Raw => balance.to_string(),
+            AmountFormat::Hex => format!("{balance:#x}"),
+            AmountFormat::Units => self.units(to_units(balance, decimals)),
+        }
+    }
+
+    /// Format a sum of balances, which may exceed the field size
+    pub fn total(&self, total: &BigUint, decimals: u8) -> String {
+        match self.amounts {
+            AmountFormat::Raw => total.to_string(),
+            AmountFormat::Hex => format!("{total:#x}"),
+            AmountFormat::Units => self.units(BigDecimal::new(
+                BigInt::from(total.clone()),
+                decimals as i64,
+            )),
+        }
+    }
+
+    /// Plain decimal, never in exponent notation, even for dust amounts
+    fn units(&self, units: BigDecimal) -> String {
+        match self.precision {
+            Some(precision) => units
+                .with_scale_round(precision as i64, self.rounding.into())
+                .to_plain_string(),
+            None => units.to_plain_string(),
+        }
+    }
+}
+
+/// Padded address with the hex digits uppercased where the matching nibble of the
+/// `starknet_keccak` of the address is 8 or more, as computed by starknet.js
+fn checksum_address(address: &Felt) -> String {
+    let bytes = address.to_bytes_be();
+    let start = bytes
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(bytes.len() - 1);
+    let hash = starknet_keccak(&bytes[start..]).to_bytes_be();
+
+    let digits = format!("{address:064x}");
+    let checksummed: String = digits
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble = if i % 2 == 0 {
+                hash[i / 2] >> 4
+            } else {
+                hash[i / 2] & 0x0f
+            };
+            if nibble >= 8 {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        })
+        .collect();
+    format!("0x{checksummed}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amount_formats() {
+        let balance = Felt::from(1_234_567u64);
+        let formatting = |amounts, precision, rounding| Formatting {
+            amounts,
+            precision,
+            rounding,
+            ..Default::default()
+        };
+
+        let raw = formatting(AmountFormat::Raw, None, Rounding::HalfEven);
+        assert_eq!(raw.balance(&balance, 6), "1234567");
+        let hex = formatting(AmountFormat::Hex, None, Rounding::HalfEven);
+        assert_eq!(hex.balance(&balance, 6), "0x12d687");
+        let units = formatting(AmountFormat::Units, None, Rounding::HalfEven);
+        assert_eq!(units.balance(&balance, 6), "1.234567");
+        assert_eq!(units.total(&BigUint::from(1_234_567u64), 6), "1.234567");
+
+        let cases = [
+            (Rounding::HalfEven, "1.2346"),
+            (Rounding::HalfUp, "1.2346"),
+            (Rounding::Down, "1.2345"),
+            (Rounding::Up, "1.2346"),
+        ];
+        for (rounding, expected) in cases {
+            let rounded = formatting(AmountFormat::Units, Some(4), rounding);
+            assert_eq!(rounded.balance(&balance, 6), expected);
+        }
+        let whole = formatting(AmountFormat::Units, Some(0), Rounding::HalfEven);
+        assert_eq!(whole.balance(&Felt::from(2_500_000u64), 6), "2");
+        assert_eq!(
+            units.balance(&Felt::from(200u64), 18),
+            "0.000000000000000200"
+        );
+    }
+
+    #[test]
+    fn test_address_formats() {
+        let address =
+            Felt::from_hex("0x49d36570d4e46f48e99674bd3fcc84644ddd6b96f7c741b1562b82f9e004dc7")
+                .unwrap();
+        let formatting = |addresses| Formatting {
+            addresses,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            formatting(AddressFormat::Padded).address(&address),
+            "0x049d36570d4e46f48e99674bd3fcc84644ddd6b96f7c741b1562b82f9e004dc7"
+        );
+        assert_eq!(
+            formatting(AddressFormat::Unpadded).address(&address),
+            "0x49d36570d4e46f48e99674bd3fcc84644ddd6b96f7c741b1562b82f9e004dc7"
+        );
+        // starknet.js getChecksumAddress of the ETH token
+        assert_eq!(
+            formatting(AddressFormat::Checksummed).address(&address),
+            "0x049D36570D4e46f48e99674bd3fcc84644DdD6b96F7C741B1562B82f9e004dC7"
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use eyre::Result;
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use starknet::core::types::Felt;
+use std::collections::BTreeMap;
+use std::io::Write;
+use tracing::info;
+
+use super::{
+    balances_by_account, label_columns, value_column, Formatting, OutputContext, OutputSink,
+    SinkOptions,
+};
+use crate::balance::BalanceSnapshot;
+use crate::destination::Destination;
+use crate::groups::GroupSummary;
+use crate::stats::TokenStats;
+use crate::valuation::{format_value, Valuation};
+
+/// Writes `token_map.json` and its group, stats and portfolio companions
+///
+/// Options: `path` (`-` for stdout), `pretty`, `true` by default, and `layout`: `token`
+/// (default) for `token -> account -> balance` or `account` for `account -> token -> balance`.
+/// With a valuation or account labels every balance is an object with the `balance` and
+/// its `value`, `label` and `group`.
+pub struct JsonSink {
+    path: Option<Destination>,
+    pretty: bool,
+    by_account: bool,
+}
+
+impl JsonSink {
+    pub fn from_options(options: &SinkOptions) -> Result<Self> {
+        options.check_known("json", &["path", "pretty", "layout"])?;
+        let by_account = match options.get("layout") {
+            None | Some("token") => false,
+            Some("account") => true,
+            Some(value) => {
+                return Err(eyre::eyre!(
+                    "Option 'layout' must be token or account, got '{}'",
+                    value
+                ))
+            }
+        };
+        Ok(Self {
+            path: options.destination(),
+            pretty: options.flag("pretty", true)?,
+            by_account,
+        })
+    }
+
+    fn to_writer<T: Serialize + ?Sized>(
+        &self,
+        writer: &mut dyn Write,
+        value: &T,
+    ) -> serde_json::Result<()> {
+        if self.pretty {
+            serde_json::to_writer_pretty(writer, value)
+        } else {
+            serde_json::to_writer(writer, value)
+        }
+    }
+}
+
+impl OutputSink for JsonSink {
+    fn write(&mut self, snapshot: &BalanceSnapshot, context: &OutputContext) -> Result<()> {
+        let main = context.destination(self.path.as_ref(), "token_map.json");
+        let partitions = context.partitions(snapshot);
+
+        // Every file is checked before the first one is written
+        let mut destinations: Vec<Destination> = partitions
+            .iter()
+            .map(|partition| context.partition_destination(&main, partition, "json"))
+            .collect();
+        if context.groups.is_some() {
+            destinations.push(context.companion(&main, "groups", "json"));
+        }
+        if snapshot.stats.is_some() {
+            destinations.push(context.companion(&main, "stats", "json"));
+        }
+        if snapshot.valuation.is_some() {
+            destinations.push(context.companion(&main, "portfolio", "json"));
+        }
+        context.check_destinations(&destinations, true)?;
+
+        for partition in partitions {
+            let destination = context.partition_destination(&main, &partition, "json");
+            let json_start = std::time::SystemTime::now();
+            let destination = context
+                .write_output(&destination, "json", partition.token(), true, |w| {
+                    store_map_as_json(self, snapshot, &partition.tokens, &context.formatting, w)
+                })
+                .map_err(|e| eyre::eyre!("Failed to store map as JSON: {}", e))?;
+            let json_end = std::time::SystemTime::now();
+            let json_time = json_end.duration_since(json_start).unwrap();
+            info!(
+                path = %destination,
+                elapsed_ms = json_time.as_millis() as u64,
+                "Results written"
+            );
+        }
+
+        if let Some(groups) = context.groups {
+            let destination = context.companion(&main, "groups", "json");
+            let destination = context
+                .write_output(&destination, "json", None, true, |w| {
+                    store_groups_as_json(self, snapshot, groups, &context.formatting, w)
+                })
+                .map_err(|e| eyre::eyre!("Failed to store group summary as JSON: {}", e))?;
+            info!(path = %destination, "Group summary written");
+        }
+
+        if let Some(stats) = &snapshot.stats {
+            let destination = context.companion(&main, "stats", "json");
+            let destination = context
+                .write_output(&destination, "json", None, true, |w| {
+                    store_stats_as_json(self, stats, &context.formatting, w)
+                })
+                .map_err(|e| eyre::eyre!("Failed to store token stats as JSON: {}", e))?;
+            info!(path = %destination, "Token stats written");
+        }
+
+        if let Some(valuation) = &snapshot.valuation {
+            let destination = context.companion(&main, "portfolio", "json");
+            let destination = context
+                .write_output(&destination, "json", None, true, |w| {
+                    store_portfolio_as_json(self, valuation, &context.formatting, w)
+                })
+                .map_err(|e| eyre::eyre!("Failed to store portfolio values as JSON: {}", e))?;
+            info!(path = %destination, "Portfolio values written");
+        }
+
+        Ok(())
+    }
+}
+
+/// Store the token map as a JSON file, sorted by address in either layout
+fn store_map_as_json(
+    sink: &JsonSink,
+    snapshot: &BalanceSnapshot,
+    tokens: &[&Felt],
+    formatting: &Formatting,
+    writer: &mut dyn Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if sink.by_account {
+        let accounts = balances_by_account(snapshot, tokens);
+        sink.to_writer(
+            writer,
+            &ByAccount {
+                snapshot,
+                accounts: &accounts,
+                formatting,
+            },
+        )?;
+    } else {
+        sink.to_writer(
+            writer,
+            &SortedBalances {
+                snapshot,
+                tokens,
+                formatting,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Serializes `token -> account -> balance` of the sorted tokens in address order,
+/// straight from the snapshot
+struct SortedBalances<'a> {
+    snapshot: &'a BalanceSnapshot,
+    tokens: &'a [&'a Felt],
+    formatting: &'a Formatting,
+}
+
+impl Serialize for SortedBalances<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.tokens.iter().map(|token| {
+            let accounts = SortedAccounts {
+                snapshot: self.snapshot,
+                token,
+                formatting: self.formatting,
+            };
+            (self.formatting.address(token), accounts)
+        }))
+    }
+}
+
+struct SortedAccounts<'a> {
+    snapshot: &'a BalanceSnapshot,
+    token: &'a Felt,
+    formatting: &'a Formatting,
+}
+
+impl Serialize for SortedAccounts<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut accounts: Vec<(&Felt, &Felt)> = self.snapshot.balances[self.token].iter().collect();
+        accounts.sort_by_key(|(account, _)| *account);
+        serializer.collect_map(accounts.into_iter().map(|(account, balance)| {
+            let entry = BalanceEntry {
+                snapshot: self.snapshot,
+                token: self.token,
+                account,
+                balance,
+                formatting: self.formatting,
+            };
+            (self.formatting.address(account), entry)
+        }))
+    }
+}
+
+/// Serializes `account -> token -> balance`, both in address order
+struct ByAccount<'a> {
+    snapshot: &'a BalanceSnapshot,
+    accounts: &'a BTreeMap<&'a Felt, BTreeMap<&'a Felt, &'a Felt>>,
+    formatting: &'a Formatting,
+}
+
+impl Serialize for ByAccount<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.accounts.iter().map(|(account, tokens)| {
+            let tokens = AccountTokens {
+                snapshot: self.snapshot,
+                account,
+                tokens,
+                formatting: self.formatting,
+            };
+            (self.formatting.address(account), tokens)
+        }))
+    }
+}
+
+struct AccountTokens<'a> {
+    snapshot: &'a BalanceSnapshot,
+    account: &'a Felt,
+    tokens: &'a BTreeMap<&'a Felt, &'a Felt>,
+    formatting: &'a Formatting,
+}
+
+impl Serialize for AccountTokens<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.tokens.iter().map(|(token, balance)| {
+            let entry = BalanceEntry {
+                snapshot: self.snapshot,
+                token,
+                account: self.account,
+                balance,
+                formatting: self.formatting,
+            };
+            (self.formatting.address(token), entry)
+        }))
+    }
+}
+
+/// A single balance, the bare amount or, with a valuation or labels, an object with its
+/// `value`, `label` and `group`, each left out when empty
+struct BalanceEntry<'a> {
+    snapshot: &'a BalanceSnapshot,
+    token: &'a Felt,
+    account: &'a Felt,
+    balance: &'a Felt,
+    formatting: &'a Formatting,
+}
+
+impl Serialize for BalanceEntry<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let balance = self
+            .formatting
+            .balance(self.balance, self.snapshot.decimals_of(self.token));
+        if self.snapshot.valuation.is_none() && self.snapshot.labels.is_empty() {
+            return serializer.serialize_str(&balance);
+        }
+        let mut entry = serializer.serialize_map(None)?;
+        entry.serialize_entry("balance", &balance)?;
+        if let Some(value) = value_column(self.snapshot, self.token, self.account) {
+            entry.serialize_entry("value", &value)?;
+        }
+        let (label, group) = label_columns(self.snapshot, self.account);
+        if !label.is_empty() {
+            entry.serialize_entry("label", &label)?;
+        }
+        if !group.is_empty() {
+            entry.serialize_entry("group", &group)?;
+        }
+        entry.end()
+    }
+}
+
+/// Store the per-group totals as a JSON file
+fn store_groups_as_json(
+    sink: &JsonSink,
+    snapshot: &BalanceSnapshot,
+    groups: &BTreeMap<String, GroupSummary>,
+    formatting: &Formatting,
+    writer: &mut dyn Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let groups: BTreeMap<&String, serde_json::Value> = groups
+        .iter()
+        .map(|(group, summary)| {
+            let accounts: Vec<String> = summary
+                .accounts
+                .iter()
+                .map(|account| formatting.address(account))
+                .collect();
+            let totals: serde_json::Map<String, serde_json::Value> = summary
+                .totals
+                .iter()
+                .map(|(token, total)| {
+                    let total = formatting.total(total, snapshot.decimals_of(token));
+                    (formatting.address(token), total.into())
+                })
+                .collect();
+            (
+                group,
+                serde_json::json!({ "accounts": accounts, "totals": totals }),
+            )
+        })
+        .collect();
+
+    sink.to_writer(writer, &groups)?;
+    Ok(())
+}
+
+/// Store the holder statistics per token as a JSON file
+fn store_stats_as_json(
+    sink: &JsonSink,
+    stats: &BTreeMap<Felt, TokenStats>,
+    formatting: &Formatting,
+    writer: &mut dyn Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let stats: BTreeMap<String, &TokenStats> = stats
+        .iter()
+        .map(|(token, stats)| (formatting.address(token), stats))
+        .collect();
+
+    sink.to_writer(writer, &stats)?;
+    Ok(())
+}
+
+/// Store per-account, per-token and per-portfolio fiat values as a JSON file
+fn store_portfolio_as_json(
+    sink: &JsonSink,
+    valuation: &Valuation,
+    formatting: &Formatting,
+    writer: &mut dyn Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut accounts = serde_json::Map::new();
+    for (account, total) in &valuation.portfolio_totals {
+        let mut tokens = serde_json::Map::new();
+        for (token, values) in &valuation.values {
+            if let Some(value) = values.get(account) {
+                tokens.insert(formatting.address(token), format_value(value).into());
+            }
+        }
+        accounts.insert(
+            formatting.address(account),
+            serde_json::json!({ "tokens": tokens, "total": format_value(total) }),
+        );
+    }
+
+    let tokens: serde_json::Map<String, serde_json::Value> = valuation
+        .token_totals
+        .iter()
+        .map(|(token, total)| (formatting.address(token), format_value(total).into()))
+        .collect();
+
+    sink.to_writer(
+        writer,
+        &serde_json::json!({ "accounts": accounts, "tokens": tokens }),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::AccountLabel;
+    use crate::output::sorted_tokens;
+    use bigdecimal::BigDecimal;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_balance_entries() -> Result<(), Box<dyn std::error::Error>> {
+        let (token, unpriced, account) = (Felt::from(1u64), Felt::from(2u64), Felt::from(10u64));
+        let mut snapshot = BalanceSnapshot {
+            balances: HashMap::from([
+                (token, HashMap::from([(account, Felt::from(100u64))])),
+                (unpriced, HashMap::from([(account, Felt::from(200u64))])),
+            ]),
+            ..Default::default()
+        };
+        let formatting = Formatting::default();
+        let write = |snapshot: &BalanceSnapshot, by_account| -> serde_json::Value {
+            let sink = JsonSink {
+                path: None,
+                pretty: false,
+                by_account,
+            };
+            let tokens = sorted_tokens(snapshot);
+            let mut output = Vec::new();
+            store_map_as_json(&sink, snapshot, &tokens, &formatting, &mut output).unwrap();
+            serde_json::from_slice(&output).unwrap()
+        };
+        let (token_key, unpriced_key, account_key) = (
+            formatting.address(&token),
+            formatting.address(&unpriced),
+            formatting.address(&account),
+        );
+
+        // Bare balances without a valuation
+        let json = write(&snapshot, false);
+        assert!(json[&token_key][&account_key].is_string());
+
+        snapshot.valuation = Some(Valuation {
+            values: HashMap::from([(token, HashMap::from([(account, BigDecimal::from(3))]))]),
+            ..Default::default()
+        });
+        let json = write(&snapshot, false);
+        assert_eq!(json[&token_key][&account_key]["value"], "3.00");
+        assert!(json[&unpriced_key][&account_key]["balance"].is_string());
+        assert!(json[&unpriced_key][&account_key].get("value").is_none());
+
+        let json = write(&snapshot, true);
+        assert_eq!(json[&account_key][&token_key]["value"], "3.00");
+
+        // Labels alone also turn balances into objects
+        snapshot.valuation = None;
+        snapshot.labels = HashMap::from([(
+            account,
+            AccountLabel {
+                label: Some("treasury".to_string()),
+                group: None,
+            },
+        )]);
+        let json = write(&snapshot, false);
+        assert_eq!(json[&token_key][&account_key]["label"], "treasury");
+        assert!(json[&token_key][&account_key].get("group").is_none());
+        assert!(json[&token_key][&account_key].get("value").is_none());
+        Ok(())
+    }
+}
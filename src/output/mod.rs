@@ -0,0 +1,931 @@
+use eyre::Result;
+use starknet::core::types::Felt;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::{info, warn};
+
+use crate::balance::BalanceSnapshot;
+use crate::destination::Destination;
+use crate::groups::{aggregate_by_group, GroupSummary};
+use crate::valuation::format_value;
+
+mod columnar_sink;
+pub use columnar_sink::{ColumnarFormat, ColumnarSink};
+
+mod csv_sink;
+pub use csv_sink::CsvSink;
+
+mod files;
+pub use files::{write_compressed, Compression, HashingWriter, Manifest, ManifestEntry};
+
+mod format;
+pub use format::{AddressFormat, AmountFormat, Formatting, Rounding};
+
+mod json_sink;
+pub use json_sink::JsonSink;
+
+mod ndjson_sink;
+pub use ndjson_sink::NdjsonSink;
+
+mod sqlite_sink;
+pub use sqlite_sink::{archive_path, archived_runs, delete_runs, SqliteSink};
+
+mod xlsx_sink;
+pub use xlsx_sink::XlsxSink;
+
+/// Number of records formatted at once, which bounds the memory used by the writers
+pub const WRITE_CHUNK: usize = 8192;
+
+/// A single token, account and balance of the snapshot
+pub type BalanceRecord<'a> = (&'a Felt, &'a Felt, &'a Felt);
+
+/// Tokens of the snapshot in address order
+pub fn sorted_tokens(snapshot: &BalanceSnapshot) -> Vec<&Felt> {
+    let mut tokens: Vec<&Felt> = snapshot.balances.keys().collect();
+    tokens.sort();
+    tokens
+}
+
+/// Balances of `tokens` ordered by token, then account, so identical snapshots give
+/// identical outputs
+///
+/// Only the accounts of one token are sorted at a time, by reference.
+pub fn sorted_balances<'a>(
+    snapshot: &'a BalanceSnapshot,
+    tokens: Vec<&'a Felt>,
+) -> impl Iterator<Item = BalanceRecord<'a>> {
+    tokens.into_iter().flat_map(move |token| {
+        let mut accounts: Vec<(&Felt, &Felt)> = snapshot.balances[token].iter().collect();
+        accounts.sort_by_key(|(account, _)| *account);
+        accounts
+            .into_iter()
+            .map(move |(account, balance)| (token, account, balance))
+    })
+}
+
+/// Balances of `tokens` pivoted to account -> token -> balance, both in address order
+pub fn balances_by_account<'a>(
+    snapshot: &'a BalanceSnapshot,
+    tokens: &[&'a Felt],
+) -> BTreeMap<&'a Felt, BTreeMap<&'a Felt, &'a Felt>> {
+    let mut accounts: BTreeMap<&Felt, BTreeMap<&Felt, &Felt>> = BTreeMap::new();
+    for token in tokens {
+        for (account, balance) in &snapshot.balances[*token] {
+            accounts.entry(account).or_default().insert(*token, balance);
+        }
+    }
+    accounts
+}
+
+/// Suffixes of companion files and the manifest, which no partition may be named after
+const RESERVED_NAMES: [&str; 4] = ["groups", "stats", "portfolio", "manifest"];
+
+/// Symbol of every token, or `None` for tokens without one or with a symbol shared by
+/// another token, so names derived from it stay unique
+fn unique_symbols<'a>(snapshot: &'a BalanceSnapshot, tokens: &[&Felt]) -> Vec<Option<&'a str>> {
+    let symbol_of = |token: &Felt| {
+        snapshot
+            .token_info
+            .get(token)
+            .and_then(|info| info.symbol.as_deref())
+    };
+    let mut uses: HashMap<&str, usize> = HashMap::new();
+    for symbol in tokens.iter().filter_map(|token| symbol_of(token)) {
+        *uses.entry(symbol).or_default() += 1;
+    }
+    tokens
+        .iter()
+        .map(|token| symbol_of(token).filter(|symbol| uses[symbol] == 1))
+        .collect()
+}
+
+/// `tokens` with their column header in wide layouts
+///
+/// Columns are labelled by symbol, or by address for tokens without one. Symbols used by
+/// several tokens are suffixed with the address to keep the headers unique.
+pub fn token_columns<'a>(
+    snapshot: &BalanceSnapshot,
+    tokens: &[&'a Felt],
+    formatting: &Formatting,
+) -> Vec<(&'a Felt, String)> {
+    tokens
+        .iter()
+        .zip(unique_symbols(snapshot, tokens))
+        .map(|(token, unique)| {
+            let header = match (
+                unique,
+                snapshot
+                    .token_info
+                    .get(*token)
+                    .and_then(|i| i.symbol.as_deref()),
+            ) {
+                (Some(symbol), _) => symbol.to_string(),
+                (None, Some(symbol)) => format!("{symbol} ({})", formatting.address(token)),
+                (None, None) => formatting.address(token),
+            };
+            (*token, header)
+        })
+        .collect()
+}
+
+/// Tokens written to a single file
+pub struct Partition<'a> {
+    /// File name suffix of a per-token partition, `None` when all tokens share one file
+    pub name: Option<String>,
+    pub tokens: Vec<&'a Felt>,
+}
+
+impl Partition<'_> {
+    /// Token of a per-token partition
+    pub fn token(&self) -> Option<Felt> {
+        match (&self.name, self.tokens.as_slice()) {
+            (Some(_), [token]) => Some(**token),
+            _ => None,
+        }
+    }
+}
+
+/// A format the results are written to, e.g. CSV files or a database
+pub trait OutputSink {
+    /// Write the snapshot, and any companion outputs the sink supports
+    fn write(&mut self, snapshot: &BalanceSnapshot, context: &OutputContext) -> Result<()>;
+}
+
+/// Settings shared by every sink of a run
+pub struct OutputContext<'a> {
+    /// Directory of the outputs without an explicit path
+    pub output_dir: &'a Path,
+    /// Replace existing output files instead of failing
+    pub force: bool,
+    /// SHA-256 of the input file
+    pub input_hash: Option<&'a str>,
+    /// Per-group totals, if a group summary was requested
+    pub groups: Option<&'a BTreeMap<String, GroupSummary>>,
+    /// Split the balances of text outputs into one file per token
+    pub partition_by_token: bool,
+    /// Compression of text outputs
+    pub compression: Option<Compression>,
+    /// Formatting of amounts and addresses
+    pub formatting: Formatting,
+    /// Files written so far
+    pub manifest: RefCell<Vec<ManifestEntry>>,
+}
+
+impl<'a> OutputContext<'a> {
+    pub fn new(output_dir: &'a Path) -> Self {
+        Self {
+            output_dir,
+            force: false,
+            input_hash: None,
+            groups: None,
+            partition_by_token: false,
+            compression: None,
+            formatting: Formatting::default(),
+            manifest: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Files the balances of a text output are split into
+    ///
+    /// Per-token files are named by the token symbol, made safe for file names. Tokens
+    /// whose name would be shared with another token, e.g. `USDC.e` and `USDC_e`, or
+    /// taken by a companion file or the manifest are named by their address instead.
+    pub fn partitions<'s>(&self, snapshot: &'s BalanceSnapshot) -> Vec<Partition<'s>> {
+        let tokens = sorted_tokens(snapshot);
+        if !self.partition_by_token {
+            return vec![Partition { name: None, tokens }];
+        }
+        let names: Vec<Option<String>> = tokens
+            .iter()
+            .map(|token| {
+                let symbol = snapshot.token_info.get(*token)?.symbol.as_deref()?;
+                let name: String = symbol
+                    .chars()
+                    .map(|c| {
+                        if c.is_ascii_alphanumeric() || c == '-' {
+                            c
+                        } else {
+                            '_'
+                        }
+                    })
+                    .collect();
+                let reserved = RESERVED_NAMES.contains(&name.to_ascii_lowercase().as_str())
+                    || name.is_empty()
+                    || name.starts_with("0x");
+                (!reserved).then_some(name)
+            })
+            .collect();
+        // Counted case-insensitively, as some file systems are
+        let mut uses: HashMap<String, usize> = HashMap::new();
+        for name in names.iter().flatten() {
+            *uses.entry(name.to_ascii_lowercase()).or_default() += 1;
+        }
+        tokens
+            .into_iter()
+            .zip(names)
+            .map(|(token, name)| {
+                let name = name
+                    .filter(|name| uses[&name.to_ascii_lowercase()] == 1)
+                    .unwrap_or_else(|| format!("{token:#064x}"));
+                Partition {
+                    name: Some(name),
+                    tokens: vec![token],
+                }
+            })
+            .collect()
+    }
+
+    /// Destination of a partition of the output at `main`
+    pub fn partition_destination(
+        &self,
+        main: &Destination,
+        partition: &Partition,
+        extension: &str,
+    ) -> Destination {
+        match &partition.name {
+            Some(name) => self.companion(main, name, extension),
+            None => main.clone(),
+        }
+    }
+
+    /// Write an output file and record it in the manifest
+    ///
+    /// Text outputs are `compressible` and get the compression extension appended, formats
+    /// with their own compression are written as is. Returns the final destination.
+    pub fn write_output<F>(
+        &self,
+        destination: &Destination,
+        format: &str,
+        token: Option<Felt>,
+        compressible: bool,
+        write: F,
+    ) -> Result<Destination>
+    where
+        F: FnOnce(&mut (dyn Write + Send)) -> Result<(), Box<dyn std::error::Error>>,
+    {
+        let compression = self.compression.filter(|_| compressible);
+        let destination = self.compressed(destination, compressible);
+
+        let mut digest = None;
+        destination.write_with(self.force, |w| {
+            let mut hashing = HashingWriter::new(w);
+            write_compressed(compression, &mut hashing, write)?;
+            digest = Some(hashing.finish());
+            Ok(())
+        })?;
+
+        if let (Destination::File(path), Some((bytes, sha256))) = (&destination, digest) {
+            self.manifest.borrow_mut().push(ManifestEntry {
+                path: path.clone(),
+                format: format.to_string(),
+                token,
+                compression,
+                bytes,
+                sha256,
+            });
+        }
+        Ok(destination)
+    }
+
+    /// `destination` with the compression extension of `compressible` outputs
+    fn compressed(&self, destination: &Destination, compressible: bool) -> Destination {
+        match self.compression.filter(|_| compressible) {
+            Some(compression) => destination.appending_extension(compression.extension()),
+            None => destination.clone(),
+        }
+    }
+
+    /// Fail before anything is written if one of the files of a sink would be refused
+    ///
+    /// Sinks writing several files check all of them up front, so an existing companion
+    /// doesn't leave a partial set of outputs behind.
+    pub fn check_destinations(
+        &self,
+        destinations: &[Destination],
+        compressible: bool,
+    ) -> Result<()> {
+        if self.force {
+            return Ok(());
+        }
+        for destination in destinations {
+            if let Destination::File(path) = self.compressed(destination, compressible) {
+                if path.exists() {
+                    eyre::bail!(
+                        "'{}' already exists, use --force to overwrite it",
+                        path.display()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Explicit path of a sink, or `default_name` inside the output directory
+    pub fn destination(&self, path: Option<&Destination>, default_name: &str) -> Destination {
+        path.cloned()
+            .unwrap_or_else(|| Destination::File(self.output_dir.join(default_name)))
+    }
+
+    /// Companion output of `main`, e.g. `token_map_groups.csv` next to `token_map.csv`
+    pub fn companion(&self, main: &Destination, suffix: &str, extension: &str) -> Destination {
+        main.companion(
+            suffix,
+            self.output_dir,
+            &format!("token_map_{suffix}.{extension}"),
+        )
+    }
+}
+
+/// `key=value` options of a single sink
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SinkOptions(BTreeMap<String, String>);
+
+impl SinkOptions {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.0.insert(key.to_string(), value.to_string());
+    }
+
+    /// The `path` option, where `-` stands for stdout
+    pub fn destination(&self) -> Option<Destination> {
+        self.get("path")
+            .map(|path| path.parse().expect("parsing a destination never fails"))
+    }
+
+    /// A boolean option, `default` when not given
+    pub fn flag(&self, key: &str, default: bool) -> Result<bool> {
+        match self.get(key) {
+            None => Ok(default),
+            Some("true") => Ok(true),
+            Some("false") => Ok(false),
+            Some(value) => Err(eyre::eyre!(
+                "Option '{}' must be true or false, got '{}'",
+                key,
+                value
+            )),
+        }
+    }
+
+    /// Fail on options a sink doesn't know, to catch typos
+    pub fn check_known(&self, sink: &str, known: &[&str]) -> Result<()> {
+        match self.0.keys().find(|key| !known.contains(&key.as_str())) {
+            Some(key) => Err(eyre::eyre!(
+                "Unknown option '{}' for the {} output, expected one of: {}",
+                key,
+                sink,
+                known.join(", ")
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A sink selected on the command line, `<name>` or `<name>:<key>=<value>,...`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SinkSpec {
+    pub name: String,
+    pub options: SinkOptions,
+}
+
+impl SinkSpec {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            options: SinkOptions::default(),
+        }
+    }
+
+    /// Set the `path` option, if given
+    pub fn with_path(mut self, path: Option<&str>) -> Self {
+        if let Some(path) = path {
+            self.options.insert("path", path);
+        }
+        self
+    }
+}
+
+impl FromStr for SinkSpec {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, options) = match value.split_once(':') {
+            Some((name, options)) => (name, Some(options)),
+            None => (value, None),
+        };
+        if name.is_empty() {
+            return Err(format!("missing output name in '{value}'"));
+        }
+
+        let mut spec = SinkSpec::new(name);
+        for option in options.into_iter().flat_map(|o| o.split(',')) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| format!("expected <key>=<value>, got '{option}'"))?;
+            spec.options.insert(key, value);
+        }
+        Ok(spec)
+    }
+}
+
+/// Creates a sink from its options
+pub type SinkFactory = Box<dyn Fn(&SinkOptions) -> Result<Box<dyn OutputSink>>>;
+
+/// Sinks available by name
+///
+/// Own sinks are added with [`SinkRegistry::register`] next to the built-in ones.
+pub struct SinkRegistry {
+    factories: BTreeMap<String, SinkFactory>,
+}
+
+impl SinkRegistry {
+    /// A registry without any sink
+    pub fn empty() -> Self {
+        Self {
+            factories: BTreeMap::new(),
+        }
+    }
+
+    /// A registry with the built-in `csv`, `json`, `ndjson`, `sqlite`, `parquet`, `arrow`
+    /// and `xlsx` sinks
+    pub fn new() -> Self {
+        let mut registry = Self::empty();
+        registry.register("csv", |options| {
+            Ok(Box::new(CsvSink::from_options(options)?))
+        });
+        registry.register("json", |options| {
+            Ok(Box::new(JsonSink::from_options(options)?))
+        });
+        registry.register("ndjson", |options| {
+            Ok(Box::new(NdjsonSink::from_options(options)?))
+        });
+        registry.register("sqlite", |options| {
+            Ok(Box::new(SqliteSink::from_options(options)?))
+        });
+        registry.register("parquet", |options| {
+            Ok(Box::new(ColumnarSink::from_options(
+                ColumnarFormat::Parquet,
+                options,
+            )?))
+        });
+        registry.register("xlsx", |options| {
+            Ok(Box::new(XlsxSink::from_options(options)?))
+        });
+        registry.register("arrow", |options| {
+            Ok(Box::new(ColumnarSink::from_options(
+                ColumnarFormat::Arrow,
+                options,
+            )?))
+        });
+        registry
+    }
+
+    /// Register a sink under `name`, replacing any sink of the same name
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(&SinkOptions) -> Result<Box<dyn OutputSink>> + 'static,
+    {
+        self.factories.insert(name.to_string(), Box::new(factory));
+    }
+
+    /// Create the sinks of all specs, failing early on unknown names and options
+    pub fn create(&self, specs: &[SinkSpec]) -> Result<Vec<Box<dyn OutputSink>>> {
+        specs
+            .iter()
+            .map(|spec| {
+                let factory = self.factories.get(&spec.name).ok_or_else(|| {
+                    eyre::eyre!(
+                        "Unknown output '{}', available: {}",
+                        spec.name,
+                        self.factories
+                            .keys()
+                            .cloned()
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                })?;
+                factory(&spec.options)
+            })
+            .collect()
+    }
+}
+
+impl Default for SinkRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Configuration shared by all outputs
+#[derive(Debug, Clone)]
+pub struct OutputConfig {
+    /// Also write per-group totals next to each enabled format
+    pub group_summary: bool,
+    /// Directory of the outputs without an explicit path
+    pub output_dir: PathBuf,
+    /// Replace existing output files instead of failing
+    pub force: bool,
+    /// SHA-256 of the input file, recorded with every SQLite run
+    pub input_hash: Option<String>,
+    /// Split CSV, JSON and NDJSON balances into one file per token
+    pub partition_by_token: bool,
+    /// Compress CSV, JSON and NDJSON outputs
+    pub compression: Option<Compression>,
+    /// Formatting of amounts and addresses in every output
+    pub formatting: Formatting,
+}
+
+impl OutputConfig {
+    pub fn new() -> Self {
+        Self {
+            group_summary: false,
+            output_dir: PathBuf::from("."),
+            force: false,
+            input_hash: None,
+            partition_by_token: false,
+            compression: None,
+            formatting: Formatting::default(),
+        }
+    }
+
+    /// A manifest of the written files is kept for partitioned or compressed outputs
+    fn writes_manifest(&self) -> bool {
+        self.partition_by_token || self.compression.is_some()
+    }
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Write results to all sinks, returning the files written through [`OutputContext::write_output`]
+pub fn write_results(
+    snapshot: &BalanceSnapshot,
+    config: &OutputConfig,
+    sinks: &mut [Box<dyn OutputSink>],
+) -> eyre::Result<Vec<ManifestEntry>> {
+    if sinks.is_empty() {
+        warn!(
+            "No output format selected. Use --csv, --json, --ndjson, --sqlite or --output to specify output formats."
+        );
+        return Ok(Vec::new());
+    }
+
+    // Calculate total records for performance reporting
+    let total_records: usize = snapshot.balances.values().map(|m| m.len()).sum();
+    info!(
+        records = total_records,
+        tokens = snapshot.balances.len(),
+        "Writing results"
+    );
+
+    let groups = if config.group_summary {
+        Some(aggregate_by_group(snapshot))
+    } else {
+        None
+    };
+
+    let context = OutputContext {
+        force: config.force,
+        input_hash: config.input_hash.as_deref(),
+        groups: groups.as_ref(),
+        partition_by_token: config.partition_by_token,
+        compression: config.compression,
+        formatting: config.formatting,
+        ..OutputContext::new(&config.output_dir)
+    };
+    let manifest_destination = Destination::File(config.output_dir.join("token_map_manifest.json"));
+    if config.writes_manifest() {
+        context.check_destinations(std::slice::from_ref(&manifest_destination), false)?;
+    }
+    for sink in sinks.iter_mut() {
+        sink.write(snapshot, &context)?;
+    }
+
+    let files = context.manifest.into_inner();
+    if config.writes_manifest() {
+        let manifest = Manifest {
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            block_number: snapshot.block_number,
+            input_hash: config.input_hash.as_deref(),
+            files: &files,
+        };
+        let destination = manifest_destination;
+        destination
+            .write_with(config.force, |w| {
+                Ok(serde_json::to_writer_pretty(w, &manifest)?)
+            })
+            .map_err(|e| eyre::eyre!("Failed to store manifest: {}", e))?;
+        info!(files = files.len(), path = %destination, "Manifest written");
+    }
+
+    Ok(files)
+}
+
+/// Fiat value column of a balance, if the snapshot was valued and the token has a price
+fn value_column(snapshot: &BalanceSnapshot, token: &Felt, account: &Felt) -> Option<String> {
+    snapshot
+        .valuation
+        .as_ref()
+        .and_then(|v| v.value_of(token, account))
+        .map(format_value)
+}
+
+/// Verification column of a balance, if the snapshot was verified
+fn verification_column(snapshot: &BalanceSnapshot, token: &Felt, account: &Felt) -> Option<String> {
+    snapshot
+        .verification_of(token, account)
+        .map(|status| status.to_string())
+}
+
+/// Label and group columns of an account, empty when the account has no label
+fn label_columns(snapshot: &BalanceSnapshot, account: &Felt) -> (String, String) {
+    match snapshot.label_of(account) {
+        Some(label) => (
+            label.label.clone().unwrap_or_default(),
+            label.group.clone().unwrap_or_default(),
+        ),
+        None => (String::new(), String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct CountingSink(std::rc::Rc<std::cell::Cell<usize>>);
+
+    impl OutputSink for CountingSink {
+        fn write(&mut self, snapshot: &BalanceSnapshot, _: &OutputContext) -> Result<()> {
+            self.0.set(snapshot.balances.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_outputs_are_deterministic() -> Result<()> {
+        let balances: HashMap<Felt, Felt> = (0..100u64)
+            .map(|i| (Felt::from(1000 - i), Felt::from(i)))
+            .collect();
+        let snapshot = BalanceSnapshot {
+            balances: HashMap::from([
+                (Felt::from(2u64), balances.clone()),
+                (Felt::from(1u64), balances),
+            ]),
+            ..Default::default()
+        };
+
+        let records: Vec<(Felt, Felt)> = sorted_balances(&snapshot, sorted_tokens(&snapshot))
+            .map(|(token, account, _)| (*token, *account))
+            .collect();
+        let mut expected = records.clone();
+        expected.sort();
+        assert_eq!(records, expected);
+
+        let by_account = balances_by_account(&snapshot, &sorted_tokens(&snapshot));
+        assert_eq!(by_account.len(), 100);
+        assert!(by_account.values().all(|tokens| tokens.len() == 2));
+
+        // Same snapshot contents, different HashMap iteration order
+        let rebuilt = BalanceSnapshot {
+            balances: snapshot.balances.clone().into_iter().collect(),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir()?;
+        let mut outputs = Vec::new();
+        for (index, snapshot) in [&snapshot, &rebuilt].into_iter().enumerate() {
+            let config = OutputConfig {
+                output_dir: dir.path().join(index.to_string()),
+                ..OutputConfig::new()
+            };
+            let mut sinks =
+                SinkRegistry::new().create(&["csv".parse().unwrap(), "json".parse().unwrap()])?;
+            write_results(snapshot, &config, &mut sinks)?;
+            outputs.push((
+                std::fs::read(config.output_dir.join("token_map.csv"))?,
+                std::fs::read(config.output_dir.join("token_map.json"))?,
+            ));
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_columns() {
+        let info = |symbol: &str| crate::balance::TokenInfo {
+            symbol: Some(symbol.to_string()),
+            decimals: None,
+        };
+        let snapshot = BalanceSnapshot {
+            balances: (1..=4u64)
+                .map(|token| (Felt::from(token), Default::default()))
+                .collect(),
+            token_info: HashMap::from([
+                (Felt::from(1u64), info("ETH")),
+                (Felt::from(2u64), info("USDC")),
+                (Felt::from(3u64), info("USDC")),
+            ]),
+            ..Default::default()
+        };
+
+        let headers: Vec<String> =
+            token_columns(&snapshot, &sorted_tokens(&snapshot), &Formatting::default())
+                .into_iter()
+                .map(|(_, header)| header)
+                .collect();
+        assert_eq!(
+            headers,
+            vec![
+                "ETH".to_string(),
+                format!("USDC ({:#064x})", Felt::from(2u64)),
+                format!("USDC ({:#064x})", Felt::from(3u64)),
+                format!("{:#064x}", Felt::from(4u64)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_partitioned_compressed_outputs() -> Result<()> {
+        let snapshot = BalanceSnapshot {
+            balances: (1..=2u64)
+                .map(|token| {
+                    let balances = HashMap::from([(Felt::from(10u64), Felt::from(token))]);
+                    (Felt::from(token), balances)
+                })
+                .collect(),
+            token_info: HashMap::from([(
+                Felt::from(1u64),
+                crate::balance::TokenInfo {
+                    symbol: Some("ETH".to_string()),
+                    decimals: None,
+                },
+            )]),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir()?;
+        let config = OutputConfig {
+            output_dir: dir.path().to_path_buf(),
+            partition_by_token: true,
+            compression: Some(Compression::Gzip),
+            ..OutputConfig::new()
+        };
+        let mut sinks = SinkRegistry::new().create(&["csv".parse().unwrap()])?;
+        write_results(&snapshot, &config, &mut sinks)?;
+
+        let eth = dir.path().join("token_map_ETH.csv.gz");
+        let other = dir
+            .path()
+            .join(format!("token_map_{:#064x}.csv.gz", Felt::from(2u64)));
+        assert!(eth.exists() && other.exists());
+
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.path().join("token_map_manifest.json"))?)?;
+        let files = manifest["files"].as_array().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0]["path"], eth.to_str().unwrap());
+        assert_eq!(files[0]["compression"], "gzip");
+        assert_eq!(files[0]["token"], format!("{:#064x}", Felt::from(1u64)));
+        assert_eq!(
+            files[0]["sha256"],
+            hex::encode(<sha2::Sha256 as sha2::Digest>::digest(std::fs::read(&eth)?))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_partition_names() {
+        let info = |symbol: &str| crate::balance::TokenInfo {
+            symbol: Some(symbol.to_string()),
+            decimals: None,
+        };
+        let snapshot = BalanceSnapshot {
+            balances: (1..=6u64)
+                .map(|token| (Felt::from(token), Default::default()))
+                .collect(),
+            token_info: HashMap::from([
+                (Felt::from(1u64), info("ETH")),
+                (Felt::from(2u64), info("USDC.e")),
+                (Felt::from(3u64), info("USDC_e")),
+                (Felt::from(4u64), info("manifest")),
+                (Felt::from(5u64), info("Stats")),
+            ]),
+            ..Default::default()
+        };
+        let context = OutputContext {
+            partition_by_token: true,
+            ..OutputContext::new(Path::new("."))
+        };
+
+        let names: Vec<String> = context
+            .partitions(&snapshot)
+            .into_iter()
+            .map(|partition| partition.name.unwrap())
+            .collect();
+        let address = |token: u64| format!("{:#064x}", Felt::from(token));
+        assert_eq!(
+            names,
+            vec![
+                "ETH".to_string(),
+                address(2),
+                address(3),
+                address(4),
+                address(5),
+                address(6),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_existing_companion_is_refused_before_writing() -> Result<()> {
+        let snapshot = BalanceSnapshot {
+            balances: HashMap::from([(
+                Felt::from(1u64),
+                HashMap::from([(Felt::from(10u64), Felt::from(5u64))]),
+            )]),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("token_map_groups.csv"), "previous")?;
+        let config = OutputConfig {
+            output_dir: dir.path().to_path_buf(),
+            group_summary: true,
+            ..OutputConfig::new()
+        };
+
+        let mut sinks = SinkRegistry::new().create(&["csv".parse().unwrap()])?;
+        assert!(write_results(&snapshot, &config, &mut sinks).is_err());
+        assert!(!dir.path().join("token_map.csv").exists());
+
+        let config = OutputConfig {
+            force: true,
+            ..config
+        };
+        write_results(&snapshot, &config, &mut sinks)?;
+        assert!(dir.path().join("token_map.csv").exists());
+
+        // A later partition that exists stops the run before the first one is written
+        let snapshot = BalanceSnapshot {
+            balances: HashMap::from([
+                (Felt::from(1u64), Default::default()),
+                (Felt::from(2u64), Default::default()),
+            ]),
+            ..Default::default()
+        };
+        let second = format!("token_map_{:#064x}.ndjson", Felt::from(2u64));
+        std::fs::write(dir.path().join(&second), "previous")?;
+        let config = OutputConfig {
+            force: false,
+            partition_by_token: true,
+            ..config
+        };
+        let mut sinks = SinkRegistry::new().create(&["ndjson".parse().unwrap()])?;
+        assert!(write_results(&snapshot, &config, &mut sinks).is_err());
+        let first = format!("token_map_{:#064x}.ndjson", Felt::from(1u64));
+        assert!(!dir.path().join(first).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_sink_spec() {
+        let spec: SinkSpec = "csv:path=out/week.csv,delimiter=;".parse().unwrap();
+        assert_eq!(spec.name, "csv");
+        assert_eq!(spec.options.get("path"), Some("out/week.csv"));
+        assert_eq!(spec.options.get("delimiter"), Some(";"));
+        assert_eq!("json".parse::<SinkSpec>().unwrap(), SinkSpec::new("json"));
+        assert!("csv:path".parse::<SinkSpec>().is_err());
+    }
+
+    #[test]
+    fn test_registry() -> Result<()> {
+        let written = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut registry = SinkRegistry::new();
+        let counter = written.clone();
+        registry.register("count", move |_| {
+            Ok(Box::new(CountingSink(counter.clone())))
+        });
+
+        let mut sinks = registry.create(&["count".parse().unwrap()])?;
+        let snapshot = BalanceSnapshot {
+            balances: HashMap::from([(Felt::ONE, Default::default())]),
+            ..Default::default()
+        };
+        write_results(&snapshot, &OutputConfig::new(), &mut sinks)?;
+        assert_eq!(written.get(), 1);
+
+        assert!(registry.create(&["yaml".parse().unwrap()]).is_err());
+        assert!(registry
+            .create(&["csv:delimter=;".parse().unwrap()])
+            .is_err());
+        Ok(())
+    }
+}
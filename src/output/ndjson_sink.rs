@@ -0,0 +1,148 @@
+use eyre::Result;
+use serde::Serialize;
+use starknet::core::types::Felt;
+use std::io::Write;
+use tracing::info;
+
+use super::{
+    label_columns, sorted_balances, value_column, verification_column, Formatting, OutputContext,
+    OutputSink, SinkOptions,
+};
+use crate::balance::BalanceSnapshot;
+use crate::destination::Destination;
+
+/// Writes one JSON object per balance and line, for `jq` and other line-based tools
+///
+/// Options: `path` (`-` for stdout).
+pub struct NdjsonSink {
+    path: Option<Destination>,
+}
+
+impl NdjsonSink {
+    pub fn from_options(options: &SinkOptions) -> Result<Self> {
+        options.check_known("ndjson", &["path"])?;
+        Ok(Self {
+            path: options.destination(),
+        })
+    }
+}
+
+/// A single line, metadata fields are left out when empty
+#[derive(Serialize)]
+struct NdjsonRecord {
+    token: String,
+    account: String,
+    balance: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    label: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    group: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verified: Option<String>,
+}
+
+impl OutputSink for NdjsonSink {
+    fn write(&mut self, snapshot: &BalanceSnapshot, context: &OutputContext) -> Result<()> {
+        let main = context.destination(self.path.as_ref(), "token_map.ndjson");
+        let partitions = context.partitions(snapshot);
+
+        // Every partition is checked before the first one is written
+        let destinations: Vec<Destination> = partitions
+            .iter()
+            .map(|partition| context.partition_destination(&main, partition, "ndjson"))
+            .collect();
+        context.check_destinations(&destinations, true)?;
+
+        for partition in partitions {
+            let destination = context.partition_destination(&main, &partition, "ndjson");
+            let start = std::time::SystemTime::now();
+            let destination = context
+                .write_output(&destination, "ndjson", partition.token(), true, |w| {
+                    store_map_as_ndjson(snapshot, partition.tokens.clone(), &context.formatting, w)
+                })
+                .map_err(|e| eyre::eyre!("Failed to store map as NDJSON: {}", e))?;
+            let time = std::time::SystemTime::now().duration_since(start).unwrap();
+            info!(
+                path = %destination,
+                elapsed_ms = time.as_millis() as u64,
+                "Results written"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Stream the token map as NDJSON, sorted by token and account
+fn store_map_as_ndjson<'a>(
+    snapshot: &'a BalanceSnapshot,
+    tokens: Vec<&'a Felt>,
+    formatting: &Formatting,
+    writer: &mut dyn Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (token, account, balance) in sorted_balances(snapshot, tokens) {
+        let (label, group) = label_columns(snapshot, account);
+        let record = NdjsonRecord {
+            token: formatting.address(token),
+            account: formatting.address(account),
+            balance: formatting.balance(balance, snapshot.decimals_of(token)),
+            label,
+            group,
+            value: value_column(snapshot, token, account),
+            verified: verification_column(snapshot, token, account),
+        };
+        serde_json::to_writer(&mut *writer, &record)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::AccountLabel;
+    use crate::output::sorted_tokens;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_one_object_per_line() -> Result<(), Box<dyn std::error::Error>> {
+        let snapshot = BalanceSnapshot {
+            balances: HashMap::from([(
+                Felt::from(1u64),
+                HashMap::from([
+                    (Felt::from(11u64), Felt::from(200u64)),
+                    (Felt::from(10u64), Felt::from(100u64)),
+                ]),
+            )]),
+            labels: HashMap::from([(
+                Felt::from(11u64),
+                AccountLabel {
+                    label: Some("treasury".to_string()),
+                    group: None,
+                },
+            )]),
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        let formatting = Formatting::default();
+        store_map_as_ndjson(
+            &snapshot,
+            sorted_tokens(&snapshot),
+            &formatting,
+            &mut output,
+        )?;
+        let lines: Vec<serde_json::Value> = String::from_utf8(output)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["balance"], "100");
+        assert!(lines[0].get("label").is_none());
+        assert_eq!(lines[1]["label"], "treasury");
+        assert_eq!(lines[1]["account"], format!("{:#064x}", Felt::from(11u64)));
+        Ok(())
+    }
+}
@@ -0,0 +1,558 @@
+use eyre::Result;
+use rusqlite::{Connection, Transaction};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+use super::{
+    sorted_balances, sorted_tokens, value_column, Formatting, OutputContext, OutputSink,
+    SinkOptions, SinkSpec,
+};
+use crate::balance::BalanceSnapshot;
+use crate::destination::Destination;
+use crate::groups::GroupSummary;
+use crate::proof::VerificationStatus;
+use crate::valuation::format_value;
+
+/// Adds every run to the `token_map.db` snapshot archive
+///
+/// Balances are kept as exact blobs, next to the `amount` in the `--amount-format`
+/// recorded with the run. Options: `path`, which can't be stdout.
+pub struct SqliteSink {
+    path: Option<PathBuf>,
+}
+
+impl SqliteSink {
+    pub fn from_options(options: &SinkOptions) -> Result<Self> {
+        options.check_known("sqlite", &["path"])?;
+        let path = match options.destination() {
+            None => None,
+            Some(Destination::File(path)) => Some(path),
+            Some(Destination::Stdout) => {
+                return Err(eyre::eyre!("SQLite output can't be written to stdout"))
+            }
+        };
+        Ok(Self { path })
+    }
+}
+
+impl OutputSink for SqliteSink {
+    fn write(&mut self, snapshot: &BalanceSnapshot, context: &OutputContext) -> Result<()> {
+        // Every run is added to the archive inside a single transaction, so an
+        // interrupted run leaves the database as it was and nothing is overwritten
+        let path = self
+            .path
+            .clone()
+            .unwrap_or_else(|| context.output_dir.join("token_map.db"));
+
+        let sqlite_start = std::time::SystemTime::now();
+        let run_id = store_map_in_sqlite(
+            snapshot,
+            context.groups,
+            &path,
+            context.input_hash,
+            &context.formatting,
+        )?;
+        let sqlite_end = std::time::SystemTime::now();
+        let sqlite_time = sqlite_end.duration_since(sqlite_start).unwrap();
+        info!(
+            run_id,
+            path = %path.display(),
+            elapsed_ms = sqlite_time.as_millis() as u64,
+            "Run written"
+        );
+        Ok(())
+    }
+}
+
+/// Path of the archive written by the `sqlite` output among `specs`, if there is one
+pub fn archive_path(specs: &[SinkSpec], output_dir: &Path) -> Option<PathBuf> {
+    let spec = specs.iter().find(|spec| spec.name == "sqlite")?;
+    match spec.options.destination() {
+        Some(Destination::File(path)) => Some(path),
+        _ => Some(output_dir.join("token_map.db")),
+    }
+}
+
+/// Id and creation time of every run of the archive, oldest first
+pub fn archived_runs(path: &Path) -> Result<Vec<(i64, i64)>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut conn =
+        Connection::open(path).map_err(|e| eyre::eyre!("Failed to open SQLite database: {}", e))?;
+    migrate(&mut conn)?;
+    let mut stmt = conn
+        .prepare("SELECT run_id, created_at FROM runs ORDER BY run_id")
+        .map_err(|e| eyre::eyre!("Failed to prepare runs query: {}", e))?;
+    let runs = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .and_then(|rows| rows.collect())
+        .map_err(|e| eyre::eyre!("Failed to query runs: {}", e));
+    runs
+}
+
+/// Delete runs and all of their rows from the archive, in a single transaction
+pub fn delete_runs(path: &Path, run_ids: &[i64]) -> Result<()> {
+    let mut conn =
+        Connection::open(path).map_err(|e| eyre::eyre!("Failed to open SQLite database: {}", e))?;
+    conn.pragma_update(None, "foreign_keys", true)
+        .map_err(|e| eyre::eyre!("Failed to enable foreign keys: {}", e))?;
+    let tx = conn
+        .transaction()
+        .map_err(|e| eyre::eyre!("Failed to begin transaction: {}", e))?;
+    for run_id in run_ids {
+        tx.execute("DELETE FROM runs WHERE run_id = ?1", [run_id])
+            .map_err(|e| eyre::eyre!("Failed to delete run {}: {}", run_id, e))?;
+    }
+    tx.commit()
+        .map_err(|e| eyre::eyre!("Failed to commit transaction: {}", e))
+}
+
+/// Schema migrations of the SQLite output, `PRAGMA user_version` holds the number applied
+const MIGRATIONS: &[fn(&Transaction) -> rusqlite::Result<()>] =
+    &[migrate_to_run_archive, migrate_to_formatted_amounts];
+
+/// Schema version written by this build
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Tables of the pre-versioned schema, which only ever appended rows
+const LEGACY_TABLES: [&str; 4] = [
+    "token_map",
+    "group_totals",
+    "token_stats",
+    "portfolio_values",
+];
+
+/// Version 1: one row in `runs` per invocation, all other rows keyed by run
+///
+/// Tables of the append-only layout are kept as `legacy_<table>`.
+fn migrate_to_run_archive(tx: &Transaction) -> rusqlite::Result<()> {
+    for table in LEGACY_TABLES {
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+            [table],
+            |row| row.get(0),
+        )?;
+        if exists {
+            tx.execute_batch(&format!("ALTER TABLE {table} RENAME TO legacy_{table}"))?;
+        }
+    }
+
+    tx.execute_batch(
+        "CREATE TABLE runs (
+            run_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            head_block INTEGER,
+            created_at INTEGER NOT NULL,
+            input_hash TEXT
+        );
+        CREATE INDEX runs_head_block ON runs (head_block);
+
+        CREATE TABLE token_map (
+            run_id INTEGER NOT NULL REFERENCES runs (run_id) ON DELETE CASCADE,
+            token BLOB NOT NULL,
+            account BLOB NOT NULL,
+            balance BLOB NOT NULL,
+            label TEXT,
+            account_group TEXT,
+            value TEXT,
+            verified INTEGER,
+            verification_error TEXT,
+            PRIMARY KEY (run_id, token, account)
+        ) WITHOUT ROWID;
+        CREATE INDEX token_map_account ON token_map (account, token);
+
+        CREATE TABLE group_totals (
+            run_id INTEGER NOT NULL REFERENCES runs (run_id) ON DELETE CASCADE,
+            account_group TEXT NOT NULL,
+            token BLOB NOT NULL,
+            accounts INTEGER NOT NULL,
+            total TEXT NOT NULL,
+            PRIMARY KEY (run_id, account_group, token)
+        ) WITHOUT ROWID;
+
+        CREATE TABLE token_stats (
+            run_id INTEGER NOT NULL REFERENCES runs (run_id) ON DELETE CASCADE,
+            token BLOB NOT NULL,
+            holders INTEGER NOT NULL,
+            sum TEXT NOT NULL,
+            mean TEXT NOT NULL,
+            median TEXT NOT NULL,
+            percentiles TEXT NOT NULL,
+            top10_share REAL NOT NULL,
+            top100_share REAL NOT NULL,
+            gini REAL NOT NULL,
+            nakamoto INTEGER NOT NULL,
+            PRIMARY KEY (run_id, token)
+        ) WITHOUT ROWID;
+
+        CREATE TABLE portfolio_values (
+            run_id INTEGER NOT NULL REFERENCES runs (run_id) ON DELETE CASCADE,
+            account BLOB NOT NULL,
+            total_value TEXT NOT NULL,
+            PRIMARY KEY (run_id, account)
+        ) WITHOUT ROWID;",
+    )
+}
+
+/// Version 2: formatted amounts next to the balance blobs, with the format of each run
+///
+/// Runs of version 1 have no format recorded, their group totals are raw integers.
+fn migrate_to_formatted_amounts(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE runs ADD COLUMN amount_format TEXT;
+        ALTER TABLE runs ADD COLUMN amount_precision INTEGER;
+        ALTER TABLE runs ADD COLUMN amount_rounding TEXT;
+        ALTER TABLE token_map ADD COLUMN amount TEXT;",
+    )
+}
+
+/// Bring the output database up to [`SCHEMA_VERSION`], one transaction per migration
+fn migrate(conn: &mut Connection) -> eyre::Result<()> {
+    let version: i64 = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(|e| eyre::eyre!("Failed to read schema version: {}", e))?;
+    let version = version as usize;
+    if version > SCHEMA_VERSION {
+        return Err(eyre::eyre!(
+            "Output database has schema version {}, this build only supports up to {}",
+            version,
+            SCHEMA_VERSION
+        ));
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn
+            .transaction()
+            .map_err(|e| eyre::eyre!("Failed to begin migration: {}", e))?;
+        migration(&tx)
+            .map_err(|e| eyre::eyre!("Failed to migrate to version {}: {}", index + 1, e))?;
+        tx.pragma_update(None, "user_version", (index + 1) as i64)
+            .map_err(|e| eyre::eyre!("Failed to update schema version: {}", e))?;
+        tx.commit()
+            .map_err(|e| eyre::eyre!("Failed to commit migration: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Store the snapshot as a new run in the SQLite archive and return its run id
+fn store_map_in_sqlite(
+    snapshot: &BalanceSnapshot,
+    groups: Option<&BTreeMap<String, GroupSummary>>,
+    path: &Path,
+    input_hash: Option<&str>,
+    formatting: &Formatting,
+) -> eyre::Result<i64> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| eyre::eyre!("Failed to create directory '{}': {}", dir.display(), e))?;
+    }
+    let mut conn =
+        Connection::open(path).map_err(|e| eyre::eyre!("Failed to open SQLite database: {}", e))?;
+    migrate(&mut conn)?;
+
+    // The run and all of its rows are committed together
+    let tx_start = std::time::SystemTime::now();
+    let tx = conn
+        .transaction()
+        .map_err(|e| eyre::eyre!("Failed to begin transaction: {}", e))?;
+
+    let created_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    tx.execute(
+        "INSERT INTO runs (head_block, created_at, input_hash, amount_format, amount_precision,
+            amount_rounding)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        rusqlite::params![
+            snapshot.block_number.map(|n| n as i64),
+            created_at,
+            input_hash,
+            value_name(formatting.amounts),
+            formatting.precision,
+            formatting
+                .precision
+                .map(|_| value_name(formatting.rounding))
+        ],
+    )
+    .map_err(|e| eyre::eyre!("Failed to insert run: {}", e))?;
+    let run_id = tx.last_insert_rowid();
+
+    // Prepare the insertion statement once
+    let mut stmt = tx
+        .prepare(
+            "INSERT INTO token_map (run_id, token, account, balance, amount, label,
+                account_group, value, verified, verification_error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        )
+        .map_err(|e| eyre::eyre!("Failed to prepare insert statement: {}", e))?;
+
+    // Stream the records in token and account order, addresses and balances as
+    // 32-byte big-endian blobs
+    for (token, account, balance) in sorted_balances(snapshot, sorted_tokens(snapshot)) {
+        let label = snapshot.label_of(account);
+        let verification = snapshot.verification_of(token, account);
+        let verification_error = match verification {
+            Some(VerificationStatus::Failed(reason)) => Some(reason.as_str()),
+            _ => None,
+        };
+        stmt.execute(rusqlite::params![
+            run_id,
+            token.to_bytes_be(),
+            account.to_bytes_be(),
+            balance.to_bytes_be(),
+            formatting.balance(balance, snapshot.decimals_of(token)),
+            label.and_then(|l| l.label.as_deref()),
+            label.and_then(|l| l.group.as_deref()),
+            value_column(snapshot, token, account),
+            verification.map(|s| *s == VerificationStatus::Verified),
+            verification_error
+        ])
+        .map_err(|e| eyre::eyre!("Failed to insert row: {}", e))?;
+    }
+    drop(stmt);
+
+    if let Some(groups) = groups {
+        let mut stmt = tx
+            .prepare(
+                "INSERT INTO group_totals (run_id, account_group, token, accounts, total)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )
+            .map_err(|e| eyre::eyre!("Failed to prepare group insert statement: {}", e))?;
+        for (group, summary) in groups {
+            for (token, total) in &summary.totals {
+                stmt.execute(rusqlite::params![
+                    run_id,
+                    group,
+                    token.to_bytes_be(),
+                    summary.holders.get(token).copied().unwrap_or(0) as i64,
+                    formatting.total(total, snapshot.decimals_of(token))
+                ])
+                .map_err(|e| eyre::eyre!("Failed to insert group row: {}", e))?;
+            }
+        }
+    }
+
+    if let Some(stats) = &snapshot.stats {
+        let mut stmt = tx
+            .prepare(
+                "INSERT INTO token_stats (run_id, token, holders, sum, mean, median, percentiles,
+                    top10_share, top100_share, gini, nakamoto)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            )
+            .map_err(|e| eyre::eyre!("Failed to prepare stats insert statement: {}", e))?;
+        for (token, stats) in stats {
+            // Percentiles are stored as a JSON object, e.g. {"p10": "1.5", ...}
+            let percentiles: BTreeMap<String, String> = stats
+                .percentiles
+                .iter()
+                .map(|(p, value)| (format!("p{p}"), value.to_string()))
+                .collect();
+            let percentiles = serde_json::to_string(&percentiles)
+                .map_err(|e| eyre::eyre!("Failed to serialize percentiles: {}", e))?;
+            stmt.execute(rusqlite::params![
+                run_id,
+                token.to_bytes_be(),
+                stats.holders as i64,
+                stats.sum.to_string(),
+                stats.mean.to_string(),
+                stats.median.to_string(),
+                percentiles,
+                stats.top10_share,
+                stats.top100_share,
+                stats.gini,
+                stats.nakamoto as i64
+            ])
+            .map_err(|e| eyre::eyre!("Failed to insert stats row: {}", e))?;
+        }
+    }
+
+    if let Some(valuation) = &snapshot.valuation {
+        let mut stmt = tx
+            .prepare(
+                "INSERT INTO portfolio_values (run_id, account, total_value) VALUES (?1, ?2, ?3)",
+            )
+            .map_err(|e| eyre::eyre!("Failed to prepare portfolio insert statement: {}", e))?;
+        for (account, total) in &valuation.portfolio_totals {
+            stmt.execute(rusqlite::params![
+                run_id,
+                account.to_bytes_be(),
+                format_value(total)
+            ])
+            .map_err(|e| eyre::eyre!("Failed to insert portfolio row: {}", e))?;
+        }
+    }
+
+    // Commit the transaction
+    tx.commit()
+        .map_err(|e| eyre::eyre!("Failed to commit transaction: {}", e))?;
+
+    let tx_end = std::time::SystemTime::now();
+    let tx_time = tx_end.duration_since(tx_start).unwrap();
+    debug!(
+        elapsed_ms = tx_time.as_millis() as u64,
+        "Committed SQLite transaction"
+    );
+
+    Ok(run_id)
+}
+
+/// Command line name of an option value, e.g. `half-even`
+fn value_name(value: impl clap::ValueEnum) -> Option<String> {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::AmountFormat;
+    use starknet::core::types::Felt;
+    use std::collections::HashMap;
+
+    fn snapshot() -> BalanceSnapshot {
+        BalanceSnapshot {
+            balances: HashMap::from([(
+                Felt::from(1u64),
+                HashMap::from([
+                    (Felt::from(10u64), Felt::from(100u64)),
+                    (Felt::from(11u64), Felt::from(200u64)),
+                ]),
+            )]),
+            block_number: Some(42),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_runs_are_archived_separately() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("token_map.db");
+
+        let first = store_map_in_sqlite(
+            &snapshot(),
+            None,
+            &path,
+            Some("abc"),
+            &Formatting::default(),
+        )?;
+        let units = Formatting {
+            amounts: AmountFormat::Units,
+            precision: Some(20),
+            ..Default::default()
+        };
+        let second = store_map_in_sqlite(&snapshot(), None, &path, Some("abc"), &units)?;
+        assert_ne!(first, second);
+
+        let conn = Connection::open(&path)?;
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        assert_eq!(version as usize, SCHEMA_VERSION);
+
+        let rows: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM token_map WHERE run_id = ?1",
+            [second],
+            |row| row.get(0),
+        )?;
+        assert_eq!(rows, 2);
+
+        let (head_block, balance): (i64, Vec<u8>) = conn.query_row(
+            "SELECT head_block, balance FROM runs JOIN token_map USING (run_id)
+             WHERE run_id = ?1 AND account = ?2",
+            rusqlite::params![first, Felt::from(11u64).to_bytes_be()],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        assert_eq!(head_block, 42);
+        assert_eq!(Felt::from_bytes_be_slice(&balance), Felt::from(200u64));
+
+        // Each run records the format of its amounts
+        let amounts: Vec<(String, Option<i64>, Option<String>, String)> = conn
+            .prepare(
+                "SELECT amount_format, amount_precision, amount_rounding, amount
+                 FROM runs JOIN token_map USING (run_id) WHERE account = ?1 ORDER BY run_id",
+            )?
+            .query_map([Felt::from(11u64).to_bytes_be()], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(
+            amounts,
+            [
+                ("raw".to_string(), None, None, "200".to_string()),
+                (
+                    "units".to_string(),
+                    Some(20),
+                    Some("half-even".to_string()),
+                    "0.00000000000000020000".to_string()
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_runs() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("token_map.db");
+        assert!(archived_runs(&path)?.is_empty());
+
+        let first = store_map_in_sqlite(&snapshot(), None, &path, None, &Formatting::default())?;
+        let second = store_map_in_sqlite(&snapshot(), None, &path, None, &Formatting::default())?;
+        assert_eq!(
+            archived_runs(&path)?
+                .iter()
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>(),
+            [first, second]
+        );
+
+        delete_runs(&path, &[first])?;
+        assert_eq!(archived_runs(&path)?.len(), 1);
+        let conn = Connection::open(&path)?;
+        let rows: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM token_map WHERE run_id = ?1",
+            [first],
+            |row| row.get(0),
+        )?;
+        assert_eq!(rows, 0);
+
+        let specs = [SinkSpec::new("csv"), SinkSpec::new("sqlite")];
+        assert_eq!(
+            archive_path(&specs, dir.path()),
+            Some(dir.path().join("token_map.db"))
+        );
+        let specs = [SinkSpec::new("sqlite").with_path(Some("runs.db"))];
+        assert_eq!(
+            archive_path(&specs, dir.path()),
+            Some(PathBuf::from("runs.db"))
+        );
+        assert_eq!(archive_path(&[], dir.path()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_tables_are_kept() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("token_map.db");
+        {
+            let conn = Connection::open(&path)?;
+            conn.execute_batch(
+                "CREATE TABLE token_map (token TEXT NOT NULL, account TEXT NOT NULL, balance TEXT NOT NULL);
+                 INSERT INTO token_map VALUES ('0x1', '0xa', '100');",
+            )?;
+        }
+
+        store_map_in_sqlite(&snapshot(), None, &path, None, &Formatting::default())?;
+
+        let conn = Connection::open(&path)?;
+        let legacy: i64 = conn.query_row("SELECT COUNT(*) FROM legacy_token_map", [], |row| {
+            row.get(0)
+        })?;
+        assert_eq!(legacy, 1);
+        let current: i64 =
+            conn.query_row("SELECT COUNT(*) FROM token_map", [], |row| row.get(0))?;
+        assert_eq!(current, 2);
+        Ok(())
+    }
+}
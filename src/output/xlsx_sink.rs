@@ -0,0 +1,215 @@
+use bigdecimal::{BigDecimal, ToPrimitive};
+use eyre::Result;
+use rust_xlsxwriter::{Format, Workbook, Worksheet, XlsxError};
+use starknet::core::types::Felt;
+use std::str::FromStr;
+use tracing::info;
+
+use super::{
+    balances_by_account, label_columns, sorted_tokens, token_columns, Formatting, OutputContext,
+    OutputSink, SinkOptions,
+};
+use crate::balance::BalanceSnapshot;
+use crate::destination::Destination;
+use crate::valuation::format_value;
+
+/// Rows of a worksheet, including the header
+const MAX_ROWS: usize = 1_048_576;
+
+/// Columns of a worksheet
+const MAX_COLUMNS: usize = 16_384;
+
+/// Writes a spreadsheet with one row per account and one column per token
+///
+/// Balances follow the `--amount-format` and are numbers whenever a spreadsheet number
+/// holds the formatted amount without loss, so they can be summed and charted. With a valuation, the fiat value of every priced token and the total value of
+/// the account follow the balances. Options: `path` (`-` for stdout).
+pub struct XlsxSink {
+    path: Option<Destination>,
+}
+
+impl XlsxSink {
+    pub fn from_options(options: &SinkOptions) -> Result<Self> {
+        options.check_known("xlsx", &["path"])?;
+        Ok(Self {
+            path: options.destination(),
+        })
+    }
+}
+
+impl OutputSink for XlsxSink {
+    fn write(&mut self, snapshot: &BalanceSnapshot, context: &OutputContext) -> Result<()> {
+        let destination = context.destination(self.path.as_ref(), "token_map.xlsx");
+
+        let start = std::time::SystemTime::now();
+        let workbook = build_workbook(snapshot, &context.formatting)?;
+        context
+            .write_output(&destination, "xlsx", None, false, |w| {
+                Ok(w.write_all(&workbook)?)
+            })
+            .map_err(|e| eyre::eyre!("Failed to store map as XLSX: {}", e))?;
+        let time = std::time::SystemTime::now().duration_since(start).unwrap();
+        info!(
+            path = %destination,
+            elapsed_ms = time.as_millis() as u64,
+            "Results written"
+        );
+        Ok(())
+    }
+}
+
+/// Build the `Balances` worksheet and return the encoded workbook
+///
+/// Addresses and balances follow the formatting, amounts a spreadsheet number can't hold
+/// without loss are written as text.
+fn build_workbook(snapshot: &BalanceSnapshot, formatting: &Formatting) -> Result<Vec<u8>> {
+    let tokens = sorted_tokens(snapshot);
+    let accounts = balances_by_account(snapshot, &tokens);
+    let tokens = token_columns(snapshot, &tokens, formatting);
+    let valued: Vec<&(&Felt, String)> = match &snapshot.valuation {
+        Some(valuation) => tokens
+            .iter()
+            .filter(|(token, _)| valuation.values.contains_key(*token))
+            .collect(),
+        None => Vec::new(),
+    };
+    let value_columns = valued.len() + usize::from(snapshot.valuation.is_some());
+    if accounts.len() + 1 > MAX_ROWS || tokens.len() + value_columns + 3 > MAX_COLUMNS {
+        return Err(eyre::eyre!(
+            "{} accounts and {} tokens don't fit in a worksheet, use the wide CSV layout instead",
+            accounts.len(),
+            tokens.len()
+        ));
+    }
+
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet();
+    let xlsx_err = |e: rust_xlsxwriter::XlsxError| eyre::eyre!("Failed to build XLSX: {}", e);
+    sheet.set_name("Balances").map_err(xlsx_err)?;
+
+    let bold = Format::new().set_bold();
+    let headers = ["Account", "Label", "Group"]
+        .into_iter()
+        .map(str::to_string)
+        .chain(tokens.iter().map(|(_, column)| column.clone()))
+        .chain(valued.iter().map(|(_, column)| format!("{column} Value")))
+        .chain(snapshot.valuation.iter().map(|_| "Total Value".to_string()));
+    for (col, header) in headers.enumerate() {
+        sheet
+            .write_string_with_format(0, col as u16, header, &bold)
+            .map_err(xlsx_err)?;
+    }
+
+    for (index, (account, balances)) in accounts.iter().enumerate() {
+        let row = index as u32 + 1;
+        let (label, group) = label_columns(snapshot, account);
+        sheet
+            .write_string(row, 0, formatting.address(account))
+            .map_err(xlsx_err)?;
+        sheet.write_string(row, 1, label).map_err(xlsx_err)?;
+        sheet.write_string(row, 2, group).map_err(xlsx_err)?;
+        for (col, (token, _)) in tokens.iter().enumerate() {
+            if let Some(balance) = balances.get(token) {
+                let amount = formatting.balance(balance, snapshot.decimals_of(token));
+                write_amount(sheet, row, col as u16 + 3, &amount).map_err(xlsx_err)?;
+            }
+        }
+        if let Some(valuation) = &snapshot.valuation {
+            let first = tokens.len() + 3;
+            let values = valued
+                .iter()
+                .map(|(token, _)| valuation.value_of(token, account))
+                .chain([valuation.portfolio_totals.get(*account)]);
+            for (col, value) in values.enumerate() {
+                if let Some(value) = value {
+                    let value = format_value(value);
+                    write_amount(sheet, row, (first + col) as u16, &value).map_err(xlsx_err)?;
+                }
+            }
+        }
+    }
+    sheet.set_freeze_panes(1, 1).map_err(xlsx_err)?;
+
+    workbook.save_to_buffer().map_err(xlsx_err)
+}
+
+/// Write a formatted amount as a number if an `f64` reads back as the same decimal, and
+/// as text otherwise, e.g. hex amounts or balances with 18 significant decimals
+fn write_amount(sheet: &mut Worksheet, row: u32, col: u16, amount: &str) -> Result<(), XlsxError> {
+    match exact_number(amount) {
+        Some(number) => sheet.write_number(row, col, number)?,
+        None => sheet.write_string(row, col, amount)?,
+    };
+    Ok(())
+}
+
+/// The `f64` that prints as a decimal amount, if there is one
+fn exact_number(amount: &str) -> Option<f64> {
+    let decimal = BigDecimal::from_str(amount).ok()?;
+    let number = decimal.to_f64()?;
+    // Display prints the shortest decimal that reads back as the same f64
+    let exact = number.is_finite() && BigDecimal::from_str(&number.to_string()).ok()? == decimal;
+    exact.then_some(number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::valuation::Valuation;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_exact_numbers() {
+        assert_eq!(exact_number("100"), Some(100.0));
+        assert_eq!(exact_number("1.2500"), Some(1.25));
+        assert_eq!(exact_number("3.00"), Some(3.0));
+        assert_eq!(exact_number("0.1"), Some(0.1));
+        // 2^53 + 1 and an 18-decimal balance have no exact f64
+        assert_eq!(exact_number("9007199254740993"), None);
+        assert_eq!(exact_number("1.234567890123456789"), None);
+        assert_eq!(exact_number("0x64"), None);
+    }
+
+    #[test]
+    fn test_worksheet_limits() -> Result<()> {
+        let snapshot = BalanceSnapshot {
+            balances: HashMap::from([(
+                Felt::from(1u64),
+                HashMap::from([(Felt::from(10u64), Felt::from(100u64))]),
+            )]),
+            ..Default::default()
+        };
+        // An XLSX file is a zip archive
+        assert!(build_workbook(&snapshot, &Formatting::default())?.starts_with(b"PK"));
+
+        // Value columns count towards the worksheet width
+        let valued = BalanceSnapshot {
+            valuation: Some(Valuation {
+                values: HashMap::from([(
+                    Felt::from(1u64),
+                    HashMap::from([(Felt::from(10u64), BigDecimal::from(3))]),
+                )]),
+                portfolio_totals: [(Felt::from(10u64), BigDecimal::from(3))].into(),
+                ..Default::default()
+            }),
+            ..snapshot
+        };
+        assert!(build_workbook(&valued, &Formatting::default())?.starts_with(b"PK"));
+        let too_wide = BalanceSnapshot {
+            balances: (0..MAX_COLUMNS as u64 - 4)
+                .map(|token| (Felt::from(token), HashMap::new()))
+                .collect(),
+            ..valued.clone()
+        };
+        assert!(build_workbook(&too_wide, &Formatting::default()).is_err());
+
+        let too_wide = BalanceSnapshot {
+            balances: (0..MAX_COLUMNS as u64)
+                .map(|token| (Felt::from(token), HashMap::new()))
+                .collect(),
+            ..Default::default()
+        };
+        assert!(build_workbook(&too_wide, &Formatting::default()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rusqlite::Connection;
+use tracing::{info, Level};
+
+/// Rows scanned between two updates of a bar or log check
+const UPDATE_EVERY: u64 = 1024;
+
+/// Interval of the progress log lines when stderr is not a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Progress of the storage scan, drawn as one bar per token on a terminal and logged
+/// periodically per shard otherwise
+pub struct ScanProgress {
+    bars: Option<MultiProgress>,
+}
+
+impl ScanProgress {
+    /// Bars are only drawn when stderr is a terminal and `info` logs are enabled
+    pub fn new() -> Self {
+        let draw = std::io::stderr().is_terminal() && tracing::enabled!(Level::INFO);
+        Self {
+            bars: draw.then(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr())),
+        }
+    }
+
+    /// Progress of the token `name` scanned in `shards`, with its estimated number of rows
+    ///
+    /// Without an estimate the bar is a spinner without an ETA.
+    pub fn token(&self, name: String, shards: usize, estimate: Option<u64>) -> TokenProgress {
+        let bar = self.bars.as_ref().map(|bars| {
+            let bar = match estimate {
+                Some(estimate) => ProgressBar::new(estimate).with_style(
+                    ProgressStyle::with_template(
+                        "{prefix:>12} [{bar:40}] {human_pos}/~{human_len} rows, ETA {eta} {msg}",
+                    )
+                    .unwrap()
+                    .progress_chars("=> "),
+                ),
+                None => ProgressBar::new_spinner().with_style(
+                    ProgressStyle::with_template(
+                        "{prefix:>12} {spinner} {human_pos} rows, {per_sec} {msg}",
+                    )
+                    .unwrap(),
+                ),
+            };
+            bars.add(bar.with_prefix(name.clone()))
+        });
+        TokenProgress {
+            name,
+            bar,
+            shards,
+            finished: AtomicUsize::new(0),
+            estimate,
+        }
+    }
+}
+
+impl Default for ScanProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Progress of the shards of one token
+pub struct TokenProgress {
+    name: String,
+    bar: Option<ProgressBar>,
+    shards: usize,
+    finished: AtomicUsize,
+    estimate: Option<u64>,
+}
+
+impl TokenProgress {
+    pub fn shard(&self, shard: usize) -> ShardProgress<'_> {
+        let now = Instant::now();
+        ShardProgress {
+            token: self,
+            shard,
+            rows: 0,
+            start: now,
+            last_log: now,
+        }
+    }
+}
+
+/// Rows scanned by one shard
+pub struct ShardProgress<'a> {
+    token: &'a TokenProgress,
+    shard: usize,
+    rows: u64,
+    start: Instant,
+    last_log: Instant,
+}
+
+impl ShardProgress<'_> {
+    /// Count a scanned row
+    pub fn inc(&mut self) {
+        self.rows += 1;
+        if !self.rows.is_multiple_of(UPDATE_EVERY) {
+            return;
+        }
+        match &self.token.bar {
+            Some(bar) => {
+                bar.inc(UPDATE_EVERY);
+                // Estimates are averages or the previous run's, a token can exceed them
+                if bar.length().is_some_and(|length| bar.position() > length) {
+                    bar.set_length(bar.position());
+                }
+            }
+            None if self.last_log.elapsed() >= LOG_INTERVAL => {
+                self.last_log = Instant::now();
+                self.log();
+            }
+            None => {}
+        }
+    }
+
+    /// Estimated rows of this shard, the token estimate split evenly across shards
+    fn estimate(&self) -> Option<u64> {
+        self.token
+            .estimate
+            .map(|estimate| estimate / self.token.shards.max(1) as u64)
+    }
+
+    fn log(&self) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let eta_s = self.estimate().and_then(|estimate| {
+            let rate = self.rows as f64 / elapsed;
+            (estimate > self.rows && rate > 0.0)
+                .then(|| ((estimate - self.rows) as f64 / rate) as u64)
+        });
+        info!(
+            token = %self.token.name,
+            shard = self.shard,
+            rows_scanned = self.rows,
+            estimated_total = self.estimate(),
+            eta_s,
+            "Scanning storage"
+        );
+    }
+
+    /// Mark the shard as done, finishing the token bar with its last shard
+    pub fn finish(self) {
+        let finished = self.token.finished.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(bar) = &self.token.bar {
+            bar.inc(self.rows % UPDATE_EVERY);
+            bar.set_message(format!("{finished}/{} shards", self.token.shards));
+            if finished == self.token.shards {
+                bar.set_length(bar.position());
+                bar.finish();
+            }
+        }
+    }
+}
+
+/// Estimated storage slots of a token, from the `sqlite_stat1` statistics of an index on
+/// `storage_updates (contract_address_id, storage_address_id, ...)`
+///
+/// This is the average over all contracts, and `None` if the database was never analyzed.
+pub fn estimated_slots_per_token(conn: &Connection) -> Option<u64> {
+    let mut stmt = conn
+        .prepare("SELECT idx, stat FROM sqlite_stat1 WHERE tbl = 'storage_updates'")
+        .ok()?;
+    let stats: Vec<(String, String)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .ok()?
+        .filter_map(|row| row.ok())
+        .collect();
+
+    for (index, stat) in stats {
+        let columns: Vec<String> = conn
+            .prepare("SELECT name FROM pragma_index_info(?1) ORDER BY seqno")
+            .and_then(|mut stmt| {
+                stmt.query_map([&index], |row| row.get(0))?
+                    .collect::<rusqlite::Result<_>>()
+            })
+            .unwrap_or_default();
+        if columns.len() < 2
+            || columns[0] != "contract_address_id"
+            || columns[1] != "storage_address_id"
+        {
+            continue;
+        }
+        // "<rows> <rows per contract> <rows per contract and slot> ..."
+        let numbers: Vec<u64> = stat
+            .split_whitespace()
+            .filter_map(|n| n.parse().ok())
+            .collect();
+        if let [_, per_contract, per_slot, ..] = numbers[..] {
+            return Some(per_contract / per_slot.max(1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimated_slots_per_token() -> rusqlite::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE storage_updates (
+                contract_address_id INTEGER NOT NULL,
+                storage_address_id INTEGER NOT NULL,
+                block_number INTEGER NOT NULL
+            );
+            CREATE INDEX storage_updates_contract_slot
+                ON storage_updates (contract_address_id, storage_address_id, block_number);",
+        )?;
+        assert_eq!(estimated_slots_per_token(&conn), None);
+
+        // 2 contracts with 50 slots updated twice each
+        let mut insert = conn.prepare("INSERT INTO storage_updates VALUES (?1, ?2, ?3)")?;
+        for contract in 0..2 {
+            for slot in 0..50 {
+                for block in 0..2 {
+                    insert.execute([contract, slot, block])?;
+                }
+            }
+        }
+        conn.execute_batch("ANALYZE")?;
+        assert_eq!(estimated_slots_per_token(&conn), Some(50));
+        Ok(())
+    }
+
+    #[test]
+    fn test_shard_progress() {
+        let progress = ScanProgress { bars: None };
+        let token = progress.token("TKN".to_string(), 2, Some(4096));
+        let mut shard = token.shard(0);
+        for _ in 0..3000 {
+            shard.inc();
+        }
+        assert_eq!(shard.rows, 3000);
+        assert_eq!(shard.estimate(), Some(2048));
+        shard.finish();
+        assert_eq!(token.finished.load(Ordering::Relaxed), 1);
+    }
+}
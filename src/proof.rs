@@ -0,0 +1,1289 @@
+use std::collections::{HashMap, HashSet};
+
+use bigdecimal::Zero;
+use eyre::Result;
+use num_bigint::BigUint;
+use rayon::prelude::*;
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+use starknet::core::crypto::pedersen_hash;
+use starknet::core::types::Felt;
+use starknet_crypto::poseidon_hash_many;
+use tracing::info;
+
+use crate::balance::{balance_storage_key, storage_value, BalanceSnapshot};
+use crate::destination::Destination;
+
+/// Number of bits of a trie key (contract or storage address)
+const KEY_BITS: usize = 251;
+
+/// Pathfinder trie tables a proof can be read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trie {
+    /// Global trie of contract state hashes, rooted at the storage commitment
+    Contracts,
+    /// Storage tries of individual contracts
+    Storage,
+}
+
+impl Trie {
+    fn table(&self) -> &'static str {
+        match self {
+            Trie::Contracts => "trie_contracts",
+            Trie::Storage => "trie_storage",
+        }
+    }
+}
+
+/// A trie node as it appears in a proof, children are referenced by hash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum TrieNode {
+    Binary { left: Felt, right: Felt },
+    Edge { child: Felt, path: Felt, length: u8 },
+}
+
+impl TrieNode {
+    pub fn hash(&self) -> Felt {
+        match self {
+            TrieNode::Binary { left, right } => pedersen_hash(left, right),
+            TrieNode::Edge {
+                child,
+                path,
+                length,
+            } => pedersen_hash(child, path) + Felt::from(*length),
+        }
+    }
+}
+
+/// A trie node as stored in the `data` column of the Pathfinder trie tables
+///
+/// Pathfinder encodes nodes with bincode's standard configuration, as an enum whose
+/// variant index and integers are varints:
+/// - `0`: binary node, left and right child index
+/// - `1`: edge node, child index and path
+/// - `2`: binary node whose children are leaves
+/// - `3`: edge node leading to a leaf, path
+///
+/// A path is a byte vector holding the path bits most significant first, followed by
+/// one byte with the number of bits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum StoredNode {
+    Binary { left: u64, right: u64 },
+    Edge { child: u64, path: Felt, length: u8 },
+    LeafBinary,
+    LeafEdge { path: Felt, length: u8 },
+}
+
+/// Reads the bincode fields of a stored node
+struct NodeReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> NodeReader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        if self.data.len() < count {
+            eyre::bail!("Truncated trie node");
+        }
+        let (bytes, rest) = self.data.split_at(count);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// Bincode varint: values below 251 in one byte, larger ones after a width marker
+    fn varint(&mut self) -> Result<u64> {
+        let le = |bytes: &[u8]| {
+            let mut buf = [0u8; 8];
+            buf[..bytes.len()].copy_from_slice(bytes);
+            u64::from_le_bytes(buf)
+        };
+        match self.take(1)?[0] {
+            byte @ 0..=250 => Ok(byte as u64),
+            251 => Ok(le(self.take(2)?)),
+            252 => Ok(le(self.take(4)?)),
+            253 => Ok(le(self.take(8)?)),
+            marker => Err(eyre::eyre!(
+                "Unsupported varint marker {} in trie node",
+                marker
+            )),
+        }
+    }
+
+    fn path(&mut self) -> Result<(Felt, u8)> {
+        let size = self.varint()?;
+        let bytes = self.take(usize::try_from(size)?)?;
+        let (&length, bits) = bytes
+            .split_last()
+            .ok_or_else(|| eyre::eyre!("Trie node path without a length"))?;
+        if length as usize > KEY_BITS || length as usize > bits.len() * 8 {
+            eyre::bail!("Trie node path of {} bits in {} bytes", length, bits.len());
+        }
+        let path = BigUint::from_bytes_be(bits) >> (bits.len() * 8 - length as usize);
+        Ok((Felt::from(path), length))
+    }
+}
+
+impl StoredNode {
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut reader = NodeReader { data };
+        match reader.varint() {
+            Ok(0) => Ok(StoredNode::Binary {
+                left: reader.varint()?,
+                right: reader.varint()?,
+            }),
+            Ok(1) => {
+                let child = reader.varint()?;
+                let (path, length) = reader.path()?;
+                Ok(StoredNode::Edge {
+                    child,
+                    path,
+                    length,
+                })
+            }
+            Ok(2) => Ok(StoredNode::LeafBinary),
+            Ok(3) => {
+                let (path, length) = reader.path()?;
+                Ok(StoredNode::LeafEdge { path, length })
+            }
+            Ok(tag) => Err(eyre::eyre!("Unknown trie node tag {}", tag)),
+            Err(_) if data.is_empty() => Err(eyre::eyre!("Empty trie node")),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Take `length` bits of `key`, starting below the `remaining` lowest bits still unread
+fn key_segment(key: &BigUint, remaining: usize, length: usize) -> BigUint {
+    (key >> (remaining - length)) & ((BigUint::from(1u8) << length) - 1u8)
+}
+
+fn felt_from_blob(blob: Vec<u8>) -> Felt {
+    Felt::from_bytes_be_slice(&blob)
+}
+
+/// Read a column of a trie node, with the statement prepared once per connection
+fn load_node_column(conn: &Connection, trie: Trie, column: &str, idx: u64) -> Result<Vec<u8>> {
+    conn.prepare_cached(&format!(
+        "SELECT {column} FROM {} WHERE idx = ?1",
+        trie.table()
+    ))
+    .and_then(|mut stmt| stmt.query_row([idx as i64], |row| row.get(0)))
+    .map_err(|e| eyre::eyre!("Failed to load {} {} {}: {}", trie.table(), column, idx, e))
+}
+
+fn load_node(conn: &Connection, trie: Trie, idx: u64) -> Result<StoredNode> {
+    StoredNode::decode(&load_node_column(conn, trie, "data", idx)?)
+}
+
+fn load_node_hash(conn: &Connection, trie: Trie, idx: u64) -> Result<Felt> {
+    load_node_column(conn, trie, "hash", idx).map(felt_from_blob)
+}
+
+fn check_edge_length(trie: Trie, idx: u64, length: u8, remaining: usize) -> Result<()> {
+    if remaining < length as usize {
+        eyre::bail!(
+            "{} edge node {} of {} bits is longer than the {} unread key bits",
+            trie.table(),
+            idx,
+            length,
+            remaining
+        );
+    }
+    Ok(())
+}
+
+/// Read the nodes on the path from the root to `key`
+///
+/// Leaf values are not stored in the trie tables, `leaf` looks them up by key. The
+/// path stops early at an edge that diverges from the key, which proves the key is unset.
+fn read_path(
+    conn: &Connection,
+    trie: Trie,
+    root_idx: Option<u64>,
+    key: &Felt,
+    leaf: &dyn Fn(&Felt) -> Result<Felt>,
+) -> Result<Vec<TrieNode>> {
+    let key = key.to_biguint();
+    let mut nodes = Vec::new();
+    let mut remaining = KEY_BITS;
+    let mut prefix = BigUint::zero();
+    let mut next = root_idx;
+
+    while let Some(idx) = next.take() {
+        match load_node(conn, trie, idx)? {
+            StoredNode::Binary { left, right } => {
+                if remaining < 1 {
+                    eyre::bail!("{} node {} is below the last key bit", trie.table(), idx);
+                }
+                nodes.push(TrieNode::Binary {
+                    left: load_node_hash(conn, trie, left)?,
+                    right: load_node_hash(conn, trie, right)?,
+                });
+                let bit = key_segment(&key, remaining, 1);
+                next = Some(if bit.is_zero() { left } else { right });
+                prefix = (prefix << 1) | bit;
+                remaining -= 1;
+            }
+            StoredNode::LeafBinary => {
+                if remaining != 1 {
+                    eyre::bail!(
+                        "{} leaf node {} is {} bits above the leaves",
+                        trie.table(),
+                        idx,
+                        remaining
+                    );
+                }
+                let base = &prefix << 1;
+                nodes.push(TrieNode::Binary {
+                    left: leaf(&Felt::from(&base))?,
+                    right: leaf(&Felt::from(base | BigUint::from(1u8)))?,
+                });
+            }
+            StoredNode::Edge {
+                child,
+                path,
+                length,
+            } => {
+                check_edge_length(trie, idx, length, remaining)?;
+                nodes.push(TrieNode::Edge {
+                    child: load_node_hash(conn, trie, child)?,
+                    path,
+                    length,
+                });
+                let segment = key_segment(&key, remaining, length as usize);
+                if segment != path.to_biguint() {
+                    break;
+                }
+                next = Some(child);
+                prefix = (prefix << length as usize) | segment;
+                remaining -= length as usize;
+            }
+            StoredNode::LeafEdge { path, length } => {
+                check_edge_length(trie, idx, length, remaining)?;
+                let leaf_key = (&prefix << length as usize) | path.to_biguint();
+                nodes.push(TrieNode::Edge {
+                    child: leaf(&Felt::from(leaf_key))?,
+                    path,
+                    length,
+                });
+            }
+        }
+    }
+
+    Ok(nodes)
+}
+
+/// Check that `nodes` prove `key` has `value` in the trie with the given root
+///
+/// A zero value is proven either by a leaf holding zero or by an edge that diverges
+/// from the key, since unset keys are not part of the trie.
+pub fn verify_path(nodes: &[TrieNode], key: &Felt, value: &Felt, root: &Felt) -> bool {
+    if nodes.is_empty() {
+        return *root == Felt::ZERO && *value == Felt::ZERO;
+    }
+
+    let key = key.to_biguint();
+    let mut expected = *root;
+    let mut remaining = KEY_BITS;
+    for node in nodes {
+        if node.hash() != expected {
+            return false;
+        }
+        match node {
+            TrieNode::Binary { left, right } => {
+                if remaining < 1 {
+                    return false;
+                }
+                let bit = key_segment(&key, remaining, 1);
+                expected = if bit.is_zero() { *left } else { *right };
+                remaining -= 1;
+            }
+            TrieNode::Edge {
+                child,
+                path,
+                length,
+            } => {
+                let length = *length as usize;
+                if remaining < length {
+                    return false;
+                }
+                if key_segment(&key, remaining, length) != path.to_biguint() {
+                    return *value == Felt::ZERO;
+                }
+                expected = *child;
+                remaining -= length;
+            }
+        }
+    }
+
+    remaining == 0 && expected == *value
+}
+
+/// Commitments of a block header, the anchors of every proof
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlockCommitments {
+    pub block_number: u64,
+    pub block_hash: Felt,
+    pub state_commitment: Felt,
+    pub storage_commitment: Felt,
+    pub class_commitment: Felt,
+}
+
+impl BlockCommitments {
+    /// The global state root derived from the storage and class commitments
+    pub fn computed_state_commitment(&self) -> Felt {
+        if self.class_commitment == Felt::ZERO {
+            self.storage_commitment
+        } else {
+            poseidon_hash_many(&[
+                Felt::from_bytes_be_slice(b"STARKNET_STATE_V0"),
+                self.storage_commitment,
+                self.class_commitment,
+            ])
+        }
+    }
+}
+
+/// Fields of a contract leaf in the global contracts trie
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ContractLeaf {
+    pub class_hash: Felt,
+    pub nonce: Felt,
+    pub storage_root: Felt,
+}
+
+impl ContractLeaf {
+    /// `H(H(H(class_hash, storage_root), nonce), 0)`
+    pub fn state_hash(&self) -> Felt {
+        pedersen_hash(
+            &pedersen_hash(
+                &pedersen_hash(&self.class_hash, &self.storage_root),
+                &self.nonce,
+            ),
+            &Felt::ZERO,
+        )
+    }
+}
+
+/// Proof of a single balance within the storage trie of its token, see [`TokenProofs`]
+#[derive(Debug, Clone)]
+pub struct BalanceProof {
+    pub storage_key: Felt,
+    pub storage_value: Felt,
+    pub storage_proof: Vec<TrieNode>,
+}
+
+/// Proofs of the balances of one token, sharing the commitments and the proof of the
+/// token contract up to the global state root
+#[derive(Debug, Clone)]
+pub struct TokenProofs {
+    pub commitments: BlockCommitments,
+    pub contract_address: Felt,
+    pub contract_leaf: ContractLeaf,
+    pub contract_proof: Vec<TrieNode>,
+    pub balances: Vec<(Felt, BalanceProof)>,
+}
+
+impl TokenProofs {
+    /// Verify the contract up to the global state root, returning the first failed check
+    pub fn verify_contract(&self) -> Result<(), String> {
+        if self.commitments.computed_state_commitment() != self.commitments.state_commitment {
+            return Err("state commitment does not match storage and class commitments".into());
+        }
+        if !verify_path(
+            &self.contract_proof,
+            &self.contract_address,
+            &self.contract_leaf.state_hash(),
+            &self.commitments.storage_commitment,
+        ) {
+            return Err("contract proof does not match the storage commitment".into());
+        }
+        Ok(())
+    }
+
+    /// Verify a balance up to the contract storage root
+    ///
+    /// Only proves the balance once [`TokenProofs::verify_contract`] succeeded.
+    pub fn verify_balance(&self, proof: &BalanceProof) -> Result<(), String> {
+        if !verify_path(
+            &proof.storage_proof,
+            &proof.storage_key,
+            &proof.storage_value,
+            &self.contract_leaf.storage_root,
+        ) {
+            return Err("storage proof does not match the contract storage root".into());
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of verifying a single balance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerificationStatus {
+    Verified,
+    Failed(String),
+}
+
+impl std::fmt::Display for VerificationStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerificationStatus::Verified => write!(f, "verified"),
+            VerificationStatus::Failed(reason) => write!(f, "failed: {reason}"),
+        }
+    }
+}
+
+/// Load the commitments of the latest block in the database
+pub fn head_commitments(conn: &Connection) -> Result<BlockCommitments> {
+    conn.query_row(
+        "SELECT number, hash, state_commitment, storage_commitment, class_commitment
+         FROM block_headers ORDER BY number DESC LIMIT 1",
+        [],
+        |row| {
+            Ok(BlockCommitments {
+                block_number: row.get::<_, i64>(0)? as u64,
+                block_hash: felt_from_blob(row.get(1)?),
+                state_commitment: felt_from_blob(row.get(2)?),
+                storage_commitment: felt_from_blob(row.get(3)?),
+                class_commitment: felt_from_blob(row.get(4)?),
+            })
+        },
+    )
+    .map_err(|e| eyre::eyre!("Failed to load the head block header: {}", e))
+}
+
+/// Latest value of a felt column for a contract at or before a block
+fn latest_felt(
+    conn: &Connection,
+    query: &str,
+    contract_address: &Felt,
+    block_number: u64,
+) -> Result<Option<Felt>> {
+    conn.query_row(
+        query,
+        rusqlite::params![contract_address.to_bytes_be().to_vec(), block_number as i64],
+        |row| row.get::<_, Vec<u8>>(0),
+    )
+    .optional()
+    .map(|value| value.map(felt_from_blob))
+    .map_err(|e| eyre::eyre!("Failed to query contract state: {}", e))
+}
+
+fn root_index(
+    conn: &Connection,
+    query: &str,
+    params: impl rusqlite::Params,
+) -> Result<Option<u64>> {
+    conn.query_row(query, params, |row| row.get::<_, Option<i64>>(0))
+        .optional()
+        .map(|idx| idx.flatten().map(|idx| idx as u64))
+        .map_err(|e| eyre::eyre!("Failed to query trie root: {}", e))
+}
+
+/// Contract leaf and its path in the global contracts trie
+fn contract_proof(
+    conn: &Connection,
+    commitments: &BlockCommitments,
+    contract_address: &Felt,
+) -> Result<(ContractLeaf, Vec<TrieNode>, Option<u64>)> {
+    let block_number = commitments.block_number;
+
+    let storage_root_idx = root_index(
+        conn,
+        "SELECT root_index FROM contract_roots
+         WHERE contract_address = ?1 AND block_number <= ?2
+         ORDER BY block_number DESC LIMIT 1",
+        rusqlite::params![contract_address.to_bytes_be().to_vec(), block_number as i64],
+    )?;
+    let storage_root = match storage_root_idx {
+        Some(idx) => load_node_hash(conn, Trie::Storage, idx)?,
+        None => Felt::ZERO,
+    };
+
+    let contract_leaf = ContractLeaf {
+        class_hash: latest_felt(
+            conn,
+            "SELECT class_hash FROM contract_updates
+             WHERE contract_address = ?1 AND block_number <= ?2
+             ORDER BY block_number DESC LIMIT 1",
+            contract_address,
+            block_number,
+        )?
+        .unwrap_or(Felt::ZERO),
+        nonce: latest_felt(
+            conn,
+            "SELECT nonce FROM nonce_updates
+             WHERE contract_address = ?1 AND block_number <= ?2
+             ORDER BY block_number DESC LIMIT 1",
+            contract_address,
+            block_number,
+        )?
+        .unwrap_or(Felt::ZERO),
+        storage_root,
+    };
+
+    let contracts_root_idx = root_index(
+        conn,
+        "SELECT root_index FROM storage_roots
+         WHERE block_number <= ?1
+         ORDER BY block_number DESC LIMIT 1",
+        [block_number as i64],
+    )?;
+    let state_hash_of = |address: &Felt| -> Result<Felt> {
+        Ok(latest_felt(
+            conn,
+            "SELECT state_hash FROM contract_state_hashes
+             WHERE contract_address = ?1 AND block_number <= ?2
+             ORDER BY block_number DESC LIMIT 1",
+            address,
+            block_number,
+        )?
+        .unwrap_or(Felt::ZERO))
+    };
+    let nodes = read_path(
+        conn,
+        Trie::Contracts,
+        contracts_root_idx,
+        contract_address,
+        &state_hash_of,
+    )?;
+
+    Ok((contract_leaf, nodes, storage_root_idx))
+}
+
+/// Build the proofs of the balance of every account for one token
+pub fn build_token_proofs(
+    conn: &Connection,
+    commitments: &BlockCommitments,
+    token: &Felt,
+    accounts: &[Felt],
+) -> Result<TokenProofs> {
+    let (contract_leaf, contract_proof, storage_root_idx) =
+        contract_proof(conn, commitments, token)?;
+    let value_of =
+        |key: &Felt| -> Result<Felt> { storage_value(conn, token, key, commitments.block_number) };
+
+    let balances = accounts
+        .iter()
+        .map(|account| {
+            let storage_key = balance_storage_key(account);
+            let storage_proof = read_path(
+                conn,
+                Trie::Storage,
+                storage_root_idx,
+                &storage_key,
+                &value_of,
+            )?;
+            Ok((
+                *account,
+                BalanceProof {
+                    storage_key,
+                    storage_value: value_of(&storage_key)?,
+                    storage_proof,
+                },
+            ))
+        })
+        .collect::<Result<_>>()?;
+    Ok(TokenProofs {
+        commitments: commitments.clone(),
+        contract_address: *token,
+        contract_leaf,
+        contract_proof,
+        balances,
+    })
+}
+
+/// Verify every balance of the snapshot against the head block's global state root
+///
+/// Each token is verified in parallel with its own connection. A balance is verified
+/// only if the proof holds and proves exactly the balance found in the snapshot.
+pub fn verify_snapshot(
+    db_path: &str,
+    snapshot: &BalanceSnapshot,
+) -> Result<HashMap<Felt, HashMap<Felt, VerificationStatus>>> {
+    let conn = Connection::open(db_path)
+        .map_err(|e| eyre::eyre!("Failed to open database '{}': {}", db_path, e))?;
+    let commitments = head_commitments(&conn)?;
+    info!(
+        block_number = commitments.block_number,
+        "Verifying balances against the state root"
+    );
+
+    snapshot
+        .balances
+        .par_iter()
+        .map(|(token, balances)| {
+            let conn = Connection::open(db_path)
+                .map_err(|e| eyre::eyre!("Failed to create database connection: {}", e))?;
+            let accounts: Vec<Felt> = balances.keys().copied().collect();
+            let proofs = build_token_proofs(&conn, &commitments, token, &accounts)?;
+
+            // The contract proof is shared by every balance of the token, check it once
+            let contract = proofs.verify_contract();
+            let statuses = proofs
+                .balances
+                .iter()
+                .map(|(account, proof)| {
+                    let verified = contract.clone().and_then(|()| proofs.verify_balance(proof));
+                    let status = match verified {
+                        Err(reason) => VerificationStatus::Failed(reason),
+                        Ok(()) if proof.storage_value != balances[account] => {
+                            VerificationStatus::Failed(
+                                "balance differs from the proven storage value".into(),
+                            )
+                        }
+                        Ok(()) => VerificationStatus::Verified,
+                    };
+                    (*account, status)
+                })
+                .collect();
+            Ok((*token, statuses))
+        })
+        .collect()
+}
+
+/// A proof node together with its hash, as returned by `starknet_getStorageProof`
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeHashToNode {
+    pub node_hash: Felt,
+    pub node: TrieNode,
+}
+
+/// The `contracts_proof` field of a `starknet_getStorageProof` response
+#[derive(Debug, Clone, Serialize)]
+pub struct ContractsProof {
+    pub nodes: Vec<NodeHashToNode>,
+    /// Leaf data of each requested contract, in request order
+    pub contract_leaves_data: Vec<ContractLeaf>,
+}
+
+/// The `global_roots` field of a `starknet_getStorageProof` response
+#[derive(Debug, Clone, Serialize)]
+pub struct GlobalRoots {
+    pub contracts_tree_root: Felt,
+    pub classes_tree_root: Felt,
+    pub block_hash: Felt,
+}
+
+/// A `starknet_getStorageProof` response
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageProof {
+    /// Always empty, balances don't depend on the classes trie
+    pub classes_proof: Vec<NodeHashToNode>,
+    pub contracts_proof: ContractsProof,
+    /// Storage proof nodes of each requested contract, in request order
+    pub contracts_storage_proofs: Vec<Vec<NodeHashToNode>>,
+    pub global_roots: GlobalRoots,
+}
+
+/// Storage keys requested for one contract, as in the `contracts_storage_keys` parameter
+#[derive(Debug, Clone, Serialize)]
+pub struct ContractStorageKeys {
+    pub contract_address: Felt,
+    pub storage_keys: Vec<Felt>,
+}
+
+/// The storage slot holding the balance of an account
+#[derive(Debug, Clone, Serialize)]
+pub struct BalanceEntry {
+    pub contract_address: Felt,
+    pub account: Felt,
+    pub storage_key: Felt,
+    pub value: Felt,
+}
+
+/// Proof evidence for every balance of a snapshot
+///
+/// `request` and `proof` are the parameters and result of the equivalent
+/// `starknet_getStorageProof` call, so any verifier of that RPC can check them.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProofExport {
+    pub block_number: u64,
+    pub block_hash: Felt,
+    pub state_root: Felt,
+    pub request: Vec<ContractStorageKeys>,
+    pub balances: Vec<BalanceEntry>,
+    pub proof: StorageProof,
+}
+
+/// Append nodes to a proof node list, skipping nodes that are already part of it
+fn push_nodes(nodes: &mut Vec<NodeHashToNode>, seen: &mut HashSet<Felt>, path: &[TrieNode]) {
+    for node in path {
+        let node_hash = node.hash();
+        if seen.insert(node_hash) {
+            nodes.push(NodeHashToNode {
+                node_hash,
+                node: node.clone(),
+            });
+        }
+    }
+}
+
+/// Read the proofs of every balance of the snapshot at the head block
+///
+/// Tokens and accounts are sorted by address so the export is reproducible.
+pub fn export_snapshot_proofs(db_path: &str, snapshot: &BalanceSnapshot) -> Result<ProofExport> {
+    let conn = Connection::open(db_path)
+        .map_err(|e| eyre::eyre!("Failed to open database '{}': {}", db_path, e))?;
+    let commitments = head_commitments(&conn)?;
+
+    let mut tokens: Vec<&Felt> = snapshot.balances.keys().collect();
+    tokens.sort();
+
+    let mut request = Vec::with_capacity(tokens.len());
+    let mut balances = Vec::new();
+    let mut contract_nodes = Vec::new();
+    let mut seen_contract_nodes = HashSet::new();
+    let mut contract_leaves_data = Vec::with_capacity(tokens.len());
+    let mut contracts_storage_proofs = Vec::with_capacity(tokens.len());
+
+    for token in tokens {
+        let mut accounts: Vec<Felt> = snapshot.balances[token].keys().copied().collect();
+        accounts.sort();
+        let proofs = build_token_proofs(&conn, &commitments, token, &accounts)?;
+
+        let mut storage_nodes = Vec::new();
+        let mut seen_storage_nodes = HashSet::new();
+        let mut storage_keys = Vec::with_capacity(proofs.balances.len());
+        for (account, proof) in &proofs.balances {
+            push_nodes(
+                &mut storage_nodes,
+                &mut seen_storage_nodes,
+                &proof.storage_proof,
+            );
+            storage_keys.push(proof.storage_key);
+            balances.push(BalanceEntry {
+                contract_address: *token,
+                account: *account,
+                storage_key: proof.storage_key,
+                value: proof.storage_value,
+            });
+        }
+
+        // Every proof of a token shares the same contract proof
+        push_nodes(
+            &mut contract_nodes,
+            &mut seen_contract_nodes,
+            &proofs.contract_proof,
+        );
+        contract_leaves_data.push(proofs.contract_leaf);
+        contracts_storage_proofs.push(storage_nodes);
+        request.push(ContractStorageKeys {
+            contract_address: *token,
+            storage_keys,
+        });
+    }
+
+    Ok(ProofExport {
+        block_number: commitments.block_number,
+        block_hash: commitments.block_hash,
+        state_root: commitments.state_commitment,
+        request,
+        balances,
+        proof: StorageProof {
+            classes_proof: Vec::new(),
+            contracts_proof: ContractsProof {
+                nodes: contract_nodes,
+                contract_leaves_data,
+            },
+            contracts_storage_proofs,
+            global_roots: GlobalRoots {
+                contracts_tree_root: commitments.storage_commitment,
+                classes_tree_root: commitments.class_commitment,
+                block_hash: commitments.block_hash,
+            },
+        },
+    })
+}
+
+/// Write the proof export to a JSON file, replacing an existing one only with `force`
+pub fn write_proof_export(
+    export: &ProofExport,
+    destination: &Destination,
+    force: bool,
+) -> Result<()> {
+    destination
+        .write_with(force, |w| {
+            serde_json::to_writer_pretty(w, export)?;
+            Ok(())
+        })
+        .map_err(|e| eyre::eyre!("Failed to store storage proofs: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::{create_storage_tables, insert_storage};
+
+    /// Insert a trie holding `leaves` into `table` and return its root index and hash
+    ///
+    /// Mirrors how Pathfinder lays out tries: edges compress runs of single children,
+    /// leaf values are not stored and nodes reference children by index.
+    fn insert_trie(
+        conn: &Connection,
+        table: &str,
+        leaves: &[(Felt, Felt)],
+    ) -> eyre::Result<(Option<u64>, Felt)> {
+        let mut leaves: Vec<(BigUint, Felt)> = leaves
+            .iter()
+            .map(|(key, value)| (key.to_biguint(), *value))
+            .collect();
+        leaves.sort();
+        if leaves.is_empty() {
+            return Ok((None, Felt::ZERO));
+        }
+        let (idx, hash) = insert_subtrie(conn, table, &leaves, KEY_BITS)?;
+        Ok((Some(idx), hash))
+    }
+
+    fn insert_node(conn: &Connection, table: &str, hash: Felt, data: Vec<u8>) -> eyre::Result<u64> {
+        conn.execute(
+            &format!("INSERT INTO {table} (hash, data) VALUES (?1, ?2)"),
+            rusqlite::params![hash.to_bytes_be().to_vec(), data],
+        )?;
+        Ok(conn.last_insert_rowid() as u64)
+    }
+
+    /// Bincode varint, as Pathfinder writes integers
+    fn encode_varint(data: &mut Vec<u8>, value: u64) {
+        match value {
+            0..=250 => data.push(value as u8),
+            251..=0xffff => {
+                data.push(251);
+                data.extend_from_slice(&(value as u16).to_le_bytes());
+            }
+            0x10000..=0xffff_ffff => {
+                data.push(252);
+                data.extend_from_slice(&(value as u32).to_le_bytes());
+            }
+            _ => {
+                data.push(253);
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+
+    /// Path bits packed most significant first, then the bit count, as a bincode `Vec<u8>`
+    fn encode_path(data: &mut Vec<u8>, path: &BigUint, length: usize) {
+        let mut bytes = vec![0u8; length.div_ceil(8)];
+        for bit in 0..length {
+            if path.bit((length - 1 - bit) as u64) {
+                bytes[bit / 8] |= 0x80 >> (bit % 8);
+            }
+        }
+        bytes.push(length as u8);
+        encode_varint(data, bytes.len() as u64);
+        data.extend_from_slice(&bytes);
+    }
+
+    /// Insert the subtrie of `leaves` below `remaining` unread key bits
+    fn insert_subtrie(
+        conn: &Connection,
+        table: &str,
+        leaves: &[(BigUint, Felt)],
+        remaining: usize,
+    ) -> eyre::Result<(u64, Felt)> {
+        // Longest run of bits shared by all keys below this height
+        let mut common = 0;
+        while common < remaining {
+            let first = key_segment(&leaves[0].0, remaining, common + 1);
+            if leaves
+                .iter()
+                .all(|(key, _)| key_segment(key, remaining, common + 1) == first)
+            {
+                common += 1;
+            } else {
+                break;
+            }
+        }
+
+        if common > 0 {
+            let path = key_segment(&leaves[0].0, remaining, common);
+            let (child_hash, mut data) = if common == remaining {
+                (leaves[0].1, vec![3])
+            } else {
+                let (idx, hash) = insert_subtrie(conn, table, leaves, remaining - common)?;
+                let mut data = vec![1];
+                encode_varint(&mut data, idx);
+                (hash, data)
+            };
+            let node = TrieNode::Edge {
+                child: child_hash,
+                path: Felt::from(&path),
+                length: common as u8,
+            };
+            encode_path(&mut data, &path, common);
+            let idx = insert_node(conn, table, node.hash(), data)?;
+            return Ok((idx, node.hash()));
+        }
+
+        let split = leaves
+            .iter()
+            .position(|(key, _)| !key_segment(key, remaining, 1).is_zero())
+            .unwrap();
+        let (left, right) = leaves.split_at(split);
+        if remaining == 1 {
+            let node = TrieNode::Binary {
+                left: left[0].1,
+                right: right[0].1,
+            };
+            let idx = insert_node(conn, table, node.hash(), vec![2])?;
+            return Ok((idx, node.hash()));
+        }
+
+        let (left_idx, left_hash) = insert_subtrie(conn, table, left, remaining - 1)?;
+        let (right_idx, right_hash) = insert_subtrie(conn, table, right, remaining - 1)?;
+        let node = TrieNode::Binary {
+            left: left_hash,
+            right: right_hash,
+        };
+        let mut data = vec![0];
+        encode_varint(&mut data, left_idx);
+        encode_varint(&mut data, right_idx);
+        let idx = insert_node(conn, table, node.hash(), data)?;
+        Ok((idx, node.hash()))
+    }
+
+    fn create_trie_table(conn: &Connection, table: &str) -> eyre::Result<()> {
+        conn.execute(
+            &format!(
+                "CREATE TABLE {table} (idx INTEGER PRIMARY KEY, hash BLOB NOT NULL, data BLOB)"
+            ),
+            [],
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_trie_paths() -> eyre::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        create_trie_table(&conn, "trie_storage")?;
+
+        let leaves = [
+            (Felt::from(0b1000u64), Felt::from(10u64)),
+            (Felt::from(0b1001u64), Felt::from(20u64)),
+            (Felt::from(0b1100u64), Felt::from(30u64)),
+            // Highest possible key bit, on the other side of the root
+            (
+                Felt::from_hex(
+                    "0x4000000000000000000000000000000000000000000000000000000000000000",
+                )?,
+                Felt::from(40u64),
+            ),
+        ];
+        let (root_idx, root) = insert_trie(&conn, "trie_storage", &leaves)?;
+        let values: HashMap<Felt, Felt> = leaves.iter().copied().collect();
+        let leaf = |key: &Felt| -> eyre::Result<Felt> {
+            Ok(values.get(key).copied().unwrap_or(Felt::ZERO))
+        };
+
+        for (key, value) in &leaves {
+            let nodes = read_path(&conn, Trie::Storage, root_idx, key, &leaf)?;
+            assert!(verify_path(&nodes, key, value, &root));
+            assert!(!verify_path(&nodes, key, &(*value + Felt::ONE), &root));
+        }
+
+        // An unset key is proven to be zero by a diverging edge
+        let unset = Felt::from(0b1110u64);
+        let nodes = read_path(&conn, Trie::Storage, root_idx, &unset, &leaf)?;
+        assert!(verify_path(&nodes, &unset, &Felt::ZERO, &root));
+        assert!(!verify_path(&nodes, &unset, &Felt::ONE, &root));
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_trie() -> eyre::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        create_trie_table(&conn, "trie_storage")?;
+
+        let leaf = |_: &Felt| -> eyre::Result<Felt> { Ok(Felt::ZERO) };
+        let nodes = read_path(&conn, Trie::Storage, None, &Felt::ONE, &leaf)?;
+        assert!(nodes.is_empty());
+        assert!(verify_path(&nodes, &Felt::ONE, &Felt::ZERO, &Felt::ZERO));
+        assert!(!verify_path(&nodes, &Felt::ONE, &Felt::ONE, &Felt::ZERO));
+        Ok(())
+    }
+
+    const TOKEN: Felt = Felt::from_hex_unchecked("0x1234");
+    const ALICE: Felt = Felt::from_hex_unchecked("0x10");
+    const BOB: Felt = Felt::from_hex_unchecked("0x11");
+    const CAROL: Felt = Felt::from_hex_unchecked("0x12");
+
+    /// Create a node database holding the balances of ALICE (1000) and BOB (2000)
+    /// together with the tries and header committing to them at block 5
+    fn create_state_database() -> eyre::Result<(tempfile::NamedTempFile, Connection)> {
+        let temp_file = tempfile::NamedTempFile::new()?;
+        let conn = Connection::open(temp_file.path())?;
+        conn.execute_batch(
+            "CREATE TABLE block_headers (
+                number INTEGER PRIMARY KEY,
+                hash BLOB NOT NULL,
+                state_commitment BLOB NOT NULL,
+                storage_commitment BLOB NOT NULL,
+                class_commitment BLOB NOT NULL
+             );
+             CREATE TABLE contract_roots (block_number INTEGER, contract_address BLOB, root_index INTEGER);
+             CREATE TABLE storage_roots (block_number INTEGER, root_index INTEGER);
+             CREATE TABLE contract_state_hashes (block_number INTEGER, contract_address BLOB, state_hash BLOB);
+             CREATE TABLE contract_updates (block_number INTEGER, contract_address BLOB, class_hash BLOB);
+             CREATE TABLE nonce_updates (block_number INTEGER, contract_address BLOB, nonce BLOB);",
+        )?;
+        create_storage_tables(&conn)?;
+        create_trie_table(&conn, "trie_storage")?;
+        create_trie_table(&conn, "trie_contracts")?;
+
+        let blob = |felt: &Felt| felt.to_bytes_be().to_vec();
+        let (token, alice, bob) = (TOKEN, ALICE, BOB);
+
+        // Storage of the token contract
+        let storage = [
+            (balance_storage_key(&alice), Felt::from(1000u64)),
+            (balance_storage_key(&bob), Felt::from(2000u64)),
+        ];
+        for (key, value) in &storage {
+            insert_storage(&conn, &token, key, value, 5)?;
+        }
+        let (storage_root_idx, storage_root) = insert_trie(&conn, "trie_storage", &storage)?;
+        conn.execute(
+            "INSERT INTO contract_roots VALUES (5, ?1, ?2)",
+            rusqlite::params![blob(&token), storage_root_idx.map(|idx| idx as i64)],
+        )?;
+
+        // Contract leaf in the global contracts trie
+        let contract_leaf = ContractLeaf {
+            class_hash: Felt::from(0xc1a55u64),
+            nonce: Felt::ZERO,
+            storage_root,
+        };
+        conn.execute(
+            "INSERT INTO contract_updates VALUES (1, ?1, ?2)",
+            [blob(&token), blob(&contract_leaf.class_hash)],
+        )?;
+        conn.execute(
+            "INSERT INTO contract_state_hashes VALUES (5, ?1, ?2)",
+            [blob(&token), blob(&contract_leaf.state_hash())],
+        )?;
+        let (contracts_root_idx, storage_commitment) = insert_trie(
+            &conn,
+            "trie_contracts",
+            &[(token, contract_leaf.state_hash())],
+        )?;
+        conn.execute(
+            "INSERT INTO storage_roots VALUES (5, ?1)",
+            [contracts_root_idx.map(|idx| idx as i64)],
+        )?;
+
+        let mut commitments = BlockCommitments {
+            block_number: 5,
+            block_hash: Felt::from(0xb10cu64),
+            state_commitment: Felt::ZERO,
+            storage_commitment,
+            class_commitment: Felt::from(0x123u64),
+        };
+        commitments.state_commitment = commitments.computed_state_commitment();
+        conn.execute(
+            "INSERT INTO block_headers VALUES (5, ?1, ?2, ?3, ?4)",
+            [
+                blob(&commitments.block_hash),
+                blob(&commitments.state_commitment),
+                blob(&commitments.storage_commitment),
+                blob(&commitments.class_commitment),
+            ],
+        )?;
+
+        Ok((temp_file, conn))
+    }
+
+    #[test]
+    fn test_verify_snapshot() -> eyre::Result<()> {
+        let (temp_file, conn) = create_state_database()?;
+        let blob = |felt: &Felt| felt.to_bytes_be().to_vec();
+        let (token, alice, bob, carol) = (TOKEN, ALICE, BOB, CAROL);
+
+        let snapshot = BalanceSnapshot {
+            balances: HashMap::from([(
+                token,
+                HashMap::from([
+                    (alice, Felt::from(1000u64)),
+                    // Tampered balance
+                    (bob, Felt::from(2001u64)),
+                    // Never set, proven to be zero
+                    (carol, Felt::ZERO),
+                ]),
+            )]),
+            ..Default::default()
+        };
+
+        let db_path = temp_file.path().to_str().unwrap();
+        let statuses = &verify_snapshot(db_path, &snapshot)?[&token];
+        assert_eq!(statuses[&alice], VerificationStatus::Verified);
+        assert!(matches!(statuses[&bob], VerificationStatus::Failed(_)));
+        assert_eq!(statuses[&carol], VerificationStatus::Verified);
+
+        // A header that doesn't commit to the tries fails every balance
+        conn.execute(
+            "UPDATE block_headers SET state_commitment = ?1",
+            [blob(&Felt::ONE)],
+        )?;
+        let statuses = &verify_snapshot(db_path, &snapshot)?[&token];
+        assert!(statuses
+            .values()
+            .all(|status| matches!(status, VerificationStatus::Failed(_))));
+        Ok(())
+    }
+
+    /// Walk the exported nodes from `root` along `key`, as an external verifier would
+    fn path_from_nodes(nodes: &[NodeHashToNode], root: &Felt, key: &Felt) -> Vec<TrieNode> {
+        let by_hash: HashMap<Felt, &TrieNode> =
+            nodes.iter().map(|n| (n.node_hash, &n.node)).collect();
+        let key = key.to_biguint();
+        let mut remaining = KEY_BITS;
+        let mut path = Vec::new();
+        let mut next = *root;
+        while let Some(node) = by_hash.get(&next) {
+            path.push((*node).clone());
+            match node {
+                TrieNode::Binary { left, right } => {
+                    let bit = key_segment(&key, remaining, 1);
+                    next = if bit.is_zero() { *left } else { *right };
+                    remaining -= 1;
+                }
+                TrieNode::Edge { child, length, .. } => {
+                    next = *child;
+                    remaining -= *length as usize;
+                }
+            }
+            if remaining == 0 {
+                break;
+            }
+        }
+        path
+    }
+
+    #[test]
+    fn test_export_snapshot_proofs() -> eyre::Result<()> {
+        let (temp_file, _conn) = create_state_database()?;
+        let snapshot = BalanceSnapshot {
+            balances: HashMap::from([(
+                TOKEN,
+                HashMap::from([
+                    (ALICE, Felt::from(1000u64)),
+                    (BOB, Felt::from(2000u64)),
+                    (CAROL, Felt::ZERO),
+                ]),
+            )]),
+            ..Default::default()
+        };
+
+        let export = export_snapshot_proofs(temp_file.path().to_str().unwrap(), &snapshot)?;
+        assert_eq!(export.block_number, 5);
+        assert_eq!(export.request.len(), 1);
+        assert_eq!(export.request[0].storage_keys.len(), 3);
+        assert_eq!(export.balances.len(), 3);
+        assert!(export.proof.classes_proof.is_empty());
+
+        // Everything an external verifier needs is in the export
+        let roots = &export.proof.global_roots;
+        let contract_leaf = &export.proof.contracts_proof.contract_leaves_data[0];
+        let contract_path = path_from_nodes(
+            &export.proof.contracts_proof.nodes,
+            &roots.contracts_tree_root,
+            &TOKEN,
+        );
+        assert!(verify_path(
+            &contract_path,
+            &TOKEN,
+            &contract_leaf.state_hash(),
+            &roots.contracts_tree_root
+        ));
+
+        let storage_nodes = &export.proof.contracts_storage_proofs[0];
+        for entry in &export.balances {
+            assert_eq!(entry.value, snapshot.balances[&TOKEN][&entry.account]);
+            let path = path_from_nodes(
+                storage_nodes,
+                &contract_leaf.storage_root,
+                &entry.storage_key,
+            );
+            assert!(verify_path(
+                &path,
+                &entry.storage_key,
+                &entry.value,
+                &contract_leaf.storage_root
+            ));
+        }
+
+        // The JSON has the shape of a starknet_getStorageProof response
+        let json = serde_json::to_value(&export)?;
+        assert!(json["proof"]["contracts_proof"]["nodes"][0]["node_hash"].is_string());
+        assert!(json["proof"]["global_roots"]["block_hash"].is_string());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_stored_nodes() {
+        // Binary node with children 1 and 300, the latter as a 2 byte varint
+        assert_eq!(
+            StoredNode::decode(&[0, 1, 251, 0x2c, 0x01]).unwrap(),
+            StoredNode::Binary {
+                left: 1,
+                right: 300
+            }
+        );
+        // Edge node to child 7 along the 3 bits 101
+        assert_eq!(
+            StoredNode::decode(&[1, 7, 2, 0b1010_0000, 3]).unwrap(),
+            StoredNode::Edge {
+                child: 7,
+                path: Felt::from(0b101u64),
+                length: 3
+            }
+        );
+        // Edge node to a leaf along 10 bits spanning two bytes
+        assert_eq!(
+            StoredNode::decode(&[3, 3, 0b1100_0000, 0b0100_0000, 10]).unwrap(),
+            StoredNode::LeafEdge {
+                path: Felt::from(0b11_0000_0001u64),
+                length: 10
+            }
+        );
+        assert_eq!(StoredNode::decode(&[2]).unwrap(), StoredNode::LeafBinary);
+
+        assert!(StoredNode::decode(&[]).is_err());
+        assert!(StoredNode::decode(&[0, 1]).is_err());
+        assert!(StoredNode::decode(&[9]).is_err());
+        // More path bits than bytes, and a path longer than a key
+        assert!(StoredNode::decode(&[3, 2, 0xff, 9]).is_err());
+        let mut long = vec![3, 34];
+        long.extend_from_slice(&[0; 33]);
+        long.push(252);
+        assert!(StoredNode::decode(&long).is_err());
+    }
+
+    #[test]
+    fn test_read_path_rejects_overlong_edges() -> eyre::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        create_trie_table(&conn, "trie_storage")?;
+        let leaf = |_: &Felt| -> eyre::Result<Felt> { Ok(Felt::ZERO) };
+
+        // A 251 bit edge leading to a binary node, which is past the last key bit
+        let mut data = vec![1, 2];
+        encode_path(&mut data, &BigUint::zero(), KEY_BITS);
+        let root = insert_node(&conn, "trie_storage", Felt::ONE, data)?;
+        insert_node(&conn, "trie_storage", Felt::TWO, vec![0, 3, 3])?;
+        assert!(read_path(&conn, Trie::Storage, Some(root), &Felt::ZERO, &leaf).is_err());
+
+        // A leaf edge longer than the key
+        let mut data = vec![3];
+        encode_path(&mut data, &BigUint::zero(), KEY_BITS);
+        let edge = insert_node(&conn, "trie_storage", Felt::THREE, data)?;
+        let binary = insert_node(
+            &conn,
+            "trie_storage",
+            Felt::from(4u64),
+            vec![0, edge as u8, edge as u8],
+        )?;
+        assert!(read_path(&conn, Trie::Storage, Some(binary), &Felt::ZERO, &leaf).is_err());
+        Ok(())
+    }
+}
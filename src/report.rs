@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use starknet::core::types::Felt;
+use tracing::info;
+
+use crate::balance::ShardScan;
+use crate::destination::Destination;
+use crate::output::ManifestEntry;
+
+/// Machine-readable summary of a run, written with `--report` for job schedulers
+#[derive(Debug, Clone, Serialize)]
+pub struct RunReport {
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    /// Error the run failed with, `None` for successful runs
+    pub error: Option<String>,
+    pub block_number: Option<u64>,
+    pub input_hash: Option<String>,
+    /// Phases in the order they ran
+    pub phases: Vec<PhaseTiming>,
+    pub shards: Vec<ShardScan>,
+    pub rows_scanned: u64,
+    pub rows_matched: u64,
+    /// Files written by the output sinks
+    pub outputs: Vec<ManifestEntry>,
+}
+
+/// Wall time of a phase of the run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PhaseTiming {
+    pub name: &'static str,
+    pub elapsed_ms: u64,
+}
+
+impl RunReport {
+    pub fn new() -> Self {
+        Self {
+            started_at: unix_time(),
+            finished_at: None,
+            error: None,
+            block_number: None,
+            input_hash: None,
+            phases: Vec::new(),
+            shards: Vec::new(),
+            rows_scanned: 0,
+            rows_matched: 0,
+            outputs: Vec::new(),
+        }
+    }
+
+    /// Run a phase in its own span and record its duration
+    pub fn phase<T>(&mut self, name: &'static str, run: impl FnOnce() -> T) -> T {
+        let span = tracing::info_span!("phase", name);
+        let _entered = span.enter();
+        let start = Instant::now();
+        let result = run();
+        let elapsed_ms = start.elapsed().as_millis() as u64;
+        info!(elapsed_ms, "Finished {name}");
+        self.phases.push(PhaseTiming { name, elapsed_ms });
+        result
+    }
+
+    /// Record the rows read by the storage scan
+    pub fn record_scan(&mut self, shards: &[ShardScan]) {
+        self.rows_scanned = shards.iter().map(|s| s.rows_scanned).sum();
+        self.rows_matched = shards.iter().map(|s| s.rows_matched).sum();
+        self.shards = shards.to_vec();
+    }
+
+    /// Mark the run as finished, with the error it failed with if any
+    pub fn finish(&mut self, result: &Result<()>) {
+        self.finished_at = Some(unix_time());
+        self.error = result.as_ref().err().map(|e| format!("{e:#}"));
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        Destination::File(path.to_path_buf())
+            .write_with(true, |w| Ok(serde_json::to_writer_pretty(w, self)?))
+            .map_err(|e| eyre::eyre!("Failed to store run report: {}", e))
+    }
+}
+
+impl Default for RunReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Storage slots scanned per token by the run of an earlier report
+///
+/// These are the progress estimates of the next scan. A missing or unreadable report
+/// gives no estimates.
+pub fn previous_rows_scanned(path: &Path) -> HashMap<Felt, u64> {
+    #[derive(Deserialize)]
+    struct PreviousReport {
+        shards: Vec<ShardScan>,
+    }
+
+    let Ok(content) = std::fs::read(path) else {
+        return HashMap::new();
+    };
+    let Ok(report) = serde_json::from_slice::<PreviousReport>(&content) else {
+        return HashMap::new();
+    };
+    let mut rows = HashMap::new();
+    for shard in report.shards {
+        *rows.entry(shard.token).or_default() += shard.rows_scanned;
+    }
+    rows
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_report() -> Result<()> {
+        let mut report = RunReport::new();
+        let value = report.phase("read_balances", || 42);
+        assert_eq!(value, 42);
+        report.record_scan(&[
+            ShardScan {
+                token: Felt::from(1u64),
+                shard: 0,
+                rows_scanned: 10,
+                rows_matched: 2,
+                elapsed_ms: 1,
+            },
+            ShardScan {
+                token: Felt::from(1u64),
+                shard: 1,
+                rows_scanned: 5,
+                rows_matched: 1,
+                elapsed_ms: 1,
+            },
+        ]);
+        report.finish(&Err(eyre::eyre!("Failed to store map as CSV")));
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("report.json");
+        report.write(&path)?;
+        let written: serde_json::Value = serde_json::from_slice(&std::fs::read(&path)?)?;
+        assert_eq!(written["phases"][0]["name"], "read_balances");
+        assert_eq!(written["rows_scanned"], 15);
+        assert_eq!(written["rows_matched"], 3);
+        assert_eq!(written["error"], "Failed to store map as CSV");
+        assert!(written["finished_at"].is_u64());
+
+        // The next run estimates its scan from this one
+        let estimates = previous_rows_scanned(&path);
+        assert_eq!(estimates, HashMap::from([(Felt::from(1u64), 15)]));
+        assert!(previous_rows_scanned(&dir.path().join("missing.json")).is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,616 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use starknet::core::types::Felt;
+use tracing::{debug, info, warn};
+
+use crate::balance::{balance_of, balances_of, head_block_number, Addresses, DEFAULT_DECIMALS};
+
+mod pool;
+pub use pool::ConnectionPool;
+
+mod rpc;
+use rpc::handle_rpc;
+
+/// Queries accepted in a single batch request
+const MAX_BATCH: usize = 1000;
+
+/// Holders returned per page when no limit is given, and the largest page
+const DEFAULT_PAGE: usize = 100;
+const MAX_PAGE: usize = 1000;
+
+/// Holder lists kept in memory, the lists of the oldest blocks are dropped first
+const HOLDER_CACHE: usize = 64;
+
+/// Largest request body read
+const MAX_BODY: u64 = 1 << 20;
+
+/// Serve balances from the Pathfinder DB over a JSON HTTP API and a Starknet JSON-RPC subset
+#[derive(Debug, clap::Args)]
+pub struct ServeArgs {
+    /// Path to the addresses JSON file: the tokens described by the API and the accounts
+    /// listed as holders
+    #[arg(short, long, env = "INPUT_FILE")]
+    input_file: String,
+
+    /// Path to the database
+    #[arg(short, long, env = "DB_PATH")]
+    db_path: String,
+
+    /// Address and port to listen on
+    #[arg(long, env = "LISTEN", default_value = "127.0.0.1:8080")]
+    listen: String,
+
+    /// Read-only database connections, also the number of requests handled at once
+    #[arg(long, default_value_t = 8)]
+    connections: usize,
+}
+
+/// Error answered to a request, with its HTTP status
+#[derive(Debug, PartialEq, Eq)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl ApiError {
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self {
+            status: 400,
+            message: message.into(),
+        }
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self {
+            status: 404,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<eyre::Report> for ApiError {
+    fn from(error: eyre::Report) -> Self {
+        Self {
+            status: 500,
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Accounts of the input with a balance of a token, largest first
+type HolderList = Arc<Vec<(Felt, Felt)>>;
+
+/// Connections and input shared by every request handler
+pub struct ServerState {
+    pool: ConnectionPool,
+    addresses: Addresses,
+    /// Holder lists by token and block, read once for all pages of a block
+    holders: Mutex<HashMap<(Felt, u64), HolderList>>,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenMetadata {
+    address: String,
+    symbol: Option<String>,
+    decimals: u8,
+}
+
+#[derive(Debug, Serialize)]
+struct BalanceResponse {
+    token: String,
+    account: String,
+    balance: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Holder {
+    account: String,
+    balance: String,
+    label: Option<String>,
+    group: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BatchRequest {
+    #[serde(default)]
+    block: Option<u64>,
+    queries: Vec<BatchQuery>,
+}
+
+#[derive(Deserialize)]
+struct BatchQuery {
+    token: Felt,
+    account: Felt,
+}
+
+impl ServerState {
+    pub fn new(pool: ConnectionPool, addresses: Addresses) -> Self {
+        Self {
+            pool,
+            addresses,
+            holders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Answer a request with its JSON body
+    pub fn handle(&self, method: &str, url: &str, body: &str) -> Result<Value, ApiError> {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let params = QueryParams::parse(query);
+
+        match (method, segments.as_slice()) {
+            ("GET", ["health"]) => {
+                let conn = self.pool.get();
+                Ok(json!({ "block_number": head_block_number(&conn) }))
+            }
+            ("GET", ["tokens"]) => Ok(json!(self
+                .addresses
+                .tokens
+                .iter()
+                .map(|token| self.token_metadata(token))
+                .collect::<Vec<_>>())),
+            ("GET", ["tokens", token]) => {
+                let token = parse_address(token)?;
+                self.check_token(&token)?;
+                Ok(json!(self.token_metadata(&token)))
+            }
+            ("GET", ["tokens", token, "balances", account]) => {
+                let (token, account) = (parse_address(token)?, parse_address(account)?);
+                self.balance(&token, &account, params.number("block")?)
+            }
+            ("GET", ["tokens", token, "holders"]) => self.holders(
+                &parse_address(token)?,
+                params.number("block")?,
+                params.number("offset")?.unwrap_or(0) as usize,
+                params.number("limit")?.map_or(DEFAULT_PAGE, |l| l as usize),
+            ),
+            ("POST", ["balances"]) => {
+                let batch: BatchRequest = serde_json::from_str(body)
+                    .map_err(|e| ApiError::bad_request(format!("Invalid batch request: {e}")))?;
+                self.batch(batch)
+            }
+            ("POST", ["rpc"]) => Ok(handle_rpc(&self.pool.get(), &self.addresses.tokens, body)),
+            (_, ["health"] | ["tokens", ..] | ["balances"] | ["rpc"]) => Err(ApiError {
+                status: 405,
+                message: format!("Method {method} not allowed on {path}"),
+            }),
+            _ => Err(ApiError::not_found(format!("No route for {path}"))),
+        }
+    }
+
+    /// Only the tokens of the input are served
+    fn check_token(&self, token: &Felt) -> Result<(), ApiError> {
+        if self.addresses.tokens.contains(token) {
+            Ok(())
+        } else {
+            Err(ApiError::not_found(format!("Unknown token {token:#064x}")))
+        }
+    }
+
+    fn token_metadata(&self, token: &Felt) -> TokenMetadata {
+        let info = self.addresses.token_info.get(token);
+        TokenMetadata {
+            address: format!("{token:#064x}"),
+            symbol: info.and_then(|info| info.symbol.clone()),
+            decimals: info.map_or(DEFAULT_DECIMALS, |info| info.decimals_or_default()),
+        }
+    }
+
+    /// Block the balances are read at: the requested one, or the head block of the database
+    ///
+    /// Databases without block headers are read at their latest state.
+    fn resolve_block(
+        &self,
+        conn: &rusqlite::Connection,
+        block: Option<u64>,
+    ) -> Result<(Option<u64>, u64), ApiError> {
+        let head = head_block_number(conn);
+        match (block, head) {
+            (Some(block), Some(head)) if block > head => Err(ApiError::bad_request(format!(
+                "Block {block} is after the head block {head}"
+            ))),
+            (Some(block), _) => Ok((Some(block), block)),
+            (None, Some(head)) => Ok((Some(head), head)),
+            (None, None) => Ok((None, i64::MAX as u64)),
+        }
+    }
+
+    fn balance(&self, token: &Felt, account: &Felt, block: Option<u64>) -> Result<Value, ApiError> {
+        self.check_token(token)?;
+        let conn = self.pool.get();
+        let (block_number, at) = self.resolve_block(&conn, block)?;
+        let balance = balance_of(&conn, token, account, at)?;
+        Ok(json!({
+            "block_number": block_number,
+            "token": format!("{token:#064x}"),
+            "account": format!("{account:#064x}"),
+            "balance": balance.to_string(),
+        }))
+    }
+
+    /// Balances of several (token, account) pairs, all read at the same block
+    ///
+    /// The accounts of each token are read together.
+    fn batch(&self, batch: BatchRequest) -> Result<Value, ApiError> {
+        if batch.queries.len() > MAX_BATCH {
+            return Err(ApiError::bad_request(format!(
+                "At most {MAX_BATCH} queries per batch, got {}",
+                batch.queries.len()
+            )));
+        }
+        let mut accounts: HashMap<Felt, Vec<Felt>> = HashMap::new();
+        for query in &batch.queries {
+            self.check_token(&query.token)?;
+            accounts.entry(query.token).or_default().push(query.account);
+        }
+
+        let conn = self.pool.get();
+        let (block_number, at) = self.resolve_block(&conn, batch.block)?;
+        let mut read = HashMap::with_capacity(accounts.len());
+        for (token, accounts) in accounts {
+            read.insert(token, balances_of(&conn, &token, &accounts, at)?);
+        }
+        let balances: Vec<BalanceResponse> = batch
+            .queries
+            .iter()
+            .map(|query| BalanceResponse {
+                token: format!("{:#064x}", query.token),
+                account: format!("{:#064x}", query.account),
+                balance: read[&query.token]
+                    .get(&query.account)
+                    .unwrap_or(&Felt::ZERO)
+                    .to_string(),
+            })
+            .collect();
+        Ok(json!({ "block_number": block_number, "balances": balances }))
+    }
+
+    /// Holders of the token at a block, from the cache or read and sorted once
+    ///
+    /// The balances of a block never change, so its list serves every page. Databases
+    /// without block headers are read again on every request.
+    fn holder_list(
+        &self,
+        conn: &rusqlite::Connection,
+        token: &Felt,
+        block_number: Option<u64>,
+        at: u64,
+    ) -> Result<HolderList> {
+        if let Some(block) = block_number {
+            if let Some(list) = self.holders.lock().unwrap().get(&(*token, block)) {
+                return Ok(Arc::clone(list));
+            }
+        }
+
+        let mut balances: Vec<(Felt, Felt)> =
+            balances_of(conn, token, &self.addresses.accounts, at)?
+                .into_iter()
+                .filter(|(_, balance)| *balance != Felt::ZERO)
+                .collect();
+        balances.sort_by(|(a, a_balance), (b, b_balance)| b_balance.cmp(a_balance).then(a.cmp(b)));
+        let list = Arc::new(balances);
+
+        if let Some(block) = block_number {
+            let mut cache = self.holders.lock().unwrap();
+            if cache.len() >= HOLDER_CACHE {
+                let oldest = cache.keys().min_by_key(|(_, block)| *block).copied();
+                if let Some(oldest) = oldest {
+                    cache.remove(&oldest);
+                }
+            }
+            cache.insert((*token, block), Arc::clone(&list));
+        }
+        Ok(list)
+    }
+
+    /// Accounts of the input with a balance of the token, largest first
+    fn holders(
+        &self,
+        token: &Felt,
+        block: Option<u64>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Value, ApiError> {
+        self.check_token(token)?;
+        if limit == 0 || limit > MAX_PAGE {
+            return Err(ApiError::bad_request(format!(
+                "limit must be between 1 and {MAX_PAGE}"
+            )));
+        }
+        let conn = self.pool.get();
+        let (block_number, at) = self.resolve_block(&conn, block)?;
+        let balances = self.holder_list(&conn, token, block_number, at)?;
+
+        let holders: Vec<Holder> = balances
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(account, balance)| {
+                let label = self.addresses.labels.get(account);
+                Holder {
+                    account: format!("{account:#064x}"),
+                    balance: balance.to_string(),
+                    label: label.and_then(|l| l.label.clone()),
+                    group: label.and_then(|l| l.group.clone()),
+                }
+            })
+            .collect();
+        Ok(json!({
+            "block_number": block_number,
+            "token": format!("{token:#064x}"),
+            "total": balances.len(),
+            "offset": offset,
+            "limit": limit,
+            "holders": holders,
+        }))
+    }
+}
+
+/// `key=value` pairs of a query string
+struct QueryParams<'a>(Vec<(&'a str, &'a str)>);
+
+impl<'a> QueryParams<'a> {
+    fn parse(query: &'a str) -> Self {
+        Self(
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .collect(),
+        )
+    }
+
+    fn number(&self, key: &str) -> Result<Option<u64>, ApiError> {
+        self.0
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| {
+                value
+                    .parse()
+                    .map_err(|_| ApiError::bad_request(format!("Invalid {key} '{value}'")))
+            })
+            .transpose()
+    }
+}
+
+fn parse_address(value: &str) -> Result<Felt, ApiError> {
+    Felt::from_hex(value)
+        .map_err(|e| ApiError::bad_request(format!("Invalid address '{value}': {e}")))
+}
+
+/// Answer one HTTP request
+fn respond(state: &ServerState, mut request: tiny_http::Request) {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let url = request.url().to_string();
+
+    let mut body = String::new();
+    let result = match request.as_reader().take(MAX_BODY).read_to_string(&mut body) {
+        Ok(_) => state.handle(&method, &url, &body),
+        Err(e) => Err(ApiError::bad_request(format!(
+            "Failed to read request body: {e}"
+        ))),
+    };
+    let (status, value) = match result {
+        Ok(value) => (200, value),
+        Err(error) => {
+            if error.status >= 500 {
+                warn!(%method, %url, error = %error.message, "Request failed");
+            }
+            (error.status, json!({ "error": error.message }))
+        }
+    };
+
+    let header = tiny_http::Header::from_bytes("Content-Type", "application/json").unwrap();
+    let response = tiny_http::Response::from_string(value.to_string())
+        .with_status_code(status)
+        .with_header(header);
+    if let Err(e) = request.respond(response) {
+        warn!(%method, %url, "Failed to send response: {}", e);
+    }
+    debug!(
+        %method,
+        %url,
+        status,
+        elapsed_ms = start.elapsed().as_millis() as u64,
+        "Request handled"
+    );
+}
+
+/// Serve the balances of the database until the process is stopped
+pub fn run_serve(args: &ServeArgs) -> Result<()> {
+    let addresses = Addresses::load(&args.input_file)?;
+
+    let pool = ConnectionPool::open(&args.db_path, args.connections)?;
+    let state = ServerState::new(pool, addresses);
+    let server = tiny_http::Server::http(&args.listen)
+        .map_err(|e| eyre::eyre!("Failed to listen on '{}': {}", args.listen, e))?;
+    info!(
+        address = %args.listen,
+        connections = state.pool.size(),
+        tokens = state.addresses.tokens.len(),
+        accounts = state.addresses.accounts.len(),
+        "Serving balances"
+    );
+
+    // One handler per connection, each waiting for the next request
+    std::thread::scope(|scope| {
+        for _ in 0..state.pool.size() {
+            scope.spawn(|| {
+                for request in server.incoming_requests() {
+                    respond(&state, request);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use rusqlite::Connection;
+
+    use crate::balance::{create_storage_tables, insert_balance, AccountLabel, TokenInfo};
+
+    fn felt(value: u64) -> Felt {
+        Felt::from(value)
+    }
+
+    /// Database with balances of accounts 1, 2 and 3 of token 0x100, updated at blocks 10 and 20
+    fn create_server() -> eyre::Result<(ServerState, tempfile::NamedTempFile)> {
+        let file = tempfile::NamedTempFile::new()?;
+        let conn = Connection::open(file.path())?;
+        conn.execute_batch(
+            "CREATE TABLE block_headers (number INTEGER PRIMARY KEY);
+            INSERT INTO block_headers VALUES (10), (20);",
+        )?;
+        create_storage_tables(&conn)?;
+        for (account, block, balance) in [(1, 10, 500), (1, 20, 700), (2, 10, 900), (3, 20, 300)] {
+            insert_balance(&conn, &felt(0x100), &felt(account), balance, block)?;
+        }
+
+        let addresses = Addresses {
+            accounts: vec![felt(1), felt(2), felt(3), felt(4)],
+            tokens: vec![felt(0x100)],
+            labels: HashMap::from([(
+                felt(2),
+                AccountLabel {
+                    label: Some("treasury".to_string()),
+                    group: None,
+                },
+            )]),
+            token_info: HashMap::from([(
+                felt(0x100),
+                TokenInfo {
+                    symbol: Some("TKN".to_string()),
+                    decimals: Some(6),
+                },
+            )]),
+        };
+        let pool = ConnectionPool::open(file.path().to_str().unwrap(), 2)?;
+        Ok((ServerState::new(pool, addresses), file))
+    }
+
+    #[test]
+    fn test_balance_routes() -> eyre::Result<()> {
+        let (state, _file) = create_server()?;
+        let get = |url: &str| state.handle("GET", url, "");
+
+        assert_eq!(get("/health").unwrap()["block_number"], 20);
+        let tokens = get("/tokens").unwrap();
+        assert_eq!(tokens[0]["symbol"], "TKN");
+        assert_eq!(tokens[0]["decimals"], 6);
+        assert_eq!(get("/tokens/0x200").unwrap_err().status, 404);
+
+        let latest = get("/tokens/0x100/balances/0x1").unwrap();
+        assert_eq!(latest["block_number"], 20);
+        assert_eq!(latest["balance"], "700");
+        assert_eq!(
+            get("/tokens/0x100/balances/0x1?block=15").unwrap()["balance"],
+            "500"
+        );
+        assert_eq!(
+            get("/tokens/0x100/balances/0x1?block=5").unwrap()["balance"],
+            "0"
+        );
+        assert_eq!(
+            get("/tokens/0x100/balances/0x1?block=30")
+                .unwrap_err()
+                .status,
+            400
+        );
+        assert_eq!(get("/tokens/0x100/balances/xyz").unwrap_err().status, 400);
+        assert_eq!(get("/tokens/0x200/balances/0x1").unwrap_err().status, 404);
+
+        let batch = state
+            .handle(
+                "POST",
+                "/balances",
+                r#"{"block": 10, "queries": [
+                    {"token": "0x100", "account": "0x1"},
+                    {"token": "0x100", "account": "0x3"}
+                ]}"#,
+            )
+            .unwrap();
+        assert_eq!(batch["block_number"], 10);
+        assert_eq!(batch["balances"][0]["balance"], "500");
+        assert_eq!(batch["balances"][1]["balance"], "0");
+        assert_eq!(
+            state.handle("POST", "/balances", "{").unwrap_err().status,
+            400
+        );
+        let unknown = r#"{"queries": [{"token": "0x200", "account": "0x1"}]}"#;
+        assert_eq!(
+            state
+                .handle("POST", "/balances", unknown)
+                .unwrap_err()
+                .status,
+            404
+        );
+        assert_eq!(
+            state.handle("DELETE", "/tokens", "").unwrap_err().status,
+            405
+        );
+        assert_eq!(get("/accounts").unwrap_err().status, 404);
+
+        let rpc = state
+            .handle(
+                "POST",
+                "/rpc",
+                r#"{"jsonrpc": "2.0", "id": 1, "method": "starknet_blockNumber"}"#,
+            )
+            .unwrap();
+        assert_eq!(rpc["result"], 20);
+        Ok(())
+    }
+
+    #[test]
+    fn test_holder_pages() -> eyre::Result<()> {
+        let (state, _file) = create_server()?;
+        let get = |url: &str| state.handle("GET", url, "").unwrap();
+
+        let first = get("/tokens/0x100/holders?limit=2");
+        assert_eq!(first["total"], 3);
+        assert_eq!(first["holders"][0]["balance"], "900");
+        assert_eq!(first["holders"][0]["label"], "treasury");
+        assert_eq!(first["holders"][1]["balance"], "700");
+        let second = get("/tokens/0x100/holders?limit=2&offset=2");
+        assert_eq!(second["holders"].as_array().unwrap().len(), 1);
+        assert_eq!(second["holders"][0]["balance"], "300");
+
+        let earlier = get("/tokens/0x100/holders?block=10");
+        assert_eq!(earlier["total"], 2);
+        assert_eq!(earlier["block_number"], 10);
+
+        // Every page of a block reads the balances once
+        assert_eq!(state.holders.lock().unwrap().len(), 2);
+        assert!(state
+            .holders
+            .lock()
+            .unwrap()
+            .contains_key(&(felt(0x100), 20)));
+        assert_eq!(
+            state
+                .handle("GET", "/tokens/0x200/holders", "")
+                .unwrap_err()
+                .status,
+            404
+        );
+        assert_eq!(
+            state
+                .handle("GET", "/tokens/0x100/holders?limit=0", "")
+                .unwrap_err()
+                .status,
+            400
+        );
+        Ok(())
+    }
+}
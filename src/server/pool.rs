@@ -0,0 +1,104 @@
+use std::ops::Deref;
+use std::sync::{Condvar, Mutex};
+
+use eyre::Result;
+use rusqlite::{Connection, OpenFlags};
+
+/// Fixed set of read-only connections to the Pathfinder DB, shared by the request handlers
+pub struct ConnectionPool {
+    idle: Mutex<Vec<Connection>>,
+    returned: Condvar,
+    size: usize,
+}
+
+impl ConnectionPool {
+    /// Open `size` read-only connections to the database at `db_path`
+    pub fn open(db_path: &str, size: usize) -> Result<Self> {
+        let connections = (0..size.max(1))
+            .map(|_| {
+                Connection::open_with_flags(
+                    db_path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                )
+                .map_err(|e| eyre::eyre!("Failed to open database '{}': {}", db_path, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            size: connections.len(),
+            idle: Mutex::new(connections),
+            returned: Condvar::new(),
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Take a connection, waiting for one to be returned when all are in use
+    pub fn get(&self) -> PooledConnection<'_> {
+        let mut idle = self.idle.lock().unwrap();
+        loop {
+            if let Some(conn) = idle.pop() {
+                return PooledConnection {
+                    pool: self,
+                    conn: Some(conn),
+                };
+            }
+            idle = self.returned.wait(idle).unwrap();
+        }
+    }
+}
+
+/// A connection taken from the pool, returned to it when dropped
+pub struct PooledConnection<'a> {
+    pool: &'a ConnectionPool,
+    conn: Option<Connection>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.idle.lock().unwrap().push(conn);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_pool() -> eyre::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        Connection::open(file.path())?.execute_batch("CREATE TABLE t (x INTEGER)")?;
+        let pool = ConnectionPool::open(file.path().to_str().unwrap(), 2)?;
+        assert_eq!(pool.size(), 2);
+
+        let first = pool.get();
+        let second = pool.get();
+        assert!(first.execute("INSERT INTO t VALUES (1)", []).is_err());
+        assert_eq!(pool.idle.lock().unwrap().len(), 0);
+        drop(first);
+        drop(second);
+        assert_eq!(pool.idle.lock().unwrap().len(), 2);
+
+        // A waiting handler gets the connection returned by another one
+        let held = pool.get();
+        std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| pool.get().query_row("SELECT 1", [], |row| row.get(0)));
+            let _other = pool.get();
+            drop(held);
+            assert_eq!(waiting.join().unwrap(), Ok(1i64));
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+use rusqlite::{Connection, OptionalExtension};
+use serde_json::{json, Value};
+use starknet::core::types::Felt;
+use starknet::core::utils::starknet_keccak;
+
+use crate::balance::{balance_storage_key, head_block_number, storage_value};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+/// Starknet API error codes
+const CONTRACT_NOT_FOUND: i64 = 20;
+const BLOCK_NOT_FOUND: i64 = 24;
+const NO_BLOCKS: i64 = 32;
+const CONTRACT_ERROR: i64 = 40;
+
+/// Error of a JSON-RPC call
+#[derive(Debug, PartialEq, Eq)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+}
+
+impl From<eyre::Report> for RpcError {
+    fn from(error: eyre::Report) -> Self {
+        Self::new(INTERNAL_ERROR, error.to_string())
+    }
+}
+
+/// Answer a JSON-RPC request or batch of requests with the subset of the Starknet API
+/// served from the database: `starknet_blockNumber`, `starknet_getStorageAt`, and
+/// `starknet_call` of `balanceOf` / `balance_of` on one of `tokens`
+pub fn handle_rpc(conn: &Connection, tokens: &[Felt], body: &str) -> Value {
+    match serde_json::from_str(body) {
+        Ok(Value::Array(requests)) if !requests.is_empty() => {
+            Value::Array(requests.iter().map(|r| answer(conn, tokens, r)).collect())
+        }
+        Ok(request) => answer(conn, tokens, &request),
+        Err(e) => response(
+            Value::Null,
+            Err(RpcError::new(PARSE_ERROR, format!("Parse error: {e}"))),
+        ),
+    }
+}
+
+fn answer(conn: &Connection, tokens: &[Felt], request: &Value) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let version = request.get("jsonrpc").and_then(Value::as_str);
+    let result = match (version, request.get("method").and_then(Value::as_str)) {
+        (Some("2.0"), Some(method)) => call(
+            conn,
+            tokens,
+            method,
+            request.get("params").unwrap_or(&Value::Null),
+        ),
+        _ => Err(RpcError::new(INVALID_REQUEST, "Invalid request")),
+    };
+    response(id, result)
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": error.code, "message": error.message },
+        }),
+    }
+}
+
+fn call(
+    conn: &Connection,
+    tokens: &[Felt],
+    method: &str,
+    params: &Value,
+) -> Result<Value, RpcError> {
+    match method {
+        "starknet_blockNumber" => head_block_number(conn)
+            .map(|number| json!(number))
+            .ok_or_else(|| RpcError::new(NO_BLOCKS, "There are no blocks")),
+        "starknet_getStorageAt" => {
+            let contract = felt(param(params, 0, "contract_address"), "contract_address")?;
+            let key = felt(param(params, 1, "key"), "key")?;
+            let block = resolve_block_id(conn, param(params, 2, "block_id"))?;
+            ensure_deployed(conn, &contract, block)?;
+            let value = storage_value(conn, &contract, &key, block)?;
+            Ok(json!(format!("{value:#x}")))
+        }
+        "starknet_call" => {
+            let request = param(params, 0, "request")
+                .ok_or_else(|| RpcError::invalid_params("Missing request"))?;
+            let contract = felt(request.get("contract_address"), "contract_address")?;
+            let selector = felt(request.get("entry_point_selector"), "entry_point_selector")?;
+            let calldata: Vec<Felt> = request
+                .get("calldata")
+                .map(|calldata| serde_json::from_value(calldata.clone()))
+                .transpose()
+                .map_err(|e| RpcError::invalid_params(format!("Invalid calldata: {e}")))?
+                .unwrap_or_default();
+            let block = resolve_block_id(conn, param(params, 1, "block_id"))?;
+            ensure_deployed(conn, &contract, block)?;
+            balance_call(conn, tokens, &contract, &selector, &calldata, block)
+        }
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, "Method not found")),
+    }
+}
+
+/// `balanceOf(account)` of an ERC20 keeping its balances in the `ERC20_balances` mapping,
+/// as the Cairo 0 and Cairo 1 OpenZeppelin layouts do: the `u256` low and high words are
+/// stored at the account's key and the next one
+///
+/// Only the configured `tokens` are known to have that layout, calls on any other contract
+/// fail instead of reading unrelated storage.
+fn balance_call(
+    conn: &Connection,
+    tokens: &[Felt],
+    contract: &Felt,
+    selector: &Felt,
+    calldata: &[Felt],
+    block: u64,
+) -> Result<Value, RpcError> {
+    if !tokens.contains(contract) {
+        return Err(RpcError::new(
+            CONTRACT_ERROR,
+            format!("Contract {contract:#x} is not one of the served ERC20 tokens"),
+        ));
+    }
+    let supported = ["balanceOf", "balance_of"]
+        .iter()
+        .any(|name| starknet_keccak(name.as_bytes()) == *selector);
+    if !supported {
+        return Err(RpcError::new(
+            CONTRACT_ERROR,
+            format!("Only balanceOf and balance_of are answered, not selector {selector:#x}"),
+        ));
+    }
+    let [account] = calldata else {
+        return Err(RpcError::new(
+            CONTRACT_ERROR,
+            format!("balanceOf takes 1 argument, got {}", calldata.len()),
+        ));
+    };
+
+    let key = balance_storage_key(account);
+    let low = storage_value(conn, contract, &key, block)?;
+    let high = storage_value(conn, contract, &(key + Felt::ONE), block)?;
+    Ok(json!([format!("{low:#x}"), format!("{high:#x}")]))
+}
+
+/// Positional or named parameter
+fn param<'a>(params: &'a Value, index: usize, name: &str) -> Option<&'a Value> {
+    match params {
+        Value::Array(params) => params.get(index),
+        params => params.get(name),
+    }
+}
+
+fn felt(value: Option<&Value>, name: &str) -> Result<Felt, RpcError> {
+    let value = value.ok_or_else(|| RpcError::invalid_params(format!("Missing {name}")))?;
+    serde_json::from_value(value.clone())
+        .map_err(|e| RpcError::invalid_params(format!("Invalid {name}: {e}")))
+}
+
+/// Block a `block_id` refers to: `latest` (`pending` is read as latest), a block number or
+/// a block hash
+///
+/// Databases without block headers are read at their latest state.
+fn resolve_block_id(conn: &Connection, block_id: Option<&Value>) -> Result<u64, RpcError> {
+    let head = head_block_number(conn);
+    let latest = head.unwrap_or(i64::MAX as u64);
+    let block_id = block_id.ok_or_else(|| RpcError::invalid_params("Missing block_id"))?;
+    if let Some(tag) = block_id.as_str() {
+        return match tag {
+            "latest" | "pending" | "pre_confirmed" => Ok(latest),
+            _ => Err(RpcError::invalid_params(format!(
+                "Invalid block tag '{tag}'"
+            ))),
+        };
+    }
+    if let Some(number) = block_id.get("block_number") {
+        let number = number
+            .as_u64()
+            .ok_or_else(|| RpcError::invalid_params("Invalid block_number"))?;
+        return match head {
+            Some(head) if number > head => Err(RpcError::new(BLOCK_NOT_FOUND, "Block not found")),
+            _ => Ok(number),
+        };
+    }
+    let hash = felt(block_id.get("block_hash"), "block_id")?;
+    conn.query_row(
+        "SELECT number FROM block_headers WHERE hash = ?1",
+        [hash.to_bytes_be().to_vec()],
+        |row| row.get::<_, i64>(0),
+    )
+    .optional()
+    .map_err(|e| eyre::eyre!("Failed to query block header: {}", e))?
+    .map(|number| number as u64)
+    .ok_or_else(|| RpcError::new(BLOCK_NOT_FOUND, "Block not found"))
+}
+
+/// Fail with `CONTRACT_NOT_FOUND` when the contract was not deployed at the block
+fn ensure_deployed(conn: &Connection, contract: &Felt, block: u64) -> Result<(), RpcError> {
+    conn.query_row(
+        "SELECT 1 FROM contract_updates
+         WHERE contract_address = ?1 AND block_number <= ?2 LIMIT 1",
+        rusqlite::params![contract.to_bytes_be().to_vec(), block as i64],
+        |_| Ok(()),
+    )
+    .optional()
+    .map_err(|e| eyre::eyre!("Failed to query contract deployment: {}", e))?
+    .ok_or_else(|| RpcError::new(CONTRACT_NOT_FOUND, "Contract not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::{create_storage_tables, insert_storage};
+
+    /// Token 0x100 deployed at block 1, with account 0x1 holding 2^128 + 5 from block 2
+    fn create_database() -> eyre::Result<Connection> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE block_headers (number INTEGER PRIMARY KEY, hash BLOB);
+            CREATE TABLE contract_updates (block_number INTEGER, contract_address BLOB, class_hash BLOB);",
+        )?;
+        create_storage_tables(&conn)?;
+        let bytes = |felt: Felt| felt.to_bytes_be().to_vec();
+        for number in 1..=3u64 {
+            conn.execute(
+                "INSERT INTO block_headers VALUES (?1, ?2)",
+                rusqlite::params![number, bytes(Felt::from(0xb00 + number))],
+            )?;
+        }
+        conn.execute(
+            "INSERT INTO contract_updates VALUES (1, ?1, ?2)",
+            [bytes(Felt::from(0x100)), bytes(Felt::from(0xc1a55))],
+        )?;
+        let token = Felt::from(0x100);
+        let key = balance_storage_key(&Felt::ONE);
+        insert_storage(&conn, &token, &key, &Felt::from(5), 2)?;
+        insert_storage(&conn, &token, &(key + Felt::ONE), &Felt::ONE, 2)?;
+        Ok(conn)
+    }
+
+    fn rpc(conn: &Connection, method: &str, params: Value) -> Value {
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        handle_rpc(conn, &[Felt::from(0x100u64)], &request.to_string())
+    }
+
+    #[test]
+    fn test_storage_and_balance_calls() -> eyre::Result<()> {
+        let conn = create_database()?;
+        assert_eq!(rpc(&conn, "starknet_blockNumber", json!([]))["result"], 3);
+
+        let key = format!("{:#x}", balance_storage_key(&Felt::ONE));
+        let storage = rpc(
+            &conn,
+            "starknet_getStorageAt",
+            json!(["0x100", key, "latest"]),
+        );
+        assert_eq!(storage["result"], "0x5");
+        let named = rpc(
+            &conn,
+            "starknet_getStorageAt",
+            json!({ "contract_address": "0x100", "key": key, "block_id": { "block_number": 1 } }),
+        );
+        assert_eq!(named["result"], "0x0");
+
+        let call = |selector: &str, block_id: Value| {
+            let request = json!({
+                "contract_address": "0x100",
+                "entry_point_selector": format!("{:#x}", starknet_keccak(selector.as_bytes())),
+                "calldata": ["0x1"],
+            });
+            rpc(&conn, "starknet_call", json!([request, block_id]))
+        };
+        let balance = call("balanceOf", json!({ "block_hash": "0xb02" }));
+        assert_eq!(balance["result"], json!(["0x5", "0x1"]));
+        assert_eq!(call("balance_of", json!("latest"))["result"][0], "0x5");
+        assert_eq!(
+            call("transfer", json!("latest"))["error"]["code"],
+            CONTRACT_ERROR
+        );
+
+        // A deployed contract that is not a served token has an unknown storage layout
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "starknet_call",
+            "params": [{
+                "contract_address": "0x100",
+                "entry_point_selector": format!("{:#x}", starknet_keccak(b"balanceOf")),
+                "calldata": ["0x1"],
+            }, "latest"],
+        });
+        let response = handle_rpc(&conn, &[], &request.to_string());
+        assert_eq!(response["error"]["code"], CONTRACT_ERROR);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_errors() -> eyre::Result<()> {
+        let conn = create_database()?;
+        let code = |response: Value| response["error"]["code"].as_i64().unwrap();
+
+        assert_eq!(code(handle_rpc(&conn, &[], "{")), PARSE_ERROR);
+        assert_eq!(
+            code(handle_rpc(&conn, &[], r#"{"id": 1}"#)),
+            INVALID_REQUEST
+        );
+        assert_eq!(
+            code(rpc(&conn, "starknet_chainId", json!([]))),
+            METHOD_NOT_FOUND
+        );
+        assert_eq!(
+            code(rpc(&conn, "starknet_getStorageAt", json!(["0x100"]))),
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            code(rpc(
+                &conn,
+                "starknet_getStorageAt",
+                json!(["0x200", "0x1", "latest"])
+            )),
+            CONTRACT_NOT_FOUND
+        );
+        assert_eq!(
+            code(rpc(
+                &conn,
+                "starknet_getStorageAt",
+                json!(["0x100", "0x1", { "block_number": 9 }])
+            )),
+            BLOCK_NOT_FOUND
+        );
+
+        let batch = handle_rpc(
+            &conn,
+            &[],
+            r#"[{"jsonrpc": "2.0", "id": 7, "method": "starknet_blockNumber"}, {"id": 8}]"#,
+        );
+        assert_eq!(batch[0]["id"], 7);
+        assert_eq!(batch[0]["result"], 3);
+        assert_eq!(code(batch[1].clone()), INVALID_REQUEST);
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::BTreeMap;
+
+use bigdecimal::{BigDecimal, ToPrimitive, Zero};
+use num_bigint::{BigInt, BigUint};
+use serde::Serialize;
+use starknet::core::types::Felt;
+use tracing::info;
+
+use crate::balance::BalanceSnapshot;
+
+/// Percentiles reported for every token
+pub const PERCENTILES: [u8; 5] = [10, 25, 75, 90, 99];
+
+/// Distribution statistics of the holders of a single token
+///
+/// Only accounts with a non-zero balance count as holders. Amounts are in whole
+/// token units, rounded to the token decimals.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenStats {
+    pub holders: usize,
+    #[serde(serialize_with = "serialize_decimal")]
+    pub sum: BigDecimal,
+    #[serde(serialize_with = "serialize_decimal")]
+    pub mean: BigDecimal,
+    #[serde(serialize_with = "serialize_decimal")]
+    pub median: BigDecimal,
+    /// percentile -> balance, using the nearest-rank method
+    #[serde(serialize_with = "serialize_percentiles")]
+    pub percentiles: BTreeMap<u8, BigDecimal>,
+    /// Fraction of the supply held by the 10 largest holders
+    pub top10_share: f64,
+    /// Fraction of the supply held by the 100 largest holders
+    pub top100_share: f64,
+    /// Gini coefficient, 0 for a perfectly even and 1 for a fully concentrated distribution
+    pub gini: f64,
+    /// Smallest number of holders that together hold more than half of the supply
+    pub nakamoto: usize,
+}
+
+/// Compute holder statistics for every token in the snapshot
+pub fn compute_stats(snapshot: &BalanceSnapshot) -> BTreeMap<Felt, TokenStats> {
+    snapshot
+        .balances
+        .iter()
+        .map(|(token, balances)| {
+            let balances: Vec<BigUint> = balances
+                .values()
+                .filter(|balance| **balance != Felt::ZERO)
+                .map(Felt::to_biguint)
+                .collect();
+            (*token, token_stats(balances, snapshot.decimals_of(token)))
+        })
+        .collect()
+}
+
+fn token_stats(mut balances: Vec<BigUint>, decimals: u8) -> TokenStats {
+    // Ascending order, the largest holders are at the end
+    balances.sort();
+
+    let holders = balances.len();
+    let units = |raw: &BigUint| BigDecimal::new(BigInt::from(raw.clone()), decimals as i64);
+    let total: BigUint = balances.iter().sum();
+
+    if holders == 0 {
+        return TokenStats {
+            holders,
+            sum: BigDecimal::zero(),
+            mean: BigDecimal::zero(),
+            median: BigDecimal::zero(),
+            percentiles: PERCENTILES
+                .iter()
+                .map(|p| (*p, BigDecimal::zero()))
+                .collect(),
+            top10_share: 0.0,
+            top100_share: 0.0,
+            gini: 0.0,
+            nakamoto: 0,
+        };
+    }
+
+    let sum = units(&total);
+    let mean = (&sum / BigDecimal::from(holders as u64)).round(decimals as i64);
+    let median = if holders % 2 == 1 {
+        units(&balances[holders / 2])
+    } else {
+        let middle = &balances[holders / 2 - 1] + &balances[holders / 2];
+        (units(&middle) / BigDecimal::from(2)).round(decimals as i64)
+    };
+    let percentiles = PERCENTILES
+        .iter()
+        .map(|p| {
+            // Nearest rank: the smallest value with at least p% of the holders at or below it
+            let rank = (*p as usize * holders).div_ceil(100).max(1);
+            (*p, units(&balances[rank - 1]))
+        })
+        .collect();
+
+    let share_of_top = |n: usize| {
+        let top: BigUint = balances.iter().rev().take(n).sum();
+        ratio(&top, &total)
+    };
+
+    // Gini = 2 * sum(i * x_i) / (n * sum(x)) - (n + 1) / n, with x ascending and i from 1
+    let weighted: BigUint = balances
+        .iter()
+        .enumerate()
+        .map(|(i, balance)| balance * (i + 1))
+        .sum();
+    let n = holders as f64;
+    let gini = if total.is_zero() {
+        0.0
+    } else {
+        2.0 * ratio(&weighted, &(&total * holders)) - (n + 1.0) / n
+    };
+
+    let mut nakamoto = 0;
+    let mut running = BigUint::zero();
+    for balance in balances.iter().rev() {
+        running += balance;
+        nakamoto += 1;
+        if &running * 2u32 > total {
+            break;
+        }
+    }
+
+    TokenStats {
+        holders,
+        sum,
+        mean,
+        median,
+        percentiles,
+        top10_share: share_of_top(10),
+        top100_share: share_of_top(100),
+        gini,
+        nakamoto,
+    }
+}
+
+fn ratio(numerator: &BigUint, denominator: &BigUint) -> f64 {
+    if denominator.is_zero() {
+        return 0.0;
+    }
+    numerator.to_f64().unwrap_or(0.0) / denominator.to_f64().unwrap_or(f64::INFINITY)
+}
+
+/// Log a summary of the statistics of every token
+pub fn print_summary(snapshot: &BalanceSnapshot, stats: &BTreeMap<Felt, TokenStats>) {
+    for (token, stats) in stats {
+        let symbol = snapshot
+            .token_info
+            .get(token)
+            .and_then(|info| info.symbol.as_deref());
+        let percentiles: Vec<String> = stats
+            .percentiles
+            .iter()
+            .map(|(p, value)| format!("p{p}: {value}"))
+            .collect();
+        info!(
+            token = %format!("{token:#064x}"),
+            symbol,
+            holders = stats.holders,
+            sum = %stats.sum,
+            mean = %stats.mean,
+            median = %stats.median,
+            percentiles = %percentiles.join(", "),
+            top10_share = stats.top10_share,
+            top100_share = stats.top100_share,
+            gini = stats.gini,
+            nakamoto = stats.nakamoto,
+            "Holder statistics"
+        );
+    }
+}
+
+fn serialize_decimal<S>(value: &BigDecimal, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(&value.to_string())
+}
+
+fn serialize_percentiles<S>(
+    percentiles: &BTreeMap<u8, BigDecimal>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_map(
+        percentiles
+            .iter()
+            .map(|(p, value)| (format!("p{p}"), value.to_string())),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn raw(values: &[u64]) -> Vec<BigUint> {
+        values.iter().map(|v| BigUint::from(*v)).collect()
+    }
+
+    #[test]
+    fn test_token_stats() -> eyre::Result<()> {
+        let stats = token_stats(raw(&[400, 100, 300, 200]), 2);
+
+        assert_eq!(stats.holders, 4);
+        assert_eq!(stats.sum, BigDecimal::from(10));
+        assert_eq!(stats.mean, BigDecimal::from_str("2.5")?);
+        assert_eq!(stats.median, BigDecimal::from_str("2.5")?);
+        assert_eq!(stats.percentiles[&25], BigDecimal::from(1));
+        assert_eq!(stats.percentiles[&75], BigDecimal::from(3));
+        assert_eq!(stats.percentiles[&99], BigDecimal::from(4));
+        assert_eq!(stats.top10_share, 1.0);
+        // 400 + 300 = 700 > 500
+        assert_eq!(stats.nakamoto, 2);
+        assert!((stats.gini - 0.25).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[test]
+    fn test_even_and_concentrated_distributions() {
+        let even = token_stats(raw(&[5, 5, 5, 5]), 0);
+        assert!(even.gini.abs() < 1e-9);
+        assert_eq!(even.nakamoto, 3);
+
+        let concentrated = token_stats(raw(&[0, 0, 0, 0, 1_000]), 0);
+        assert!((concentrated.gini - 0.8).abs() < 1e-9);
+        assert_eq!(concentrated.nakamoto, 1);
+    }
+
+    #[test]
+    fn test_zero_balances_are_not_holders() {
+        let token = Felt::from(1u64);
+        let snapshot = BalanceSnapshot {
+            balances: std::collections::HashMap::from([(
+                token,
+                std::collections::HashMap::from([
+                    (Felt::from(10u64), Felt::ZERO),
+                    (Felt::from(11u64), Felt::from(7u64)),
+                ]),
+            )]),
+            ..Default::default()
+        };
+
+        let stats = compute_stats(&snapshot);
+        assert_eq!(stats[&token].holders, 1);
+
+        let empty = token_stats(Vec::new(), 18);
+        assert_eq!(empty.holders, 0);
+        assert_eq!(empty.nakamoto, 0);
+    }
+}
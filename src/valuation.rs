@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use bigdecimal::{BigDecimal, Zero};
+use eyre::Result;
+use num_bigint::BigInt;
+use serde::Deserialize;
+use starknet::core::types::Felt;
+
+use crate::balance::BalanceSnapshot;
+
+/// Number of decimal places fiat values are rounded to in the outputs
+pub const FIAT_SCALE: i64 = 2;
+
+/// token -> price key (block number or timestamp) -> price of one whole token
+///
+/// Prices without a key are stored under key 0, so they apply to any snapshot.
+pub type Prices = HashMap<Felt, BTreeMap<u64, BigDecimal>>;
+
+/// A price is either a single value or a map of block number / timestamp to value
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PriceSpec {
+    Flat(PriceValue),
+    Keyed(HashMap<String, PriceValue>),
+}
+
+/// Prices can be given as JSON strings (preferred, no precision loss) or numbers
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PriceValue {
+    Text(String),
+    Number(f64),
+}
+
+impl PriceValue {
+    fn to_decimal(&self) -> Result<BigDecimal> {
+        let text = match self {
+            PriceValue::Text(text) => text.clone(),
+            PriceValue::Number(number) => number.to_string(),
+        };
+        BigDecimal::from_str(&text).map_err(|e| eyre::eyre!("Invalid price '{}': {}", text, e))
+    }
+}
+
+/// Fiat values computed from a snapshot and a price file
+#[derive(Debug, Clone, Default)]
+pub struct Valuation {
+    /// token -> account -> value
+    pub values: HashMap<Felt, HashMap<Felt, BigDecimal>>,
+    /// token -> total value held by all accounts
+    pub token_totals: BTreeMap<Felt, BigDecimal>,
+    /// account -> total value of all tokens held
+    pub portfolio_totals: BTreeMap<Felt, BigDecimal>,
+}
+
+impl Valuation {
+    /// Returns the value of an account's balance of a token, if the token has a price
+    pub fn value_of(&self, token: &Felt, account: &Felt) -> Option<&BigDecimal> {
+        self.values.get(token).and_then(|m| m.get(account))
+    }
+}
+
+/// Load a price file mapping token addresses to prices
+pub fn load_prices(path: &str) -> Result<Prices> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| eyre::eyre!("Failed to read price file '{}': {}", path, e))?;
+    parse_prices(&content).map_err(|e| eyre::eyre!("Failed to parse price file '{}': {}", path, e))
+}
+
+fn parse_prices(content: &str) -> Result<Prices> {
+    let raw: HashMap<Felt, PriceSpec> = serde_json::from_str(content)?;
+
+    let mut prices = Prices::with_capacity(raw.len());
+    for (token, spec) in raw {
+        let series = match spec {
+            PriceSpec::Flat(value) => BTreeMap::from([(0, value.to_decimal()?)]),
+            PriceSpec::Keyed(values) => values
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = key
+                        .parse::<u64>()
+                        .map_err(|e| eyre::eyre!("Invalid price key '{}': {}", key, e))?;
+                    Ok((key, value.to_decimal()?))
+                })
+                .collect::<Result<_>>()?,
+        };
+        prices.insert(token, series);
+    }
+    Ok(prices)
+}
+
+/// Select the price of a token at the given key, or the latest price if no key is given
+fn price_at(prices: &Prices, token: &Felt, at: Option<u64>) -> Option<BigDecimal> {
+    let series = prices.get(token)?;
+    let entry = match at {
+        Some(at) => series.range(..=at).next_back(),
+        None => series.iter().next_back(),
+    };
+    entry.map(|(_, price)| price.clone())
+}
+
+/// Convert a raw balance into whole token units using the token decimals
+pub fn to_units(balance: &Felt, decimals: u8) -> BigDecimal {
+    BigDecimal::new(BigInt::from(balance.to_biguint()), decimals as i64)
+}
+
+/// Format a fiat value with [`FIAT_SCALE`] decimal places
+pub fn format_value(value: &BigDecimal) -> String {
+    value.round(FIAT_SCALE).with_scale(FIAT_SCALE).to_string()
+}
+
+/// Compute per-account, per-token and per-portfolio fiat values
+///
+/// Tokens without a price are left out of the valuation.
+pub fn value_snapshot(snapshot: &BalanceSnapshot, prices: &Prices, at: Option<u64>) -> Valuation {
+    let mut valuation = Valuation::default();
+
+    for (token, balances) in &snapshot.balances {
+        let Some(price) = price_at(prices, token, at) else {
+            continue;
+        };
+        let decimals = snapshot.decimals_of(token);
+
+        let mut token_total = BigDecimal::zero();
+        let mut token_values = HashMap::with_capacity(balances.len());
+        for (account, balance) in balances {
+            let value = to_units(balance, decimals) * &price;
+            token_total += &value;
+            *valuation
+                .portfolio_totals
+                .entry(*account)
+                .or_insert_with(BigDecimal::zero) += &value;
+            token_values.insert(*account, value);
+        }
+
+        valuation.token_totals.insert(*token, token_total);
+        valuation.values.insert(*token, token_values);
+    }
+
+    valuation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::TokenInfo;
+
+    #[test]
+    fn test_parse_prices() -> Result<()> {
+        let prices = parse_prices(
+            r#"{
+                "0x1": "3150.25",
+                "0x2": { "100": "1.5", "200": 2 }
+            }"#,
+        )?;
+
+        let eth = Felt::from(1u64);
+        let strk = Felt::from(2u64);
+        assert_eq!(
+            price_at(&prices, &eth, None),
+            Some(BigDecimal::from_str("3150.25")?)
+        );
+        assert_eq!(
+            price_at(&prices, &eth, Some(5)),
+            Some(BigDecimal::from_str("3150.25")?)
+        );
+        assert_eq!(price_at(&prices, &strk, Some(99)), None);
+        assert_eq!(
+            price_at(&prices, &strk, Some(150)),
+            Some(BigDecimal::from_str("1.5")?)
+        );
+        assert_eq!(price_at(&prices, &strk, None), Some(BigDecimal::from(2)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_snapshot() -> Result<()> {
+        let eth = Felt::from(1u64);
+        let usdc = Felt::from(2u64);
+        let alice = Felt::from(10u64);
+        let bob = Felt::from(11u64);
+
+        let snapshot = BalanceSnapshot {
+            balances: HashMap::from([
+                (
+                    eth,
+                    HashMap::from([
+                        // 1.5 ETH
+                        (alice, Felt::from(1_500_000_000_000_000_000u64)),
+                        // 0.25 ETH
+                        (bob, Felt::from(250_000_000_000_000_000u64)),
+                    ]),
+                ),
+                // 10 USDC
+                (usdc, HashMap::from([(alice, Felt::from(10_000_000u64))])),
+            ]),
+            token_info: HashMap::from([(
+                usdc,
+                TokenInfo {
+                    symbol: Some("USDC".to_string()),
+                    decimals: Some(6),
+                },
+            )]),
+            ..Default::default()
+        };
+        let prices = parse_prices(r#"{ "0x1": "2000", "0x2": "1" }"#)?;
+
+        let valuation = value_snapshot(&snapshot, &prices, None);
+
+        assert_eq!(
+            format_value(valuation.value_of(&eth, &alice).unwrap()),
+            "3000.00"
+        );
+        assert_eq!(
+            format_value(valuation.value_of(&eth, &bob).unwrap()),
+            "500.00"
+        );
+        assert_eq!(format_value(&valuation.token_totals[&eth]), "3500.00");
+        assert_eq!(format_value(&valuation.portfolio_totals[&alice]), "3010.00");
+        assert_eq!(format_value(&valuation.portfolio_totals[&bob]), "500.00");
+        Ok(())
+    }
+}